@@ -1,7 +1,10 @@
 use lazy_static::lazy_static;
 use protocols::Transporter::TransportNode;
+use protocols::pluginhandler::PluginCodec;
 
-pub mod autogen_protobuf;
+pub mod autogen_protobuf {
+    include!(concat!(env!("OUT_DIR"), "/autogen_protobuf.rs"));
+}
 pub mod test_interface;
 // __PUBMODPROTOCOLS__ Do not remove this line. This line is used to add new protocols.
 
@@ -12,6 +15,7 @@ lazy_static! {
         // __REGISTERINTERFACES__ Do not remove this line. This line is used to add new protocols.
         m
     };
+    static ref CODEC: PluginCodec = PluginCodec::default();
 }
 
 // Need this for some reason to compile to wasm.
@@ -35,7 +39,7 @@ pub extern fn init() {
 pub extern fn handle_request_ffi(data: &[u8]) -> Vec<u8> {
     println!("handle_request_ffi");
     log::trace!("Inside dynamic library handle_request_ffi(...)...");
-    let ret = protocols::pluginhandler::ffi_handle_received_bytes(&NODE, data);
+    let ret = protocols::pluginhandler::ffi_handle_received_bytes(&CODEC, &NODE, data);
     log::trace!("...Leaving dynamic library handle_request_ffi(...)");
     ret
 }
@@ -63,7 +67,7 @@ pub extern fn handle_request_ffi_wasm(id: i32, arg_bytesize: i32) -> i32 {
     unsafe { get_arg_data(arg_data.as_ptr(), arg_bytesize as usize, id); }
     println!("Got arg {:?}", arg_data);
     log::trace!("Got arg {:?}", arg_data);
-    let ret = protocols::pluginhandler::ffi_handle_received_bytes(&NODE, &arg_data);
+    let ret = protocols::pluginhandler::ffi_handle_received_bytes(&CODEC, &NODE, &arg_data);
     unsafe { return_data(ret.as_ptr(), ret.len(), id); }
     println!("...Leaving dynamic library handle_request_ffi(...)");
     log::trace!("...Leaving dynamic library handle_request_ffi(...)");
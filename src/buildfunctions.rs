@@ -3,6 +3,9 @@ use failure::Error;
 use std::fs::File;	
 use std::path::PathBuf;
 use std::io::Write;
+use crate::schemastore::{self, SchemaStore, LocalSchemaStore};
+use crate::protoparser::ProtoFile;
+use crate::schemacompat::{self, Compatibility, CompatibilityReport};
 
 
 pub fn build_rust_code_from_protobuffer(proto_filename: &PathBuf) -> Result<PathBuf, Error> {
@@ -11,7 +14,7 @@ pub fn build_rust_code_from_protobuffer(proto_filename: &PathBuf) -> Result<Path
 		"//__SCHEMA_URL__".to_string()
 	];
 
-	build_rust_code_from_protobuffer_with_options(proto_filename, includes, Box::new(|_, _| Ok(()) ))
+	build_rust_code_from_protobuffer_with_options(proto_filename, includes, rpc_generator())
 }
 
 /// Call protoc on protobuffer and create non-rpc code
@@ -51,58 +54,319 @@ pub fn build_rust_code_from_protobuffer_with_options(proto_filename: &PathBuf, i
 /// In parent program, lib.rs loads in the schema_link at compile time so that the library can use it.
 #[cfg(not(target_arch = "wasm32"))]
 pub fn add_file_to_ipfs(path: &PathBuf) -> Result<String, Error> {
+	log::info!("Adding {:?} to ipfs...", path);
+	let file = std::fs::File::open(path)?;
+	let hash = add_to_ipfs(file)?;
+	log::info!("...Added {:?} to ipfs as {:?}", path, hash);
+	Ok(hash)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn add_to_ipfs<R: 'static + std::io::Read + Send>(data: R) -> Result<String, Error> {
 	use std::sync::{Arc, Mutex};
 	use futures::future::Future;
 	let client = ipfs_api::IpfsClient::default();
 	
 	// Create atomics for hyper
-	let should_panic = Arc::new(Mutex::new(false));
-	let should_panic_clone = should_panic.clone();
+	let failed = Arc::new(Mutex::new(false));
+	let failed_clone = failed.clone();
 	let hash = Arc::new(Mutex::new(String::new()));
 	let hash_clone = hash.clone();
 
-	log::info!("Adding {:?} to ipfs...", path);
-
-	let file = std::fs::File::open(path)?;
-	/*let client = reqwest::Client::new();
-	let mut res = client.post("http://localhost:5001/api/v0/add").body(file).send()?;
-
-	println!("Result from add: {:?}", res);*/
-
 	let req = client
-		.add(file)
+		.add(data)
 		.map(move |result| { 
 			let mut hash = hash_clone.lock().unwrap();
 			*hash = result.hash;
 		})
 		.map_err(move |_e| {
-			let mut data = should_panic_clone.lock().unwrap();
+			let mut data = failed_clone.lock().unwrap();
 			*data = true; 
 		});
 
 	hyper::rt::run(req);
 
-	// We have to panic in the main thread.
-	if *should_panic.lock().unwrap() == true {
-		panic!(r#"Unable to retrieve schema URL from ipfs. Make sure that IPFS daemon is running! You can get IPFS from ipfs.io\nRun Command: ipfs daemon --enable-pubsub-experiment\n"#);
+	if *failed.lock().unwrap() == true {
+		return Err(failure::format_err!(r#"Unable to retrieve schema URL from ipfs. Make sure that IPFS daemon is running! You can get IPFS from ipfs.io\nRun Command: ipfs daemon --enable-pubsub-experiment\n"#));
 	}
 
-	//let hash = res.text()?;
 	let hash = hash.lock().unwrap().to_string();
-    Ok(hash)
+	Ok(hash)
 }
 
+/// Stores schemas in IPFS. Requires a running IPFS daemon.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+pub struct IpfsSchemaStore;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SchemaStore for IpfsSchemaStore {
+	fn add(&self, schema: &[u8]) -> Result<String, Error> {
+		add_to_ipfs(std::io::Cursor::new(schema.to_vec()))
+	}
+
+	fn get(&self, hash: &str) -> Result<Vec<u8>, Error> {
+		resolve_ipfs(hash)
+	}
+}
+
+/// Generate code and hash the schema into the local schema store. No IPFS daemon needed.
 pub fn hash_protobuf_and_generate_code(proto_path: &PathBuf) -> Result<(), Error> {
+	hash_protobuf_and_generate_code_with_store(proto_path, &LocalSchemaStore::default())
+}
+
+pub fn hash_protobuf_and_generate_code_with_store(proto_path: &PathBuf, store: &SchemaStore) -> Result<(), Error> {
 	let generated_rs_file = build_rust_code_from_protobuffer(proto_path)?;
-	let hash = add_file_to_ipfs(proto_path)?;
+	let hash = store.add(&std::fs::read(proto_path)?)?;
 	replace_schema_url_comment_with_hash_constant(&generated_rs_file, &hash)?;
 	add_to_schema_urls_rs(&base_name(proto_path), &hash)?;
 	Ok(())
 }
 
+/// Generate code for every .proto file in schema_dir into OUT_DIR. Nothing in the source tree is touched.
+/// Call this from build.rs, then add this to lib.rs:
+/// pub mod autogen_protobuf { include!(concat!(env!("OUT_DIR"), "/autogen_protobuf.rs")); }
+pub fn generate_protobuf_in_out_dir(schema_dir: &PathBuf) -> Result<PathBuf, Error> {
+	generate_protobuf_in_out_dir_with_options(schema_dir, None, &rpc_generator)
+}
+
+/// Schemas are also added to the store if there is one. rpc_generator is called once per .proto file.
+pub fn generate_protobuf_in_out_dir_with_options(schema_dir: &PathBuf, store: Option<&SchemaStore>, rpc_generator: &Fn() -> pb_rs::types::RpcGeneratorFunction) -> Result<PathBuf, Error> {
+	use pb_rs::types::Config;
+	let out_dir = PathBuf::from(std::env::var("OUT_DIR").map_err(|_| failure::format_err!("OUT_DIR is not set. Only call this from build.rs!"))?);
+	let mut generated_dir = out_dir.clone();
+	generated_dir.push("autogen_protobuf");
+	std::fs::create_dir_all(&generated_dir)?;
+
+	// Also rerun when a schema is added or removed.
+	println!("cargo:rerun-if-changed={}", schema_dir.display());
+
+	let protos = protos_in_dir(schema_dir)?;
+
+	let mut modules = String::new();
+	let mut aliases = String::new();
+	for (path, file) in &protos {
+		println!("cargo:rerun-if-changed={}", path.display());
+		let name = base_name(path);
+		log::info!("Building protobuf for {:?}...", path);
+
+		let source = std::fs::read(path)?;
+		let hash = match store {
+			Some(store) => store.add(&source)?,
+			None => schemastore::ipfs_hash(&source)?,
+		};
+
+		let mut includes = vec![format!("pub static SCHEMA_URL: &str = \"{}\";", hash)];
+		includes.extend(import_statements(path, file, &protos)?);
+
+		let mut out_file = generated_dir.clone();
+		out_file.push(format!("{}.rs", name));
+
+		let config = Config {
+			in_file: path.clone(),
+			out_file: out_file.clone(),
+			single_module: true,
+			import_search_path: vec![schema_dir.clone()],
+			no_output: false,
+			error_cycle: false,
+			headers: false, // Inner attributes cannot be include!(...)ed. The module below gets them instead.
+			dont_use_cow: true,
+			custom_struct_derive: vec!["derive_new::new".into()],
+			custom_rpc_generator: rpc_generator(),
+			custom_includes: includes,
+		};
+
+		if let Err(e) = pb_rs::types::FileDescriptor::write_proto(&config) {
+			return Err(failure::format_err!("{:?}", e));
+		}
+		log::info!("...Pb-rs ran on {:?} and created {:?}", path, out_file);
+
+		modules += "#[allow(non_snake_case, non_upper_case_globals, non_camel_case_types, unused_imports, unknown_lints, clippy::all)]\n";
+		modules += &format!("pub mod {} {{\n", name);
+		modules += &format!("\tinclude!(concat!(env!(\"OUT_DIR\"), \"/autogen_protobuf/{}.rs\"));\n", name);
+		modules += "}\n\n";
+		aliases += &format!("\t\tret.insert(\"{}\".to_string(), \"{}\");\n", name, hash);
+	}
+
+	let mut file_data = String::from("// Generated by protocols::buildfunctions::generate_protobuf_in_out_dir. Do not edit.\n\n");
+	file_data += &modules;
+	file_data += "pub mod schema_urls {\n";
+	file_data += "\tpub fn get_all_aliases() -> hashbrown::HashMap<String, &'static str> {\n";
+	file_data += "\t\tlet mut ret = hashbrown::HashMap::new();\n";
+	file_data += &aliases;
+	file_data += "\t\tret\n";
+	file_data += "\t}\n";
+	file_data += "}\n";
+
+	let mut module_file = out_dir;
+	module_file.push("autogen_protobuf.rs");
+	write_to_file(&module_file, file_data)?;
+	Ok(module_file)
+}
+
+/// An absolute path inside the package that is being built. Only works from build.rs.
+pub fn manifest_path(relative: &str) -> Result<PathBuf, Error> {
+	let mut path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR")?);
+	path.push(relative);
+	Ok(path)
+}
+
+// Each generated file is a sibling module of the files it imports. pb-rs nests a file's messages in one module per
+// package segment, so the path has to name the imported file's package too.
+fn import_statements(path: &PathBuf, file: &ProtoFile, protos: &[(PathBuf, ProtoFile)]) -> Result<Vec<String>, Error> {
+	let mut statements = Vec::new();
+	for import in &file.imports {
+		let import_name = base_name(&PathBuf::from(import));
+		let imported = protos.iter().find(|(other, _)| base_name(other) == import_name)
+			.ok_or(failure::format_err!("{:?} imports {:?}, which is not in the same schema directory!", path, import))?;
+		statements.push(format!("use {}::*;", imported_module_path(&import_name, imported.1.package.as_ref().map(|package| package.as_str()))));
+	}
+	Ok(statements)
+}
+
+fn imported_module_path(import_name: &str, package: Option<&str>) -> String {
+	let mut module_path = format!("super::{}", import_name);
+	for segment in package.into_iter().flat_map(|package| package.split('.')).filter(|segment| !segment.is_empty()) {
+		module_path += "::";
+		module_path += segment;
+	}
+	module_path
+}
+
+// Sorted so that the generated module file does not change between builds.
+fn protos_in_dir(schema_dir: &PathBuf) -> Result<Vec<(PathBuf, ProtoFile)>, Error> {
+	let mut paths = Vec::new();
+	for entry in std::fs::read_dir(schema_dir)? {
+		let path = entry?.path();
+		if path.extension().map(|ext| ext == "proto").unwrap_or(false) {
+			paths.push(path);
+		}
+	}
+	paths.sort();
+
+	let mut protos = Vec::new();
+	for path in paths {
+		let file = ProtoFile::parse_file(&path)?;
+		protos.push((path, file));
+	}
+	Ok(protos)
+}
+
+/// Turns each protobuf service into a trait, a client that builds RequestTransports, and a dispatcher for TransportNode.
+/// The generated code uses SCHEMA_URL, so the schema must be hashed when it is generated.
+/// It refers to this crate as protocols. Use rpc_generator_with_crate_path(...) if it goes by another name.
+pub fn rpc_generator() -> pb_rs::types::RpcGeneratorFunction {
+	rpc_generator_with_crate_path("protocols")
+}
+
+/// crate_path is how the generated code reaches this crate, e.g. "crate" when generating code inside protocols itself.
+pub fn rpc_generator_with_crate_path(crate_path: &str) -> pb_rs::types::RpcGeneratorFunction {
+	let crate_path = crate_path.to_string();
+	Box::new(move |service, w| write_rpc_service(&crate_path, service, w))
+}
+
+fn write_rpc_service(crate_path: &str, service: &pb_rs::types::RpcService, w: &mut Write) -> pb_rs::errors::Result<()> {
+	let name = &service.service_name;
+
+	writeln!(w, "pub trait {} {{", name)?;
+	for function in &service.functions {
+		writeln!(w, "    fn {}(&mut self, arg: {}) -> Result<{}, failure::Error>;", function.name, rust_type(&function.arg), rust_type(&function.ret))?;
+	}
+	writeln!(w, "}}\n")?;
+
+	writeln!(w, "/// Builds requests for {}. Send them with any Transporter.", name)?;
+	writeln!(w, "pub struct {}Client {{", name)?;
+	writeln!(w, "    pub module_id: {}::ModuleId,", crate_path)?;
+	writeln!(w, "}}\n")?;
+	writeln!(w, "impl {}Client {{", name)?;
+	writeln!(w, "    pub fn new(module_id: {}::ModuleId) -> Self {{", crate_path)?;
+	writeln!(w, "        {}Client{{ module_id }}", name)?;
+	writeln!(w, "    }}")?;
+	for function in &service.functions {
+		writeln!(w)?;
+		writeln!(w, "    pub fn {}(&self, arg: &{}) -> Result<{}::RequestTransport, failure::Error> {{", function.name, rust_type(&function.arg), crate_path)?;
+		writeln!(w, "        {}::rpc::rpc_request(&self.module_id, SCHEMA_URL, \"{}.{}\", arg)", crate_path, name, function.name)?;
+		writeln!(w, "    }}\n")?;
+		writeln!(w, "    pub fn {}_reply(&self, ret: &{}::ReturnTransport) -> Result<{}, failure::Error> {{", function.name, crate_path, rust_type(&function.ret))?;
+		writeln!(w, "        {}::rpc::rpc_decode_reply(ret, SCHEMA_URL, \"{}\")", crate_path, function.ret)?;
+		writeln!(w, "    }}")?;
+	}
+	writeln!(w, "}}\n")?;
+
+	writeln!(w, "/// Calls the handler for each {} request. Add it with add_struct_handler_for_descriptors(...) using descriptors().", name)?;
+	writeln!(w, "#[derive(Default)]")?;
+	writeln!(w, "pub struct {}Dispatcher<H: {} + Default> {{", name, name)?;
+	writeln!(w, "    pub handler: H,")?;
+	writeln!(w, "}}\n")?;
+	writeln!(w, "impl<H: {} + Default> {}Dispatcher<H> {{", name, name)?;
+	writeln!(w, "    /// One descriptor per method. They all lead to the same dispatcher.")?;
+	writeln!(w, "    pub fn descriptors() -> Vec<{}::TypeDescriptor> {{", crate_path)?;
+	writeln!(w, "        vec![")?;
+	for function in &service.functions {
+		writeln!(w, "            {}::TypeDescriptor::new(SCHEMA_URL.to_string(), \"{}.{}\".to_string()),", crate_path, name, function.name)?;
+	}
+	writeln!(w, "        ]")?;
+	writeln!(w, "    }}")?;
+	writeln!(w, "}}\n")?;
+	writeln!(w, "impl<H: {} + Default> {}::CommonStructureFunctions for {}Dispatcher<H> {{", name, crate_path, name)?;
+	writeln!(w, "    fn process_struct(&mut self, data: {}::ProcessStructData) -> Result<Vec<{}::Event>, failure::Error> {{", crate_path, crate_path)?;
+	writeln!(w, "        match data.changes.descriptor.structure.as_str() {{")?;
+	for function in &service.functions {
+		writeln!(w, "            \"{}.{}\" => {{", name, function.name)?;
+		writeln!(w, "                let ret = self.handler.{}({}::rpc::rpc_decode_arg(&data)?)?;", function.name, crate_path)?;
+		writeln!(w, "                Ok(vec![{}::rpc::rpc_reply(SCHEMA_URL, \"{}\", &ret)?])", crate_path, function.ret)?;
+		writeln!(w, "            }},")?;
+	}
+	writeln!(w, "            other => Err(failure::format_err!(\"{} has no method {{:?}}!\", other)),", name)?;
+	writeln!(w, "        }}")?;
+	writeln!(w, "    }}")?;
+	writeln!(w, "}}\n")?;
+	Ok(())
+}
+
+// Nested messages are generated as mod_Outer::Inner
+fn rust_type(proto_type: &str) -> String {
+	let proto_type = proto_type.trim_start_matches('.');
+	match proto_type.rfind('.') {
+		None => proto_type.to_string(),
+		Some(split) => {
+			let modules: Vec<String> = proto_type[..split].split('.').map(|outer| format!("mod_{}", outer)).collect();
+			format!("{}::{}", modules.join("::"), &proto_type[split + 1..])
+		},
+	}
+}
+
+/// Compare two versions of a schema file.
+pub fn check_schema_compatibility(old_proto: &PathBuf, new_proto: &PathBuf) -> Result<CompatibilityReport, Error> {
+	let old = ProtoFile::parse_file(old_proto)?;
+	let new = ProtoFile::parse_file(new_proto)?;
+	Ok(log_compatibility_report(schemacompat::check_compatibility(&old, &new)))
+}
+
+/// Compare a schema file against an older version that was stored by its hash.
+pub fn check_schema_compatibility_with_hash(store: &SchemaStore, old_hash: &str, new_proto: &PathBuf) -> Result<CompatibilityReport, Error> {
+	let old = ProtoFile::parse(std::str::from_utf8(&store.get(old_hash)?)?)?;
+	let new = ProtoFile::parse_file(new_proto)?;
+	Ok(log_compatibility_report(schemacompat::check_compatibility(&old, &new)))
+}
+
+/// Call this from build.rs. Returning the error fails the build when the schema breaks compatibility.
+pub fn require_schema_compatibility(old_proto: &PathBuf, new_proto: &PathBuf, required: Compatibility) -> Result<(), Error> {
+	check_schema_compatibility(old_proto, new_proto)?.require(required)
+}
+
+fn log_compatibility_report(report: CompatibilityReport) -> CompatibilityReport {
+	for change in &report.changes {
+		log::info!("{}: {} (breaks backward: {}, breaks forward: {})", change.message, change.description, change.breaks_backward, change.breaks_forward);
+	}
+	log::info!("Schema compatibility: {:?}", report.compatibility());
+	report
+}
+
+/// Fetch a schema through the IPFS schema store. At runtime, prefer a SchemaRegistry with an IpfsSchemaStore backend.
 pub fn download_schema_from_ipfs(schema_name: &str, schema_ipfs_hash: &str) -> Result<PathBuf, Error> {
 	log::debug!("Downloading schema {:?}...", schema_ipfs_hash);
-	let schema_data = resolve_ipfs(schema_ipfs_hash)?;
+	let schema_data = IpfsSchemaStore.get(schema_ipfs_hash)?;
 
 	// TODO: Handle files of other protocols
 	let schema_path = format!("./downloaded_schema/{}.proto", schema_name);
@@ -236,9 +500,64 @@ pub fn base_name(protobuf_path: &PathBuf) -> String {
 	base_name
 }
 
+/// Where the older generator functions write. Prefer generate_protobuf_in_out_dir, which leaves the source tree alone.
 pub fn autogen_dir() -> PathBuf {
 	let mut dir = PathBuf::from(std::env::current_dir().unwrap());
 	dir.push("src");
 	dir.push("autogen_protobuf");
 	dir
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn proto(name: &str, source: &str) -> (PathBuf, ProtoFile) {
+		(PathBuf::from(format!("schema/{}.proto", name)), ProtoFile::parse(source).unwrap())
+	}
+
+	#[test]
+	fn test_imports_name_the_package() {
+		let protos = vec![
+			proto("common", "syntax = \"proto2\"; package my.common; message Shared { required string name = 1; }"),
+			proto("plain", "syntax = \"proto2\"; message Plain { required string name = 1; }"),
+			proto("user", "syntax = \"proto2\"; import \"common.proto\"; import \"plain.proto\"; message User { required my.common.Shared shared = 1; }"),
+		];
+		let statements = import_statements(&protos[2].0, &protos[2].1, &protos).unwrap();
+		assert_eq!(statements, vec!["use super::common::my::common::*;".to_string(), "use super::plain::*;".to_string()]);
+	}
+
+	#[test]
+	fn test_import_outside_schema_dir_is_refused() {
+		let protos = vec![proto("user", "syntax = \"proto2\"; import \"missing.proto\"; message User {}")];
+		assert!(import_statements(&protos[0].0, &protos[0].1, &protos).is_err());
+	}
+
+	#[test]
+	fn test_rpc_service_uses_the_crate_path() {
+		use pb_rs::types::{RpcFunctionDeclaration, RpcService};
+		let service = RpcService {
+			service_name: "Echo".to_string(),
+			functions: vec![
+				RpcFunctionDeclaration { name: "first".to_string(), arg: "Test".to_string(), ret: ".Outer.Inner".to_string() },
+				RpcFunctionDeclaration { name: "second".to_string(), arg: "Test".to_string(), ret: "Empty".to_string() },
+			],
+		};
+
+		let mut generated = Vec::new();
+		rpc_generator_with_crate_path("crate")(&service, &mut generated).unwrap();
+		let generated = String::from_utf8(generated).unwrap();
+		assert!(!generated.contains("protocols::"));
+		assert!(generated.contains("fn first(&mut self, arg: Test) -> Result<mod_Outer::Inner, failure::Error>;"));
+		assert!(generated.contains("crate::rpc::rpc_request(&self.module_id, SCHEMA_URL, \"Echo.first\", arg)"));
+		assert!(generated.contains("crate::TypeDescriptor::new(SCHEMA_URL.to_string(), \"Echo.second\".to_string()),"));
+		assert!(generated.contains("impl<H: Echo + Default> crate::CommonStructureFunctions for EchoDispatcher<H>"));
+	}
+
+	#[test]
+	fn test_nested_types_use_their_module() {
+		assert_eq!(rust_type("Test"), "Test");
+		assert_eq!(rust_type(".Outer.Inner"), "mod_Outer::Inner");
+		assert_eq!(rust_type("A.B.C"), "mod_A::mod_B::C");
+	}
+}
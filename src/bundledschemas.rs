@@ -0,0 +1,16 @@
+//! Schemas that are compiled into this crate. Negotiation, codecs and the JSON mapping all describe transport messages with these.
+use crate::dynamicmessage::DynamicSchema;
+use crate::schemastore;
+
+pub static TRANSPORT_SCHEMA: &str = include_str!("../schema/transport.proto");
+pub static TRANSPORT_SCHEMA_NAME: &str = "transport";
+
+/// The hash of the transport.proto this crate was built with.
+pub fn transport_schema_hash() -> String {
+    schemastore::ipfs_hash(TRANSPORT_SCHEMA.as_bytes()).expect("transport.proto is too large to hash!")
+}
+
+/// transport.proto, for decoding transport messages without generated code.
+pub fn transport_dynamic_schema() -> DynamicSchema {
+    DynamicSchema::parse(TRANSPORT_SCHEMA).expect("The bundled transport.proto does not parse!")
+}
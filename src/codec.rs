@@ -0,0 +1,422 @@
+//! Codecs decide how RequestTransport and ReturnTransport are written to bytes at plugin and network boundaries.
+//! Protobuf is always available. CBOR and MessagePack go through the dynamic schema layer and key fields by number,
+//! so renaming a field in transport.proto does not break them any more than it breaks protobuf.
+//! The schema handshake itself is always protobuf, because the codec has not been agreed on yet.
+//! Arrays, maps and messages nested deeper than dynamicmessage::MAX_DEPTH are refused, so a peer cannot run us out of stack.
+use crate::autogen_protobuf::transport::*;
+use crate::bundledschemas::transport_dynamic_schema;
+use crate::dynamicmessage::{DynamicMessage, DynamicSchema, FieldType, Value, MAX_DEPTH};
+use crate::protoparser::Label;
+
+use failure::Error;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+pub static PROTOBUF: &str = "protobuf";
+pub static CBOR: &str = "cbor";
+pub static MESSAGEPACK: &str = "msgpack";
+
+/// Both sides of a connection must use the same codec. Send so servers can be moved onto their own thread.
+pub trait Codec: Send {
+    /// The name sent in SchemaHandshake.codecs
+    fn name(&self) -> &'static str;
+    fn encode_request(&self, request: &RequestTransport) -> Result<Vec<u8>, Error>;
+    fn decode_request(&self, bytes: &[u8]) -> Result<RequestTransport, Error>;
+    fn encode_return(&self, ret: &ReturnTransport) -> Result<Vec<u8>, Error>;
+    fn decode_return(&self, bytes: &[u8]) -> Result<ReturnTransport, Error>;
+}
+
+/// Every codec this crate ships, preferred first.
+pub fn supported_codecs() -> Vec<&'static str> {
+    vec![PROTOBUF, CBOR, MESSAGEPACK]
+}
+
+pub fn codec_by_name(name: &str) -> Option<Box<Codec>> {
+    match name {
+        "protobuf" => Some(Box::new(ProtobufCodec)),
+        "cbor" => Some(Box::new(CborCodec::default())),
+        "msgpack" => Some(Box::new(MessagePackCodec::default())),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtobufCodec;
+
+impl Codec for ProtobufCodec {
+    fn name(&self) -> &'static str {
+        PROTOBUF
+    }
+
+    fn encode_request(&self, request: &RequestTransport) -> Result<Vec<u8>, Error> {
+        Ok(quick_protobuf::serialize_into_vec(request)?)
+    }
+
+    fn decode_request(&self, bytes: &[u8]) -> Result<RequestTransport, Error> {
+        Ok(quick_protobuf::deserialize_from_slice(bytes)?)
+    }
+
+    fn encode_return(&self, ret: &ReturnTransport) -> Result<Vec<u8>, Error> {
+        Ok(quick_protobuf::serialize_into_vec(ret)?)
+    }
+
+    fn decode_return(&self, bytes: &[u8]) -> Result<ReturnTransport, Error> {
+        Ok(quick_protobuf::deserialize_from_slice(bytes)?)
+    }
+}
+
+pub struct CborCodec {
+    transport: DynamicSchema,
+}
+
+impl Default for CborCodec {
+    fn default() -> Self {
+        CborCodec{ transport: transport_dynamic_schema() }
+    }
+}
+
+impl Codec for CborCodec {
+    fn name(&self) -> &'static str {
+        CBOR
+    }
+
+    fn encode_request(&self, request: &RequestTransport) -> Result<Vec<u8>, Error> {
+        let tree = message_to_tree(&self.transport, &self.transport.from_generated("RequestTransport", request)?, 0)?;
+        Ok(serde_cbor::to_vec(&tree_to_cbor(tree))?)
+    }
+
+    fn decode_request(&self, bytes: &[u8]) -> Result<RequestTransport, Error> {
+        let tree = cbor_to_tree(serde_cbor::from_slice(bytes)?, 0)?;
+        self.transport.to_generated(&tree_to_message(&self.transport, "RequestTransport", tree, 0)?)
+    }
+
+    fn encode_return(&self, ret: &ReturnTransport) -> Result<Vec<u8>, Error> {
+        let tree = message_to_tree(&self.transport, &self.transport.from_generated("ReturnTransport", ret)?, 0)?;
+        Ok(serde_cbor::to_vec(&tree_to_cbor(tree))?)
+    }
+
+    fn decode_return(&self, bytes: &[u8]) -> Result<ReturnTransport, Error> {
+        let tree = cbor_to_tree(serde_cbor::from_slice(bytes)?, 0)?;
+        self.transport.to_generated(&tree_to_message(&self.transport, "ReturnTransport", tree, 0)?)
+    }
+}
+
+pub struct MessagePackCodec {
+    transport: DynamicSchema,
+}
+
+impl Default for MessagePackCodec {
+    fn default() -> Self {
+        MessagePackCodec{ transport: transport_dynamic_schema() }
+    }
+}
+
+impl MessagePackCodec {
+    fn write(tree: Tree) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        rmpv::encode::write_value(&mut bytes, &tree_to_msgpack(tree))?;
+        Ok(bytes)
+    }
+
+    fn read(mut bytes: &[u8]) -> Result<Tree, Error> {
+        // rmpv counts two levels for each array or map, and up to three for the value inside.
+        // Its limit only protects the stack. msgpack_to_tree(...) checks the exact depth.
+        msgpack_to_tree(rmpv::decode::read_value_with_max_depth(&mut bytes, 2 * MAX_DEPTH + 3)?, 0)
+    }
+}
+
+impl Codec for MessagePackCodec {
+    fn name(&self) -> &'static str {
+        MESSAGEPACK
+    }
+
+    fn encode_request(&self, request: &RequestTransport) -> Result<Vec<u8>, Error> {
+        MessagePackCodec::write(message_to_tree(&self.transport, &self.transport.from_generated("RequestTransport", request)?, 0)?)
+    }
+
+    fn decode_request(&self, bytes: &[u8]) -> Result<RequestTransport, Error> {
+        self.transport.to_generated(&tree_to_message(&self.transport, "RequestTransport", MessagePackCodec::read(bytes)?, 0)?)
+    }
+
+    fn encode_return(&self, ret: &ReturnTransport) -> Result<Vec<u8>, Error> {
+        MessagePackCodec::write(message_to_tree(&self.transport, &self.transport.from_generated("ReturnTransport", ret)?, 0)?)
+    }
+
+    fn decode_return(&self, bytes: &[u8]) -> Result<ReturnTransport, Error> {
+        self.transport.to_generated(&tree_to_message(&self.transport, "ReturnTransport", MessagePackCodec::read(bytes)?, 0)?)
+    }
+}
+
+// The data model that CBOR and MessagePack share. Messages are maps from field number to value.
+#[derive(Clone, Debug, PartialEq)]
+enum Tree {
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Array(Vec<Tree>),
+    Map(Vec<(Tree, Tree)>),
+}
+
+// Fails once arrays, maps and messages are nested more than MAX_DEPTH deep.
+fn check_depth(depth: usize) -> Result<(), Error> {
+    if depth > MAX_DEPTH {
+        return Err(failure::format_err!("Value is nested more than {} deep!", MAX_DEPTH));
+    }
+    Ok(())
+}
+
+fn message_to_tree(schema: &DynamicSchema, message: &DynamicMessage, depth: usize) -> Result<Tree, Error> {
+    check_depth(depth)?;
+    let (def, _) = schema.find_message(&message.name)
+        .ok_or(failure::format_err!("Schema does not declare {:?}!", message.name))?;
+
+    let mut fields = Vec::new();
+    for (name, value) in &message.fields {
+        let field = def.field_by_name(name)
+            .ok_or(failure::format_err!("{:?} has no field {:?}!", message.name, name))?;
+        fields.push((Tree::UInt(u64::from(field.number)), value_to_tree(schema, value, depth + 1)?));
+    }
+    Ok(Tree::Map(fields))
+}
+
+fn value_to_tree(schema: &DynamicSchema, value: &Value, depth: usize) -> Result<Tree, Error> {
+    check_depth(depth)?;
+    let tree = match value {
+        Value::Int(v) => Tree::Int(*v),
+        Value::UInt(v) => Tree::UInt(*v),
+        Value::Float(v) => Tree::Float(f64::from(*v)),
+        Value::Double(v) => Tree::Float(*v),
+        Value::Bool(v) => Tree::Bool(*v),
+        Value::String(v) => Tree::Text(v.clone()),
+        Value::Bytes(v) => Tree::Bytes(v.clone()),
+        Value::Enum(number, _) => Tree::Int(i64::from(*number)),
+        Value::Message(message) => message_to_tree(schema, message, depth)?,
+        Value::List(values) => Tree::Array(values.iter().map(|value| value_to_tree(schema, value, depth + 1)).collect::<Result<_, _>>()?),
+        Value::Map(entries) => {
+            let mut map = Vec::new();
+            for (key, value) in entries {
+                map.push((value_to_tree(schema, key, depth + 1)?, value_to_tree(schema, value, depth + 1)?));
+            }
+            Tree::Map(map)
+        },
+    };
+    Ok(tree)
+}
+
+fn tree_to_message(schema: &DynamicSchema, structure: &str, tree: Tree, depth: usize) -> Result<DynamicMessage, Error> {
+    check_depth(depth)?;
+    let (def, _) = schema.find_message(structure)
+        .ok_or(failure::format_err!("Schema does not declare {:?}!", structure))?;
+    let entries = match tree {
+        Tree::Map(entries) => entries,
+        other => return Err(failure::format_err!("Expected a map for {:?} but found {:?}!", structure, other)),
+    };
+
+    let mut message = DynamicMessage::new(structure);
+    for (key, tree) in entries {
+        let number = tree_integer::<u32>(&key)?;
+        let field = match def.field_by_number(number) {
+            Some(field) => field,
+            None => {
+                log::trace!("Skipping unknown field {} in {:?}", number, structure);
+                continue;
+            },
+        };
+
+        let field_type = schema.field_type(structure, &field.typ)?;
+        let value = match (&field_type, tree) {
+            (FieldType::Map(key_type, value_type), Tree::Map(entries)) => {
+                let key_type = schema.field_type(structure, key_type)?;
+                let value_type = schema.field_type(structure, value_type)?;
+                let mut map = Vec::new();
+                for (key, value) in entries {
+                    map.push((tree_to_value(schema, &key_type, key, depth + 1)?, tree_to_value(schema, &value_type, value, depth + 1)?));
+                }
+                Value::Map(map)
+            },
+            (_, Tree::Array(values)) if field.label == Label::Repeated => {
+                Value::List(values.into_iter().map(|tree| tree_to_value(schema, &field_type, tree, depth + 1)).collect::<Result<_, _>>()?)
+            },
+            (_, tree) => tree_to_value(schema, &field_type, tree, depth + 1)?,
+        };
+        message.set(&field.name, value);
+    }
+
+    message.fields.sort_by_key(|(name, _)| def.fields.iter().position(|field| &field.name == name));
+    Ok(message)
+}
+
+fn tree_to_value(schema: &DynamicSchema, field_type: &FieldType, tree: Tree, depth: usize) -> Result<Value, Error> {
+    let value = match (field_type, tree) {
+        (FieldType::Scalar(typ), tree) => match (*typ, tree) {
+            ("int32", tree) | ("int64", tree) | ("sint32", tree) | ("sint64", tree) | ("sfixed32", tree) | ("sfixed64", tree) => Value::Int(tree_integer(&tree)?),
+            ("uint32", tree) | ("uint64", tree) | ("fixed32", tree) | ("fixed64", tree) => Value::UInt(tree_integer(&tree)?),
+            ("float", Tree::Float(v)) => Value::Float(v as f32),
+            ("double", Tree::Float(v)) => Value::Double(v),
+            ("bool", Tree::Bool(v)) => Value::Bool(v),
+            ("string", Tree::Text(v)) => Value::String(v),
+            ("bytes", Tree::Bytes(v)) => Value::Bytes(v),
+            (typ, tree) => return Err(failure::format_err!("Cannot read {:?} as {}!", tree, typ)),
+        },
+        (FieldType::Enum(def), tree) => {
+            let number = tree_integer::<i32>(&tree)?;
+            Value::Enum(number, def.values.iter().find(|(_, value)| *value == number).map(|(name, _)| name.clone()))
+        },
+        (FieldType::Message(name, _, _), tree) => Value::Message(tree_to_message(schema, name, tree, depth)?),
+        (FieldType::Map(_, _), _) => return Err(failure::format_err!("Maps cannot be nested directly!")),
+    };
+    Ok(value)
+}
+
+fn tree_integer<T>(tree: &Tree) -> Result<T, Error>
+    where T: std::convert::TryFrom<i64> + std::convert::TryFrom<u64> {
+    let converted = match tree {
+        Tree::Int(v) => T::try_from(*v).ok(),
+        Tree::UInt(v) => T::try_from(*v).ok(),
+        _ => None,
+    };
+    converted.ok_or(failure::format_err!("Expected an integer in range but found {:?}!", tree))
+}
+
+fn tree_to_cbor(tree: Tree) -> serde_cbor::Value {
+    use serde_cbor::Value as Cbor;
+    match tree {
+        Tree::Bool(v) => Cbor::Bool(v),
+        Tree::Int(v) => Cbor::Integer(i128::from(v)),
+        Tree::UInt(v) => Cbor::Integer(i128::from(v)),
+        Tree::Float(v) => Cbor::Float(v),
+        Tree::Text(v) => Cbor::Text(v),
+        Tree::Bytes(v) => Cbor::Bytes(v),
+        Tree::Array(values) => Cbor::Array(values.into_iter().map(tree_to_cbor).collect()),
+        Tree::Map(entries) => Cbor::Map(entries.into_iter().map(|(key, value)| (tree_to_cbor(key), tree_to_cbor(value))).collect::<BTreeMap<_, _>>()),
+    }
+}
+
+fn cbor_to_tree(value: serde_cbor::Value, depth: usize) -> Result<Tree, Error> {
+    use serde_cbor::Value as Cbor;
+    check_depth(depth)?;
+    let out_of_range = |v: i128| failure::format_err!("CBOR integer {} is out of range!", v);
+    let tree = match value {
+        Cbor::Bool(v) => Tree::Bool(v),
+        Cbor::Integer(v) if v < 0 => Tree::Int(i64::try_from(v).map_err(|_| out_of_range(v))?),
+        Cbor::Integer(v) => Tree::UInt(u64::try_from(v).map_err(|_| out_of_range(v))?),
+        Cbor::Float(v) => Tree::Float(v),
+        Cbor::Text(v) => Tree::Text(v),
+        Cbor::Bytes(v) => Tree::Bytes(v),
+        Cbor::Array(values) => Tree::Array(values.into_iter().map(|value| cbor_to_tree(value, depth + 1)).collect::<Result<_, _>>()?),
+        Cbor::Map(entries) => {
+            let mut map = Vec::new();
+            for (key, value) in entries {
+                map.push((cbor_to_tree(key, depth + 1)?, cbor_to_tree(value, depth + 1)?));
+            }
+            Tree::Map(map)
+        },
+        other => return Err(failure::format_err!("Unsupported CBOR value {:?}!", other)),
+    };
+    Ok(tree)
+}
+
+fn tree_to_msgpack(tree: Tree) -> rmpv::Value {
+    use rmpv::Value as MsgPack;
+    match tree {
+        Tree::Bool(v) => MsgPack::Boolean(v),
+        Tree::Int(v) => MsgPack::from(v),
+        Tree::UInt(v) => MsgPack::from(v),
+        Tree::Float(v) => MsgPack::F64(v),
+        Tree::Text(v) => MsgPack::from(v),
+        Tree::Bytes(v) => MsgPack::Binary(v),
+        Tree::Array(values) => MsgPack::Array(values.into_iter().map(tree_to_msgpack).collect()),
+        Tree::Map(entries) => MsgPack::Map(entries.into_iter().map(|(key, value)| (tree_to_msgpack(key), tree_to_msgpack(value))).collect()),
+    }
+}
+
+fn msgpack_to_tree(value: rmpv::Value, depth: usize) -> Result<Tree, Error> {
+    use rmpv::Value as MsgPack;
+    check_depth(depth)?;
+    let tree = match value {
+        MsgPack::Boolean(v) => Tree::Bool(v),
+        MsgPack::Integer(v) => match (v.as_u64(), v.as_i64()) {
+            (Some(v), _) => Tree::UInt(v),
+            (None, Some(v)) => Tree::Int(v),
+            (None, None) => return Err(failure::format_err!("MessagePack integer {} is out of range!", v)),
+        },
+        MsgPack::F32(v) => Tree::Float(f64::from(v)),
+        MsgPack::F64(v) => Tree::Float(v),
+        MsgPack::String(v) => Tree::Text(v.into_str().ok_or(failure::format_err!("MessagePack string is not utf8!"))?),
+        MsgPack::Binary(v) => Tree::Bytes(v),
+        MsgPack::Array(values) => Tree::Array(values.into_iter().map(|value| msgpack_to_tree(value, depth + 1)).collect::<Result<_, _>>()?),
+        MsgPack::Map(entries) => {
+            let mut map = Vec::new();
+            for (key, value) in entries {
+                map.push((msgpack_to_tree(key, depth + 1)?, msgpack_to_tree(value, depth + 1)?));
+            }
+            Tree::Map(map)
+        },
+        other => return Err(failure::format_err!("Unsupported MessagePack value {:?}!", other)),
+    };
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> RequestTransport {
+        let descriptor = TypeDescriptor::new("alias".to_string(), "Structure".to_string());
+        let changes = StructDataChanges::new(vec![0, 1, 255], vec!["a".to_string(), "b".to_string()], descriptor);
+        let delivery = DeliveryHeader::new("sender".to_string(), u64::max_value(), Some(u64::max_value()));
+        RequestTransport::new(ModuleId::new("module".to_string()), Event::new(ProcessStructData::new(changes, None).into()), Some(delivery), None)
+    }
+
+    #[test]
+    fn test_every_codec_round_trips() {
+        let ret = ReturnTransport::new(vec![request().event], vec!["error".to_string()], Vec::new(), None, None);
+        for name in supported_codecs() {
+            let codec = codec_by_name(name).unwrap();
+            assert_eq!(codec.name(), name);
+            assert_eq!(codec.decode_request(&codec.encode_request(&request()).unwrap()).unwrap(), request(), "{}", name);
+            assert_eq!(codec.decode_return(&codec.encode_return(&ret).unwrap()).unwrap(), ret, "{}", name);
+        }
+        assert!(codec_by_name("unknown").is_none());
+    }
+
+    #[test]
+    fn test_garbage_is_refused() {
+        for name in supported_codecs() {
+            let codec = codec_by_name(name).unwrap();
+            let bytes = codec.encode_request(&request()).unwrap();
+            assert!(codec.decode_request(&bytes[..bytes.len() / 2]).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_deep_nesting_is_refused() {
+        // One element arrays inside of each other, around the integer 1. It is written the same way in both formats.
+        let nested = |depth: usize, array: u8| {
+            let mut bytes = vec![array; depth];
+            bytes.push(0x01);
+            bytes
+        };
+
+        for depth in &[MAX_DEPTH + 1, 100_000] {
+            assert!(MessagePackCodec::default().decode_request(&nested(*depth, 0x91)).is_err());
+            assert!(CborCodec::default().decode_request(&nested(*depth, 0x81)).is_err());
+        }
+        assert!(MessagePackCodec::read(&nested(MAX_DEPTH, 0x91)).is_ok());
+        // serde_cbor allows deeper nesting than we do.
+        assert!(cbor_to_tree(serde_cbor::from_slice(&nested(MAX_DEPTH, 0x81)).unwrap(), 0).is_ok());
+        assert!(cbor_to_tree(serde_cbor::from_slice(&nested(MAX_DEPTH + 1, 0x81)).unwrap(), 0).is_err());
+    }
+
+    #[test]
+    fn test_cbor_integers_out_of_range_are_refused() {
+        // -2^64 and 2^64 - 1 are valid CBOR. Only the second fits in a Tree.
+        let lowest = serde_cbor::from_slice(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert!(format!("{:?}", cbor_to_tree(lowest, 0).unwrap_err()).contains("out of range"));
+        let highest = serde_cbor::from_slice(&[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).unwrap();
+        assert_eq!(cbor_to_tree(highest, 0).unwrap(), Tree::UInt(u64::max_value()));
+    }
+}
@@ -1,15 +1,65 @@
 use failure::Error;
 use crate::autogen_protobuf::transport::*;
+use crate::merge::ChangeMerger;
 use hashbrown::HashMap;
 
 pub trait CommonStructureFunctions {
     fn process_struct(&mut self, data: ProcessStructData) -> Result<Vec<Event>, Error>; 
+
+    /// Called when a model this module subscribed to has changed.
+    fn model_changed(&mut self, _data: ModelChangedData) -> Result<Vec<Event>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Return data.reply(...) among the events. An error is sent back to the caller as the reply.
+    fn call(&mut self, data: CallData) -> Result<Vec<Event>, Error> {
+        Err(failure::format_err!("Method {:?} is not supported!", data.method))
+    }
+
+    /// Called with the reply to a call this module made.
+    fn reply(&mut self, data: ReplyData) -> Result<Vec<Event>, Error> {
+        log::warn!("Reply {:?} was not handled!", data.correlationId);
+        Ok(Vec::new())
+    }
 }
 
 pub trait CommonModelFunctions {
     fn constructor(&mut self, data: ConstructorData) -> Result<Vec<Event>, Error>;
     fn destructor(&mut self, data: DestructorData) -> Result<Vec<Event>, Error>;
+    /// Return a ModelChangedData event with the changes that were actually applied. Only those are sent to subscribers.
     fn update_model(&mut self, data: UpdateModelData) -> Result<Vec<Event>, Error>;
+
+    /// Return an object event for every object. Handlers that cannot list their objects return nothing.
+    fn list(&mut self, _data: ListObjectsData) -> Result<Vec<Event>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Return an object event for a single object.
+    fn get(&mut self, data: GetObjectData) -> Result<Vec<Event>, Error> {
+        Err(failure::format_err!("Cannot get {:?}. Its handler does not support queries!", data.id))
+    }
+
+    /// Return the events that would undo `event` if it were applied right now. Used to roll back transactions.
+    /// Handlers that cannot be rolled back return nothing.
+    fn inverse(&self, _event: &Event) -> Result<Vec<Event>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Called when a model this module subscribed to has changed.
+    fn model_changed(&mut self, _data: ModelChangedData) -> Result<Vec<Event>, Error> {
+        Ok(Vec::new())
+    }
+
+    /// Return data.reply(...) among the events. An error is sent back to the caller as the reply.
+    fn call(&mut self, data: CallData) -> Result<Vec<Event>, Error> {
+        Err(failure::format_err!("Method {:?} is not supported!", data.method))
+    }
+
+    /// Called with the reply to a call this module made.
+    fn reply(&mut self, data: ReplyData) -> Result<Vec<Event>, Error> {
+        log::warn!("Reply {:?} was not handled!", data.correlationId);
+        Ok(Vec::new())
+    }
 }
 
 pub trait Modifiable {
@@ -18,10 +68,26 @@ pub trait Modifiable {
     fn get_all_model_changes(&self) -> Vec<ModelDataChanges>;
     fn get_all_struct_changes(&self) -> Vec<StructDataChanges>;
     fn get_all_sub_object_ids(&self) -> Vec<(Id, TypeDescriptor)>;
+    /// Serialize every property with all of them marked dirty. Applying this with modify(...) restores the object.
+    /// Models that return None cannot have their changes rolled back, and get(...) returns them without state.
+    fn get_state(&self) -> Option<StructDataChanges> {
+        None
+    }
 }
 
+#[derive(Default)]
 pub struct ModelInterface<M: Default + Modifiable> {
     objects: HashMap<Id, M>,
+    // The type each object was constructed as.
+    descriptors: HashMap<Id, TypeDescriptor>,
+    merger: ChangeMerger,
+}
+
+impl<M> ModelInterface<M> where M: Default + Modifiable {
+    /// Use this to choose how each property merges when changes arrive from other replicas.
+    pub fn merger_mut(&mut self) -> &mut ChangeMerger {
+        &mut self.merger
+    }
 }
 
 /// This is a standard model interface. All models will have a hashmap of objects. 
@@ -47,6 +113,7 @@ impl<M> CommonModelFunctions for ModelInterface<M> where M: Default + Modifiable
         if let Some(_old_data) = self.objects.insert(data.id.clone(), obj) {
             log::warn!("Model {:?} has already been created! It should have been removed!... wierd.", data);
         }
+        self.descriptors.insert(data.id, data.descriptor);
 
         Ok(events)
     }
@@ -57,6 +124,8 @@ impl<M> CommonModelFunctions for ModelInterface<M> where M: Default + Modifiable
             None => return Err(failure::format_err!("Model {:?} does not exist to be removed!", data)),
             Some(obj) => obj,
         };
+        self.descriptors.remove(&data.id);
+        self.merger.remove(&data.id);
 
         let events: Vec<Event> = obj.get_all_sub_object_ids().iter()
             .map(|(id, descriptor)| Event::new(DestructorData{
@@ -69,7 +138,20 @@ impl<M> CommonModelFunctions for ModelInterface<M> where M: Default + Modifiable
     /// Update the "data only". Return any events that are necessary due to the sideeffects of update_model(...)
     fn update_model(&mut self, data: UpdateModelData) -> Result<Vec<Event>, Error> {
         let obj = self.objects.get_mut(&data.id).ok_or(failure::format_err!("Cannot update model. Missing {:?}", data))?;
-        obj.modify(&data.changes);
+
+        // Local changes are stamped here. Changes from other replicas may have been beaten by newer ones, or may have already been applied.
+        // Rollbacks only reach us from the node's own transactions. They put the state back as it was, merge history included.
+        let changes = if data.changes.rollback == Some(true) {
+            self.merger.restore(&data.changes);
+            data.changes
+        } else {
+            match self.merger.merge(&data.changes) {
+                Some(changes) => changes,
+                None => return Ok(Vec::new()),
+            }
+        };
+        obj.modify(&changes);
+        let applied = Event::new(ModelChangedData{ changes }.into());
         let model_change_events: Vec<Event> = obj.get_all_model_changes().iter()
             .map(|changes| Event::new(UpdateModelData{
                 id: changes.id.clone(),
@@ -79,10 +161,83 @@ impl<M> CommonModelFunctions for ModelInterface<M> where M: Default + Modifiable
         let mut struct_change_events: Vec<Event> = obj.get_all_struct_changes().iter()
             .map(|changes| Event::new(ProcessStructData{
                 changes: changes.clone(),
+                rpcReply: None,
             }.into())).collect();
             
-        let mut events = model_change_events;
+        let mut events = vec![applied];
+        events.extend(model_change_events);
         events.append(&mut struct_change_events);
         Ok(events)
     }
+
+    /// Return an object event for every object, without its state.
+    fn list(&mut self, data: ListObjectsData) -> Result<Vec<Event>, Error> {
+        let events: Vec<Event> = self.descriptors.iter()
+            .filter(|(_id, descriptor)| match &data.descriptor {
+                Some(wanted) => &wanted == descriptor,
+                None => true,
+            })
+            .map(|(id, descriptor)| Event::new(ObjectData{
+                id: id.clone(),
+                descriptor: descriptor.clone(),
+                state: None,
+                moduleId: None,
+            }.into())).collect();
+        Ok(events)
+    }
+
+    /// Return a single object event along with the object's current state.
+    fn get(&mut self, data: GetObjectData) -> Result<Vec<Event>, Error> {
+        let obj = self.objects.get(&data.id).ok_or(failure::format_err!("Cannot get model. Missing {:?}", data))?;
+        Ok(vec![Event::new(ObjectData{
+            id: data.id,
+            descriptor: data.descriptor,
+            state: obj.get_state(),
+            moduleId: None,
+        }.into())])
+    }
+
+    /// Constructors are undone by destructors. Updates and destructors are undone by restoring the current state, along with its merge history.
+    fn inverse(&self, event: &Event) -> Result<Vec<Event>, Error> {
+        let inverse = match &event.data {
+            mod_Event::OneOfdata::constructor(data) => vec![
+                Event::new(DestructorData{
+                    id: data.id.clone(),
+                    descriptor: data.descriptor.clone(),
+                }.into()),
+            ],
+            mod_Event::OneOfdata::destructor(data) => {
+                let obj = self.objects.get(&data.id).ok_or(failure::format_err!("Cannot find inverse. Missing {:?}", data))?;
+                let mut inverse = vec![
+                    Event::new(ConstructorData{
+                        id: data.id.clone(),
+                        descriptor: data.descriptor.clone(),
+                        serializedData: None,
+                    }.into()),
+                ];
+                // Without a snapshot the object comes back with its defaults.
+                if let Some(state) = obj.get_state() {
+                    inverse.push(Event::new(UpdateModelData{
+                        id: data.id.clone(),
+                        changes: self.merger.snapshot(&data.id, state),
+                    }.into()));
+                }
+                inverse
+            },
+            mod_Event::OneOfdata::update_model(data) => {
+                let obj = self.objects.get(&data.id).ok_or(failure::format_err!("Cannot find inverse. Missing {:?}", data))?;
+                match obj.get_state() {
+                    Some(state) => vec![
+                        Event::new(UpdateModelData{
+                            id: data.id.clone(),
+                            changes: self.merger.snapshot(&data.id, state),
+                        }.into()),
+                    ],
+                    None => Vec::new(),
+                }
+            },
+            _ => Vec::new(),
+        };
+        Ok(inverse)
+    }
 }
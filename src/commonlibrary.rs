@@ -1,28 +1,32 @@
 #[cfg(not(target_arch = "wasm32"))]
 use std::path::PathBuf;
 use crate::autogen_protobuf::transport::*;
+use crate::codec::Codec;
 use failure::Error;
 use hashbrown::HashMap;
 
 pub trait CommonFFI {
-    fn call_ffi_handle_request(&self, request: &RequestTransport) -> Result<ReturnTransport, Error>;
+    /// The codec must be the one agreed on during negotiation.
+    fn call_ffi_handle_request(&self, request: &RequestTransport, codec: &Codec) -> Result<ReturnTransport, Error>;
     fn call_ffi_init(&self) -> Result<(), Error>;
+    /// Send the schema versions we support and receive the ones the plugin supports.
+    fn call_ffi_negotiate(&self, supported: &SchemaHandshake) -> Result<SchemaHandshake, Error>;
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 impl CommonFFI for libloading::Library {
     // TODO: Handle c-style ffi
-    fn call_ffi_handle_request(&self, request: &RequestTransport) -> Result<ReturnTransport, Error> {
+    fn call_ffi_handle_request(&self, request: &RequestTransport, codec: &Codec) -> Result<ReturnTransport, Error> {
         log::trace!("Calling FFI function 'ffi_handle_request(...)'...");
 
-        let bytes = quick_protobuf::serialize_into_vec(request)?;
+        let bytes = codec.encode_request(request)?;
 
         let from_ffi = unsafe {
             let handle_request: libloading::Symbol<unsafe extern fn(&[u8]) -> Vec<u8>> = self.get(b"ffi_handle_request")?;
             handle_request(&bytes)
         };
 
-        let ret: crate::ReturnTransport = quick_protobuf::deserialize_from_slice(&from_ffi)?;
+        let ret = codec.decode_return(&from_ffi)?;
         log::trace!("...Received from FFI: {:?}", ret);
         Ok(ret)
     }
@@ -36,23 +40,42 @@ impl CommonFFI for libloading::Library {
         log::debug!("...init() successful!");
         Ok(())
     }
+
+    fn call_ffi_negotiate(&self, supported: &SchemaHandshake) -> Result<SchemaHandshake, Error> {
+        log::debug!("Calling FFI function 'ffi_negotiate(...)'...");
+
+        let bytes = quick_protobuf::serialize_into_vec(supported)?;
+
+        let from_ffi = unsafe {
+            let negotiate: libloading::Symbol<unsafe extern fn(&[u8]) -> Vec<u8>> = self.get(b"ffi_negotiate")?;
+            negotiate(&bytes)
+        };
+
+        let ret: SchemaHandshake = quick_protobuf::deserialize_from_slice(&from_ffi)?;
+        log::debug!("...Plugin supports {:?}", ret);
+        Ok(ret)
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 pub trait PluginLoader {
     fn load_and_cache_plugin(&mut self, path: &PathBuf) -> Result<(), Error> {
-        if !path.exists() {
-            return Err(failure::format_err!("Failed to load dynamic library. {:?} does not exist!", path));
-        }
-
-        let ext = path.extension().ok_or(failure::format_err!("Cannot determine extension for {:?}", path))?;
-        if ext == "wasm" {
+        if is_webasm(path)? {
             self.load_and_cache_webasm(path)
         } else {
             self.load_and_cache_dll(path)
         }
     }
 
+    /// Load a dll or wasm library without caching it.
+    fn load_plugin(&self, path: &PathBuf) -> Result<Box<CommonFFI>, Error> {
+        if is_webasm(path)? {
+            self.load_webasm(path)
+        } else {
+            self.load_dll(path)
+        }
+    }
+
     fn load_dll(&self, path: &PathBuf) -> Result<Box<CommonFFI>, Error> {
         if !path.exists() {
             return Err(failure::format_err!("Failed to load dynamic library. {:?} does not exist!", path));
@@ -82,6 +105,16 @@ pub trait PluginLoader {
     fn load_and_cache_webasm(&mut self, path: &PathBuf) -> Result<(), Error>;
 }
 
+#[cfg(not(target_arch = "wasm32"))]
+fn is_webasm(path: &PathBuf) -> Result<bool, Error> {
+    if !path.exists() {
+        return Err(failure::format_err!("Failed to load dynamic library. {:?} does not exist!", path));
+    }
+
+    let ext = path.extension().ok_or(failure::format_err!("Cannot determine extension for {:?}", path))?;
+    Ok(ext == "wasm")
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 impl PluginLoader for HashMap<ModuleId, Box<CommonFFI>> {
     fn load_and_cache_dll(&mut self, path: &PathBuf) -> Result<(), Error> {
@@ -0,0 +1,311 @@
+//! Finds other nodes on the local network without a central registry.
+//! Each node multicasts a PeerAnnouncement with its node id, the types it handles, and the address its TcpTransportServer listens on.
+//! Peers that stop announcing are forgotten. update_router(...) connects to new peers as routing neighbours and drops forgotten ones.
+//! Announcements are not authenticated. Anyone on the network can announce any node id, so give Discovery a NoiseConfig
+//! that trusts only known peers before letting it connect to them. Without one, update_router(...) connects in plain text to whoever announced.
+use crate::autogen_protobuf::transport::*;
+use crate::noise::NoiseConfig;
+use crate::routing::RoutingNode;
+use crate::tcptransport::TcpTransporter;
+
+use failure::Error;
+use hashbrown::HashMap;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// In the administratively scoped range, so announcements stay on the local network.
+pub const DEFAULT_MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 80, 76);
+pub const DEFAULT_DISCOVERY_PORT: u16 = 7645;
+pub const DEFAULT_PEER_TTL_MS: u64 = 10_000;
+
+/// Longer ttls are cut down to this, so an announcement cannot keep a peer around forever.
+pub const MAX_PEER_TTL_MS: u64 = 60 * 60 * 1000;
+
+/// update_router(...) gives up on a peer that does not accept the connection within this time.
+/// Peers are connected to in parallel, so a tick waits for this at most once.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Announcements must fit in one datagram.
+const MAX_ANNOUNCEMENT_SIZE: usize = 65_507;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub node_id: String,
+    pub address: SocketAddr,
+    pub descriptors: Vec<TypeDescriptor>,
+}
+
+#[derive(Default)]
+pub struct PeerTable {
+    peers: HashMap<String, (Peer, Instant)>,
+}
+
+impl PeerTable {
+    /// Returns the peer if it was not known before. `sender` is where the announcement came from.
+    /// A peer may only announce an address on its own host, so nobody can point us at someone else's server.
+    pub fn update(&mut self, announcement: &PeerAnnouncement, sender: IpAddr) -> Result<Option<Peer>, Error> {
+        let mut address: SocketAddr = announcement.address.parse()?;
+        if address.ip().is_unspecified() {
+            address.set_ip(sender);
+        } else if address.ip() != sender {
+            return Err(failure::format_err!("{:?} announced {:?}, which is not its own address!", sender, address));
+        }
+
+        let peer = Peer {
+            node_id: announcement.nodeId.clone(),
+            address,
+            descriptors: announcement.descriptors.clone(),
+        };
+        let expires = Instant::now() + Duration::from_millis(std::cmp::min(announcement.ttlMs, MAX_PEER_TTL_MS));
+
+        // A peer that restarted on another address is treated as new.
+        let is_new = match self.peers.get(&peer.node_id) {
+            Some((existing, _)) => existing.address != peer.address,
+            None => true,
+        };
+        self.peers.insert(peer.node_id.clone(), (peer.clone(), expires));
+
+        if is_new {
+            return Ok(Some(peer));
+        }
+        Ok(None)
+    }
+
+    /// Forget peers that have not announced in time. Returns the forgotten peers.
+    pub fn expire(&mut self) -> Vec<Peer> {
+        let now = Instant::now();
+        let expired: Vec<String> = self.peers.iter()
+            .filter(|(_, (_, expires))| *expires <= now)
+            .map(|(node_id, _)| node_id.clone())
+            .collect();
+
+        expired.iter()
+            .filter_map(|node_id| self.peers.remove(node_id))
+            .map(|(peer, _)| peer)
+            .collect()
+    }
+
+    pub fn get(&self, node_id: &str) -> Option<&Peer> {
+        self.peers.get(node_id).map(|(peer, _)| peer)
+    }
+
+    pub fn peers(&self) -> Vec<&Peer> {
+        self.peers.values().map(|(peer, _)| peer).collect()
+    }
+
+    /// Every peer that handles the descriptor.
+    pub fn find(&self, descriptor: &TypeDescriptor) -> Vec<&Peer> {
+        self.peers.values()
+            .map(|(peer, _)| peer)
+            .filter(|peer| peer.descriptors.contains(descriptor))
+            .collect()
+    }
+}
+
+pub struct Discovery {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    announcement: PeerAnnouncement,
+    table: PeerTable,
+    encryption: Option<NoiseConfig>,
+}
+
+impl Discovery {
+    /// Join the default group on every interface. `address` is where this node's TcpTransportServer listens.
+    pub fn join(node_id: &str, address: SocketAddr) -> Result<Self, Error> {
+        Self::join_group(node_id, address, DEFAULT_MULTICAST_GROUP, DEFAULT_DISCOVERY_PORT, Ipv4Addr::UNSPECIFIED)
+    }
+
+    /// Use Ipv4Addr::LOCALHOST as the interface to only find nodes on this machine.
+    pub fn join_group(node_id: &str, address: SocketAddr, group: Ipv4Addr, port: u16, interface: Ipv4Addr) -> Result<Self, Error> {
+        let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+        // Every node on this machine listens on the same port.
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SockAddr::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)))?;
+        socket.join_multicast_v4(&group, &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+
+        let socket = socket.into_udp_socket();
+        socket.set_nonblocking(true)?;
+        log::debug!("Joined discovery group {:?}:{} as {:?}", group, port, node_id);
+
+        let announcement = PeerAnnouncement::new(node_id.to_string(), address.to_string(), Vec::new(), DEFAULT_PEER_TTL_MS);
+        Ok(Discovery{ socket, group: SocketAddrV4::new(group, port), announcement, table: PeerTable::default(), encryption: None })
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.announcement.nodeId
+    }
+
+    /// The types announced to other nodes.
+    pub fn set_descriptors(&mut self, descriptors: Vec<TypeDescriptor>) {
+        self.announcement.descriptors = descriptors;
+    }
+
+    /// How long peers remember this node. Announce more often than this.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.announcement.ttlMs = ttl.as_millis() as u64;
+    }
+
+    pub fn table(&self) -> &PeerTable {
+        &self.table
+    }
+
+    /// Connect to peers with a Noise handshake. Only peers whose static key the config trusts become neighbours.
+    pub fn with_encryption(mut self, config: NoiseConfig) -> Self {
+        self.encryption = Some(config);
+        self
+    }
+
+    pub fn announce(&self) -> Result<(), Error> {
+        let bytes = quick_protobuf::serialize_into_vec(&self.announcement)?;
+        if bytes.len() > MAX_ANNOUNCEMENT_SIZE {
+            return Err(failure::format_err!("Announcement of {} bytes does not fit in a datagram!", bytes.len()));
+        }
+        self.socket.send_to(&bytes, self.group)?;
+        Ok(())
+    }
+
+    /// Read every announcement that has arrived. Returns peers that were not known before. Never blocks.
+    pub fn poll(&mut self) -> Result<Vec<Peer>, Error> {
+        let mut discovered = Vec::new();
+        let mut buf = vec![0u8; MAX_ANNOUNCEMENT_SIZE];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            let announcement: PeerAnnouncement = match quick_protobuf::deserialize_from_slice(&buf[..len]) {
+                Ok(announcement) => announcement,
+                Err(e) => {
+                    log::warn!("Ignoring announcement from {:?}! {:?}", from, e);
+                    continue;
+                },
+            };
+
+            if announcement.nodeId == self.announcement.nodeId {
+                continue; // Our own announcement looped back.
+            }
+
+            match self.table.update(&announcement, from.ip()) {
+                Ok(Some(peer)) => {
+                    log::debug!("Discovered {:?} at {:?}", peer.node_id, peer.address);
+                    discovered.push(peer);
+                },
+                Ok(None) => (),
+                Err(e) => log::warn!("Ignoring announcement from {:?}! {:?}", from, e),
+            }
+        }
+        Ok(discovered)
+    }
+
+    /// Forget peers that stopped announcing. Returns the forgotten peers.
+    pub fn expire(&mut self) -> Vec<Peer> {
+        self.table.expire()
+    }
+
+    /// Connect to newly discovered peers as routing neighbours, and remove peers that were forgotten.
+    /// The router learns which modules each peer serves from its route advertisements.
+    /// The connections are only authenticated if with_encryption(...) was used. See the note at the top of this file.
+    pub fn update_router(&mut self, router: &mut RoutingNode) -> Result<(), Error> {
+        for peer in self.expire() {
+            log::debug!("Lost peer {:?}", peer.node_id);
+            router.remove_neighbour(&peer.node_id);
+        }
+
+        // Each peer gets its own thread, so peers that announced and then went away only hold up their own connection.
+        let connecting: Vec<_> = self.poll()?.into_iter()
+            .map(|peer| {
+                let encryption = self.encryption.clone();
+                std::thread::spawn(move || {
+                    let connected = match &encryption {
+                        Some(config) => TcpTransporter::connect_encrypted_timeout(&peer.address, CONNECT_TIMEOUT, config),
+                        None => TcpTransporter::connect_timeout(&peer.address, CONNECT_TIMEOUT),
+                    };
+                    (peer, connected)
+                })
+            })
+            .collect();
+
+        for handle in connecting {
+            let (peer, connected) = handle.join()
+                .map_err(|_| failure::format_err!("Thread connecting to a peer panicked!"))?;
+
+            // It may have restarted somewhere else.
+            if router.has_neighbour(&peer.node_id) {
+                router.remove_neighbour(&peer.node_id);
+            }
+
+            match connected {
+                Ok(transporter) => router.add_neighbour(&peer.node_id, transporter),
+                Err(e) => log::warn!("Unable to connect to peer {:?} at {:?}! {:?}", peer.node_id, peer.address, e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A port and group no other test run is using, so parallel runs do not hear each other.
+    fn unused_group() -> (Ipv4Addr, u16) {
+        let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        (Ipv4Addr::new(239, 255, (port >> 8) as u8, port as u8), port)
+    }
+
+    fn announcement(address: &str, ttl_ms: u64) -> PeerAnnouncement {
+        PeerAnnouncement::new("peer".to_string(), address.to_string(), Vec::new(), ttl_ms)
+    }
+
+    #[test]
+    fn test_peers_only_announce_their_own_address() {
+        let sender: IpAddr = "10.0.0.5".parse().unwrap();
+        let mut table = PeerTable::default();
+        assert!(table.update(&announcement("10.0.0.6:7001", 1000), sender).is_err());
+        assert!(table.get("peer").is_none());
+
+        let peer = table.update(&announcement("0.0.0.0:7001", 1000), sender).unwrap().unwrap();
+        assert_eq!(peer.address, "10.0.0.5:7001".parse::<SocketAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_huge_ttl_is_clamped() {
+        let mut table = PeerTable::default();
+        table.update(&announcement("127.0.0.1:7001", u64::max_value()), IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap();
+        assert!(table.expire().is_empty());
+        assert!(table.get("peer").is_some());
+    }
+
+    #[test]
+    fn test_loopback_discovery() {
+        let (group, port) = unused_group();
+        let mut a = Discovery::join_group("a", "127.0.0.1:7001".parse().unwrap(), group, port, Ipv4Addr::LOCALHOST).unwrap();
+        let mut b = Discovery::join_group("b", "127.0.0.1:7002".parse().unwrap(), group, port, Ipv4Addr::LOCALHOST).unwrap();
+        let descriptor = TypeDescriptor::new("alias".to_string(), "Model".to_string());
+        a.set_descriptors(vec![descriptor.clone()]);
+
+        a.announce().unwrap();
+        let mut discovered = Vec::new();
+        for _ in 0..100 {
+            discovered.append(&mut b.poll().unwrap());
+            if !discovered.is_empty() { break; }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered[0].node_id, "a");
+        assert_eq!(discovered[0].address, "127.0.0.1:7001".parse::<SocketAddr>().unwrap());
+        assert_eq!(b.table().find(&descriptor).len(), 1);
+
+        // a hears its own announcement, but never lists itself.
+        assert!(a.poll().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,611 @@
+//! Dynamic messages decode protobuf payloads using a .proto file that was loaded at runtime.
+//! Routers, debuggers and bridges can look inside payloads that they were not compiled against.
+use crate::protoparser::{EnumDef, FieldDef, Label, MessageDef, ProtoFile};
+
+use failure::Error;
+use quick_protobuf::{MessageRead, MessageWrite};
+
+// Messages nested deeper than this are refused, so a hostile payload cannot overflow the stack.
+pub const MAX_DEPTH: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(i64),
+    UInt(u64),
+    Float(f32),
+    Double(f64),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+    /// The name is None if the number is not declared in the schema.
+    Enum(i32, Option<String>),
+    Message(DynamicMessage),
+    List(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DynamicMessage {
+    /// The full name of the message type. Nested messages are written as Outer.Inner
+    pub name: String,
+    /// Fields that were set, in schema order.
+    pub fields: Vec<(String, Value)>,
+}
+
+impl DynamicMessage {
+    pub fn new(name: &str) -> Self {
+        DynamicMessage{ name: name.to_string(), fields: Vec::new() }
+    }
+
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        self.fields.iter().find(|(name, _)| name == field).map(|(_, value)| value)
+    }
+
+    /// Follow nested messages. For example "changes.descriptor.structure"
+    pub fn get_path(&self, path: &str) -> Option<&Value> {
+        let mut parts = path.split('.');
+        let mut value = self.get(parts.next()?)?;
+        for part in parts {
+            value = match value {
+                Value::Message(message) => message.get(part)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    pub fn set(&mut self, field: &str, value: Value) {
+        match self.fields.iter_mut().find(|(name, _)| name == field) {
+            Some(existing) => existing.1 = value,
+            None => self.fields.push((field.to_string(), value)),
+        }
+    }
+}
+
+/// What a field's type name refers to once it has been resolved.
+pub enum FieldType<'a> {
+    Scalar(&'a str),
+    /// The full message name, its definition, and whether it came from a proto3 file.
+    Message(String, &'a MessageDef, bool),
+    Enum(&'a EnumDef),
+    Map(String, String),
+}
+
+/// A set of parsed .proto files. Add imported files so that their types can be found.
+#[derive(Clone, Debug, Default)]
+pub struct DynamicSchema {
+    files: Vec<ProtoFile>,
+}
+
+impl DynamicSchema {
+    pub fn new(file: ProtoFile) -> Self {
+        DynamicSchema{ files: vec![file] }
+    }
+
+    pub fn parse(source: &str) -> Result<Self, Error> {
+        Ok(DynamicSchema::new(ProtoFile::parse(source)?))
+    }
+
+    pub fn add_file(&mut self, file: ProtoFile) {
+        self.files.push(file);
+    }
+
+    pub fn has_message(&self, structure: &str) -> bool {
+        self.find_message(structure).is_some()
+    }
+
+    pub fn decode(&self, structure: &str, bytes: &[u8]) -> Result<DynamicMessage, Error> {
+        let (message, _) = self.find_message(structure)
+            .ok_or(failure::format_err!("Schema does not declare {:?}!", structure))?;
+        self.decode_message(structure, message, bytes, 0)
+    }
+
+    pub fn encode(&self, message: &DynamicMessage) -> Result<Vec<u8>, Error> {
+        let (def, proto3) = self.find_message(&message.name)
+            .ok_or(failure::format_err!("Schema does not declare {:?}!", message.name))?;
+        let mut out = Vec::new();
+        self.encode_message(&message.name, def, proto3, message, &mut out)?;
+        Ok(out)
+    }
+
+    /// Convert a message that has generated code, such as a RequestTransport.
+    pub fn from_generated<M: MessageWrite>(&self, structure: &str, message: &M) -> Result<DynamicMessage, Error> {
+        let bytes = quick_protobuf::serialize_into_vec(message)?;
+        self.decode(structure, strip_length_prefix(&bytes)?)
+    }
+
+    pub fn to_generated<M>(&self, message: &DynamicMessage) -> Result<M, Error>
+        where M: for<'a> MessageRead<'a> {
+        let bytes = add_length_prefix(&self.encode(message)?);
+        Ok(quick_protobuf::deserialize_from_slice(&bytes)?)
+    }
+
+    /// The message definition, and whether it came from a proto3 file.
+    pub fn find_message(&self, path: &str) -> Option<(&MessageDef, bool)> {
+        let path = path.trim_start_matches('.');
+        self.files.iter()
+            .filter_map(|file| file.find_message(path).map(|message| (message, file.is_proto3())))
+            .next()
+    }
+
+    fn find_enum(&self, path: &str) -> Option<&EnumDef> {
+        let path = path.trim_start_matches('.');
+        for file in &self.files {
+            let path = match &file.package {
+                Some(package) if path.starts_with(&format!("{}.", package)) => &path[package.len() + 1..],
+                _ => path,
+            };
+
+            let found = match path.rfind('.') {
+                None => file.enums.iter().find(|def| def.name == path),
+                Some(split) => file.find_message(&path[..split])
+                    .and_then(|message| message.enums.iter().find(|def| def.name == path[split + 1..])),
+            };
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    /// Resolve a field's type name the way protoc does: from the innermost scope outward.
+    pub fn field_type<'a>(&'a self, scope: &str, typ: &'a str) -> Result<FieldType<'a>, Error> {
+        if crate::protoparser::is_scalar(typ) {
+            return Ok(FieldType::Scalar(typ));
+        }
+
+        if typ.starts_with("map<") {
+            let inner = &typ[4..typ.len() - 1];
+            let mut parts = inner.splitn(2, ',');
+            let key = parts.next().unwrap_or_default().to_string();
+            let value = parts.next().unwrap_or_default().to_string();
+            return Ok(FieldType::Map(key, value));
+        }
+
+        let mut candidates = Vec::new();
+        if typ.starts_with('.') {
+            candidates.push(typ.to_string());
+        } else {
+            let mut scope: Vec<&str> = scope.split('.').collect();
+            loop {
+                let prefix = scope.join(".");
+                candidates.push(if prefix.is_empty() { typ.to_string() } else { format!("{}.{}", prefix, typ) });
+                if scope.pop().is_none() { break; }
+            }
+        }
+
+        for candidate in candidates {
+            if let Some((message, proto3)) = self.find_message(&candidate) {
+                return Ok(FieldType::Message(candidate.trim_start_matches('.').to_string(), message, proto3));
+            }
+            if let Some(def) = self.find_enum(&candidate) {
+                return Ok(FieldType::Enum(def));
+            }
+        }
+
+        Err(failure::format_err!("Cannot resolve type {:?} in {:?}!", typ, scope))
+    }
+
+    fn decode_message(&self, name: &str, def: &MessageDef, bytes: &[u8], depth: usize) -> Result<DynamicMessage, Error> {
+        if depth > MAX_DEPTH {
+            return Err(failure::format_err!("{:?} is nested more than {} messages deep!", name, MAX_DEPTH));
+        }
+
+        let mut message = DynamicMessage::new(name);
+        let mut reader = Reader{ bytes, pos: 0 };
+
+        while !reader.is_empty() {
+            let key = reader.varint()?;
+            let number = (key >> 3) as u32;
+            let wire_type = (key & 7) as u8;
+
+            let field = match def.field_by_number(number) {
+                Some(field) => field,
+                None => {
+                    log::trace!("Skipping unknown field {} in {:?}", number, name);
+                    reader.skip(wire_type)?;
+                    continue;
+                },
+            };
+
+            let resolved = self.field_type(name, &field.typ)?;
+            let is_packed = wire_type == 2 && field.label == Label::Repeated && match &resolved {
+                FieldType::Scalar(typ) => crate::protoparser::is_packable(typ),
+                FieldType::Enum(_) => true,
+                _ => false,
+            };
+
+            let mut values = Vec::new();
+            if is_packed {
+                let mut packed = Reader{ bytes: reader.length_delimited()?, pos: 0 };
+                let element_wire_type = match &resolved {
+                    FieldType::Scalar(typ) => scalar_wire_type(typ),
+                    _ => 0,
+                };
+                while !packed.is_empty() {
+                    values.push(self.decode_value(name, &resolved, element_wire_type, &mut packed, depth)?);
+                }
+            } else {
+                values.push(self.decode_value(name, &resolved, wire_type, &mut reader, depth)?);
+            }
+
+            for value in values {
+                add_decoded_value(&mut message, field, value);
+            }
+        }
+
+        // Keep schema order so that the same data always looks the same.
+        message.fields.sort_by_key(|(field_name, _)| def.fields.iter().position(|field| &field.name == field_name));
+        Ok(message)
+    }
+
+    fn decode_value(&self, scope: &str, resolved: &FieldType, wire_type: u8, reader: &mut Reader, depth: usize) -> Result<Value, Error> {
+        let value = match resolved {
+            FieldType::Scalar(typ) => {
+                if wire_type != scalar_wire_type(typ) {
+                    return Err(failure::format_err!("Field of type {} has wire type {}!", typ, wire_type));
+                }
+
+                match *typ {
+                    "int32" => Value::Int(reader.varint()? as i32 as i64),
+                    "int64" => Value::Int(reader.varint()? as i64),
+                    "uint32" | "uint64" => Value::UInt(reader.varint()?),
+                    "sint32" | "sint64" => {
+                        let raw = reader.varint()?;
+                        Value::Int(((raw >> 1) as i64) ^ -((raw & 1) as i64))
+                    },
+                    "bool" => Value::Bool(reader.varint()? != 0),
+                    "fixed32" => Value::UInt(u64::from(reader.fixed32()?)),
+                    "sfixed32" => Value::Int(i64::from(reader.fixed32()? as i32)),
+                    "float" => Value::Float(f32::from_bits(reader.fixed32()?)),
+                    "fixed64" => Value::UInt(reader.fixed64()?),
+                    "sfixed64" => Value::Int(reader.fixed64()? as i64),
+                    "double" => Value::Double(f64::from_bits(reader.fixed64()?)),
+                    "string" => Value::String(String::from_utf8(reader.length_delimited()?.to_vec())?),
+                    "bytes" => Value::Bytes(reader.length_delimited()?.to_vec()),
+                    other => return Err(failure::format_err!("Unknown scalar type {:?}!", other)),
+                }
+            },
+            FieldType::Enum(def) => {
+                let number = reader.varint()? as i32;
+                let name = def.values.iter().find(|(_, value)| *value == number).map(|(name, _)| name.clone());
+                Value::Enum(number, name)
+            },
+            FieldType::Message(name, def, _) => {
+                if wire_type != 2 {
+                    return Err(failure::format_err!("Message {:?} has wire type {}!", name, wire_type));
+                }
+                Value::Message(self.decode_message(name, def, reader.length_delimited()?, depth + 1)?)
+            },
+            FieldType::Map(key_type, value_type) => {
+                let mut entry = Reader{ bytes: reader.length_delimited()?, pos: 0 };
+                let key_resolved = self.field_type(scope, key_type)?;
+                let value_resolved = self.field_type(scope, value_type)?;
+                let mut key = None;
+                let mut value = None;
+                while !entry.is_empty() {
+                    let entry_key = entry.varint()?;
+                    let entry_wire_type = (entry_key & 7) as u8;
+                    match entry_key >> 3 {
+                        1 => key = Some(self.decode_value(scope, &key_resolved, entry_wire_type, &mut entry, depth)?),
+                        2 => value = Some(self.decode_value(scope, &value_resolved, entry_wire_type, &mut entry, depth)?),
+                        _ => entry.skip(entry_wire_type)?,
+                    }
+                }
+
+                let key = key.unwrap_or_else(|| default_value(&key_resolved));
+                let value = value.unwrap_or_else(|| default_value(&value_resolved));
+                Value::Map(vec![(key, value)])
+            },
+        };
+        Ok(value)
+    }
+
+    fn encode_message(&self, name: &str, def: &MessageDef, proto3: bool, message: &DynamicMessage, out: &mut Vec<u8>) -> Result<(), Error> {
+        // Encode in schema order, like generated code does.
+        for field in &def.fields {
+            let value = match message.get(&field.name) {
+                None => continue,
+                Some(value) => value,
+            };
+
+            let resolved = self.field_type(name, &field.typ)?;
+            match (field.label, value) {
+                (Label::Repeated, Value::Map(entries)) => {
+                    for (key, value) in entries {
+                        let (key_type, value_type) = match &resolved {
+                            FieldType::Map(key_type, value_type) => (key_type, value_type),
+                            _ => return Err(failure::format_err!("Field {:?} is not a map!", field.name)),
+                        };
+                        let mut entry = Vec::new();
+                        self.encode_field(name, 1, &self.field_type(name, key_type)?, key, &mut entry)?;
+                        self.encode_field(name, 2, &self.field_type(name, value_type)?, value, &mut entry)?;
+                        write_key(out, field.number, 2);
+                        write_bytes(out, &entry);
+                    }
+                },
+                (Label::Repeated, Value::List(values)) => {
+                    let packable = match &resolved {
+                        FieldType::Scalar(typ) => crate::protoparser::is_packable(typ),
+                        FieldType::Enum(_) => true,
+                        _ => false,
+                    };
+
+                    if packable && field.packed.unwrap_or(proto3) {
+                        let mut packed = Vec::new();
+                        for value in values {
+                            write_scalar(&mut packed, &resolved, value)?;
+                        }
+                        write_key(out, field.number, 2);
+                        write_bytes(out, &packed);
+                    } else {
+                        for value in values {
+                            self.encode_field(name, field.number, &resolved, value, out)?;
+                        }
+                    }
+                },
+                (Label::Repeated, _) => return Err(failure::format_err!("Repeated field {:?} must be a list!", field.name)),
+                (_, value) => self.encode_field(name, field.number, &resolved, value, out)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_field(&self, scope: &str, number: u32, resolved: &FieldType, value: &Value, out: &mut Vec<u8>) -> Result<(), Error> {
+        match resolved {
+            FieldType::Message(name, def, proto3) => {
+                let message = match value {
+                    Value::Message(message) => message,
+                    other => return Err(failure::format_err!("Expected a {:?} message but found {:?}!", name, other)),
+                };
+                let mut nested = Vec::new();
+                self.encode_message(name, def, *proto3, message, &mut nested)?;
+                write_key(out, number, 2);
+                write_bytes(out, &nested);
+            },
+            FieldType::Map(_, _) => return Err(failure::format_err!("Maps cannot be nested directly in {:?}!", scope)),
+            FieldType::Scalar(typ) => {
+                write_key(out, number, scalar_wire_type(typ));
+                write_scalar(out, resolved, value)?;
+            },
+            FieldType::Enum(_) => {
+                write_key(out, number, 0);
+                write_scalar(out, resolved, value)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+fn add_decoded_value(message: &mut DynamicMessage, field: &FieldDef, value: Value) {
+    if field.label != Label::Repeated {
+        // Singular fields: the last one on the wire wins.
+        message.set(&field.name, value);
+        return;
+    }
+
+    let existing = message.fields.iter_mut().find(|(name, _)| name == &field.name);
+    match (existing, value) {
+        (Some((_, Value::Map(entries))), Value::Map(mut new_entries)) => entries.append(&mut new_entries),
+        (Some((_, Value::List(values))), value) => values.push(value),
+        (None, Value::Map(entries)) => message.fields.push((field.name.clone(), Value::Map(entries))),
+        (None, value) => message.fields.push((field.name.clone(), Value::List(vec![value]))),
+        (Some(_), _) => log::warn!("Field {:?} mixes map and list values!", field.name),
+    }
+}
+
+fn default_value(resolved: &FieldType) -> Value {
+    match resolved {
+        FieldType::Scalar(typ) => match *typ {
+            "int32" | "int64" | "sint32" | "sint64" | "sfixed32" | "sfixed64" => Value::Int(0),
+            "uint32" | "uint64" | "fixed32" | "fixed64" => Value::UInt(0),
+            "float" => Value::Float(0.0),
+            "double" => Value::Double(0.0),
+            "bool" => Value::Bool(false),
+            "string" => Value::String(String::new()),
+            _ => Value::Bytes(Vec::new()),
+        },
+        FieldType::Enum(def) => Value::Enum(0, def.values.iter().find(|(_, value)| *value == 0).map(|(name, _)| name.clone())),
+        FieldType::Message(name, _, _) => Value::Message(DynamicMessage::new(name)),
+        FieldType::Map(_, _) => Value::Map(Vec::new()),
+    }
+}
+
+fn scalar_wire_type(typ: &str) -> u8 {
+    match typ {
+        "fixed64" | "sfixed64" | "double" => 1,
+        "string" | "bytes" => 2,
+        "fixed32" | "sfixed32" | "float" => 5,
+        _ => 0,
+    }
+}
+
+fn write_scalar(out: &mut Vec<u8>, resolved: &FieldType, value: &Value) -> Result<(), Error> {
+    let typ = match resolved {
+        FieldType::Scalar(typ) => *typ,
+        FieldType::Enum(def) => {
+            let number = match value {
+                Value::Enum(number, _) => *number,
+                Value::Int(number) => *number as i32,
+                other => return Err(failure::format_err!("Expected a {:?} enum but found {:?}!", def.name, other)),
+            };
+            write_varint(out, number as i64 as u64);
+            return Ok(());
+        },
+        _ => return Err(failure::format_err!("{:?} is not a scalar!", value)),
+    };
+
+    match (typ, value) {
+        ("int32", Value::Int(v)) | ("int64", Value::Int(v)) => write_varint(out, *v as u64),
+        ("uint32", Value::UInt(v)) | ("uint64", Value::UInt(v)) => write_varint(out, *v),
+        ("sint32", Value::Int(v)) | ("sint64", Value::Int(v)) => write_varint(out, ((*v << 1) ^ (*v >> 63)) as u64),
+        ("bool", Value::Bool(v)) => write_varint(out, *v as u64),
+        ("fixed32", Value::UInt(v)) => out.extend_from_slice(&(*v as u32).to_le_bytes()),
+        ("sfixed32", Value::Int(v)) => out.extend_from_slice(&(*v as i32).to_le_bytes()),
+        ("float", Value::Float(v)) => out.extend_from_slice(&v.to_bits().to_le_bytes()),
+        ("fixed64", Value::UInt(v)) => out.extend_from_slice(&v.to_le_bytes()),
+        ("sfixed64", Value::Int(v)) => out.extend_from_slice(&v.to_le_bytes()),
+        ("double", Value::Double(v)) => out.extend_from_slice(&v.to_bits().to_le_bytes()),
+        ("string", Value::String(v)) => write_bytes(out, v.as_bytes()),
+        ("bytes", Value::Bytes(v)) => write_bytes(out, v),
+        (typ, value) => return Err(failure::format_err!("Cannot write {:?} as {}!", value, typ)),
+    }
+    Ok(())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_key(out: &mut Vec<u8>, number: u32, wire_type: u8) {
+    write_varint(out, (u64::from(number) << 3) | u64::from(wire_type));
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// quick_protobuf::serialize_into_vec writes the length before the message. Fails if the length does not cover exactly the rest of the bytes.
+pub fn strip_length_prefix(bytes: &[u8]) -> Result<&[u8], Error> {
+    let mut reader = Reader{ bytes, pos: 0 };
+    let message = reader.length_delimited()?;
+    if !reader.is_empty() {
+        return Err(failure::format_err!("{} bytes follow the length prefixed message!", bytes.len() - reader.pos));
+    }
+    Ok(message)
+}
+
+/// Add the length that quick_protobuf::deserialize_from_slice expects before a message.
+pub fn add_length_prefix(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + 5);
+    write_varint(&mut out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bytes.len() - self.pos {
+            return Err(failure::format_err!("Unexpected end of message!"));
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(failure::format_err!("Varint is too long!"))
+    }
+
+    fn fixed32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn fixed64(&mut self) -> Result<u64, Error> {
+        let bytes = self.take(8)?;
+        let mut array = [0u8; 8];
+        array.copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(array))
+    }
+
+    fn length_delimited(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.varint()?;
+        if len > (self.bytes.len() - self.pos) as u64 {
+            return Err(failure::format_err!("Length {} is longer than the rest of the message!", len));
+        }
+        self.take(len as usize)
+    }
+
+    fn skip(&mut self, wire_type: u8) -> Result<(), Error> {
+        match wire_type {
+            0 => { self.varint()?; },
+            1 => { self.take(8)?; },
+            2 => { self.length_delimited()?; },
+            5 => { self.take(4)?; },
+            other => return Err(failure::format_err!("Unsupported wire type {}!", other)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autogen_protobuf::transport::*;
+
+    fn transport_schema() -> DynamicSchema {
+        crate::bundledschemas::transport_dynamic_schema()
+    }
+
+    #[test]
+    fn test_round_trip_generated() {
+        let schema = transport_schema();
+        let descriptor = TypeDescriptor::new("alias".to_string(), "Structure".to_string());
+        let changes = StructDataChanges::new(vec![1, 2, 3], vec!["a".to_string(), "b".to_string()], descriptor);
+        let sent = RequestTransport::new(ModuleId::new("module".to_string()), Event::new(ProcessStructData::new(changes, None).into()), None, None);
+
+        let message = schema.from_generated("RequestTransport", &sent).unwrap();
+        assert_eq!(message.get_path("moduleId.val"), Some(&Value::String("module".to_string())));
+        assert_eq!(message.get_path("event.process_struct.changes.descriptor.structure"), Some(&Value::String("Structure".to_string())));
+        let received: RequestTransport = schema.to_generated(&message).unwrap();
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn test_truncated_and_oversized_lengths() {
+        let schema = DynamicSchema::parse("message M { optional string name = 1; optional fixed64 big = 2; }").unwrap();
+        assert_eq!(schema.decode("M", &[0x0a, 0x02, b'h', b'i']).unwrap().get("name"), Some(&Value::String("hi".to_string())));
+        assert!(schema.decode("M", &[0x0a, 0x05, b'h', b'i']).is_err());
+        assert!(schema.decode("M", &[0x11, 1, 2, 3]).is_err());
+        assert!(schema.decode("M", &[0x0a, 0x80]).is_err());
+        // A length of u64::MAX must not wrap around the end of the buffer.
+        assert!(schema.decode("M", &[0x0a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01, b'h']).is_err());
+    }
+
+    #[test]
+    fn test_strip_length_prefix() {
+        assert_eq!(strip_length_prefix(&add_length_prefix(&[1, 2, 3])).unwrap(), &[1, 2, 3]);
+        assert_eq!(strip_length_prefix(&[0]).unwrap(), &[] as &[u8]);
+        assert!(strip_length_prefix(&[1, 2, 3]).is_err());
+        assert!(strip_length_prefix(&[5, 1, 2]).is_err());
+        assert!(strip_length_prefix(&[]).is_err());
+    }
+
+    #[test]
+    fn test_nesting_is_limited() {
+        let schema = DynamicSchema::parse("message Node { optional Node child = 1; }").unwrap();
+        let nested = |depth: usize| (0..depth).fold(Vec::new(), |inner, _| {
+            let mut outer = vec![0x0a];
+            write_varint(&mut outer, inner.len() as u64);
+            outer.extend(inner);
+            outer
+        });
+
+        assert!(schema.decode("Node", &nested(MAX_DEPTH)).is_ok());
+        assert!(schema.decode("Node", &nested(MAX_DEPTH + 1)).is_err());
+        assert!(schema.decode("Node", &nested(10_000)).is_err());
+    }
+}
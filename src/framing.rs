@@ -0,0 +1,331 @@
+//! Framing shared by the stream transports. Each frame is a u32 big-endian length followed by that many bytes.
+//! A request frame always gets exactly one return frame, so a connection carries one call at a time.
+use crate::autogen_protobuf::transport::*;
+use crate::codec::Codec;
+use crate::transporter::Transporter;
+
+use failure::Error;
+use std::io::{Read, Write};
+use std::sync::mpsc;
+
+/// Anything larger is treated as a corrupt stream rather than allocated.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+// The payload buffer starts at most this big, and grows as the bytes actually arrive.
+const INITIAL_FRAME_CAPACITY: usize = 64 * 1024;
+
+/// A connection that sends and receives whole messages.
+pub trait FramedConnection {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), Error>;
+    /// None when the other side closed the connection.
+    fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// Refuses to be used again after any send or receive fails. A read that timed out may have left part of a frame
+/// in the stream, so the next frame read would not be the reply to the next request.
+pub struct PoisonOnError<C: FramedConnection> {
+    connection: C,
+    poisoned: bool,
+}
+
+impl<C: FramedConnection> PoisonOnError<C> {
+    pub fn new(connection: C) -> Self {
+        PoisonOnError{ connection, poisoned: false }
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.connection
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.connection
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    fn check<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if result.is_err() {
+            self.poisoned = true;
+        }
+        result
+    }
+}
+
+impl<C: FramedConnection> FramedConnection for PoisonOnError<C> {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if self.poisoned {
+            return Err(failure::format_err!("Connection is unusable after an earlier error! Reconnect."));
+        }
+        let result = self.connection.send_frame(payload);
+        self.check(result)
+    }
+
+    fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.poisoned {
+            return Err(failure::format_err!("Connection is unusable after an earlier error! Reconnect."));
+        }
+        let result = self.connection.receive_frame();
+        self.check(result)
+    }
+}
+
+/// Frames any byte stream, such as a TcpStream or a UnixStream.
+pub struct StreamConnection<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> StreamConnection<S> {
+    pub fn new(stream: S) -> Self {
+        StreamConnection{ stream }
+    }
+
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: Read + Write> FramedConnection for StreamConnection<S> {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), Error> {
+        write_frame(&mut self.stream, payload)
+    }
+
+    fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        read_frame(&mut self.stream)
+    }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), Error> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(failure::format_err!("Frame of {} bytes is larger than the maximum of {}!", payload.len(), MAX_FRAME_SIZE));
+    }
+
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// None if the stream ended cleanly before a new frame started.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, Error> {
+    let mut len = [0u8; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match reader.read(&mut len[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(failure::format_err!("Connection closed in the middle of a frame header!")),
+            n => read += n,
+        }
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(failure::format_err!("Frame of {} bytes is larger than the maximum of {}!", len, MAX_FRAME_SIZE));
+    }
+
+    // The header alone is not trusted with a large allocation.
+    let mut payload = Vec::with_capacity(std::cmp::min(len, INITIAL_FRAME_CAPACITY));
+    reader.by_ref().take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(failure::format_err!("Connection closed after {} of {} bytes of a frame!", payload.len(), len));
+    }
+    Ok(Some(payload))
+}
+
+/// Send a request and wait for what it returns.
+pub fn request<C: FramedConnection + ?Sized>(connection: &mut C, codec: &Codec, request: &RequestTransport) -> Result<ReturnTransport, Error> {
+    connection.send_frame(&codec.encode_request(request)?)?;
+    let frame = connection.receive_frame()?
+        .ok_or(failure::format_err!("Connection closed before the request returned!"))?;
+    codec.decode_return(&frame)
+}
+
+/// Answer every request on the connection with the transporter, until the other side hangs up.
+pub fn serve_connection<C: FramedConnection + ?Sized, T: Transporter>(connection: &mut C, codec: &Codec, transporter: &mut T) -> Result<(), Error> {
+    while let Some(frame) = connection.receive_frame()? {
+        let ret = crate::pluginhandler::ffi_handle_received_bytes_with_codec(transporter, codec, &frame);
+        connection.send_frame(&ret)?;
+    }
+    Ok(())
+}
+
+type FrameAndReply = (Vec<u8>, mpsc::Sender<Vec<u8>>);
+
+enum Received {
+    Frame(FrameAndReply),
+    // The acceptor cannot go on. The server stops with this error.
+    Failed(Error),
+}
+
+// A failed accept that only concerns the connection being accepted. Any other io error means the listener itself is broken,
+// and retrying it would spin. Errors that are not io errors come from setting up a single connection.
+fn is_transient(error: &Error) -> bool {
+    use std::io::ErrorKind::*;
+    match error.downcast_ref::<std::io::Error>() {
+        Some(error) => match error.kind() {
+            ConnectionAborted | ConnectionReset | ConnectionRefused | BrokenPipe | Interrupted | WouldBlock | TimedOut | UnexpectedEof => true,
+            _ => false,
+        },
+        None => true,
+    }
+}
+
+/// Serve many connections at once. Each connection is read on its own thread, but every request is handled
+/// on the calling thread, so the transporter does not need to be Send. Returns when `incoming` runs out,
+/// or with the error as soon as accepting fails for a reason other than the connection being accepted.
+pub fn serve_connections<C, I, T>(incoming: I, codec: &Codec, transporter: &mut T) -> Result<(), Error>
+    where C: 'static + FramedConnection + Send, I: 'static + Iterator<Item = Result<C, Error>> + Send, T: Transporter {
+    serve_connections_with_setup(incoming, Ok, codec, transporter)
+}
+
+/// Like serve_connections(...), but each accepted stream is turned into a connection by `setup` on the connection's own thread.
+/// Use it for handshakes, so that a slow client cannot hold up everyone else that is connecting.
+pub fn serve_connections_with_setup<A, C, I, S, T>(incoming: I, setup: S, codec: &Codec, transporter: &mut T) -> Result<(), Error>
+    where A: 'static + Send, C: FramedConnection, I: 'static + Iterator<Item = Result<A, Error>> + Send,
+        S: 'static + Fn(A) -> Result<C, Error> + Send + Sync, T: Transporter {
+    let (frames, received) = mpsc::channel::<Received>();
+    let setup = std::sync::Arc::new(setup);
+
+    std::thread::spawn(move || {
+        for accepted in incoming {
+            match accepted {
+                Ok(accepted) => {
+                    let frames = frames.clone();
+                    let setup = setup.clone();
+                    std::thread::spawn(move || match setup(accepted) {
+                        Ok(connection) => forward_frames(connection, frames),
+                        Err(e) => log::warn!("Unable to set up connection! {:?}", e),
+                    });
+                },
+                Err(e) if is_transient(&e) => log::warn!("Unable to accept connection! {:?}", e),
+                Err(e) => {
+                    log::error!("Stopped accepting connections! {:?}", e);
+                    let _ = frames.send(Received::Failed(e));
+                    return;
+                },
+            }
+        }
+    });
+
+    // Ends once the acceptor thread and every connection thread have dropped their senders.
+    for received in received {
+        let (frame, reply) = match received {
+            Received::Frame(frame_and_reply) => frame_and_reply,
+            Received::Failed(e) => return Err(e),
+        };
+        let ret = crate::pluginhandler::ffi_handle_received_bytes_with_codec(transporter, codec, &frame);
+        if reply.send(ret).is_err() {
+            log::debug!("Connection closed before its request returned.");
+        }
+    }
+    Ok(())
+}
+
+fn forward_frames<C: FramedConnection>(mut connection: C, frames: mpsc::Sender<Received>) {
+    let (reply, replies) = mpsc::channel();
+    loop {
+        let frame = match connection.receive_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Dropping connection! {:?}", e);
+                return;
+            },
+        };
+
+        if frames.send(Received::Frame((frame, reply.clone()))).is_err() {
+            return; // The server has stopped.
+        }
+
+        let ret = match replies.recv() {
+            Ok(ret) => ret,
+            Err(_) => return,
+        };
+
+        if let Err(e) = connection.send_frame(&ret) {
+            log::warn!("Dropping connection! {:?}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::ProtobufCodec;
+    use std::io::Cursor;
+
+    // Answers nothing. The tests below never get as far as a request.
+    struct Nothing;
+
+    impl Transporter for Nothing {
+        fn transport_data(&mut self, _transport: &RequestTransport) -> ReturnTransport {
+            Vec::new().into()
+        }
+    }
+
+    // Fails the first receive, then has a frame ready.
+    struct FailsOnce {
+        failed: bool,
+    }
+
+    impl FramedConnection for FailsOnce {
+        fn send_frame(&mut self, _payload: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+            if !self.failed {
+                self.failed = true;
+                return Err(failure::format_err!("Timed out!"));
+            }
+            Ok(Some(vec![1]))
+        }
+    }
+
+    #[test]
+    fn test_frames_round_trip() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &[1, 2, 3]).unwrap();
+        write_frame(&mut stream, &[]).unwrap();
+
+        let mut stream = Cursor::new(stream);
+        assert_eq!(read_frame(&mut stream).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_frame(&mut stream).unwrap(), Some(Vec::new()));
+        assert_eq!(read_frame(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn test_truncated_frame_is_refused() {
+        let mut stream = (32 * 1024 * 1024u32).to_be_bytes().to_vec();
+        stream.extend_from_slice(&[1, 2, 3]);
+        assert!(read_frame(&mut Cursor::new(stream)).is_err());
+
+        let stream = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes().to_vec();
+        assert!(read_frame(&mut Cursor::new(stream)).is_err());
+    }
+
+    #[test]
+    fn test_connection_is_poisoned_by_an_error() {
+        let mut connection = PoisonOnError::new(FailsOnce{ failed: false });
+        assert!(connection.receive_frame().is_err());
+        assert!(connection.is_poisoned());
+        assert!(connection.receive_frame().is_err());
+        assert!(connection.send_frame(&[1]).is_err());
+    }
+
+    #[test]
+    fn test_acceptor_stops_on_listener_errors() {
+        type Accepted = Result<StreamConnection<Cursor<Vec<u8>>>, Error>;
+        let reset: Accepted = Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset").into());
+
+        // A connection that failed on its own does not stop the server. It returns once there is nothing left to accept.
+        assert!(serve_connections(vec![reset].into_iter(), &ProtobufCodec, &mut Nothing).is_ok());
+
+        // The broken listener would fail forever, so the server stops instead of retrying it.
+        let forever = std::iter::repeat_with(move || -> Accepted { Err(std::io::Error::new(std::io::ErrorKind::Other, "broken listener").into()) });
+        let err = serve_connections(forever, &ProtobufCodec, &mut Nothing).unwrap_err();
+        assert!(format!("{}", err).contains("broken listener"));
+    }
+}
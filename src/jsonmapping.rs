@@ -0,0 +1,437 @@
+//! Canonical proto3 JSON mapping for transport messages and for any schema loaded at runtime.
+//! Field names are lowerCamelCase, bytes are base64, and 64 bit integers are strings so that javascript does not round them.
+//! A serializedData payload is written as a JSON object if its descriptor's schema is known, and as base64 otherwise.
+use crate::autogen_protobuf::transport::*;
+use crate::dynamicmessage::{self, DynamicMessage, DynamicSchema, FieldType, Value};
+use crate::protoparser::Label;
+use crate::schemaregistry::SchemaRegistry;
+
+use failure::Error;
+use quick_protobuf::{MessageRead, MessageWrite};
+use serde_json::{Map, Value as Json};
+
+pub struct JsonTranscoder {
+    transport: DynamicSchema,
+    registry: SchemaRegistry,
+}
+
+impl Default for JsonTranscoder {
+    fn default() -> Self {
+        JsonTranscoder::with_registry(SchemaRegistry::default())
+    }
+}
+
+impl JsonTranscoder {
+    /// Payloads are decoded with the schemas in the registry.
+    pub fn with_registry(registry: SchemaRegistry) -> Self {
+        JsonTranscoder{ transport: crate::bundledschemas::transport_dynamic_schema(), registry }
+    }
+
+    pub fn registry_mut(&mut self) -> &mut SchemaRegistry {
+        &mut self.registry
+    }
+
+    pub fn request_to_json(&mut self, request: &RequestTransport) -> Result<String, Error> {
+        self.transport_to_json("RequestTransport", request)
+    }
+
+    pub fn request_from_json(&mut self, json: &str) -> Result<RequestTransport, Error> {
+        self.transport_from_json("RequestTransport", json)
+    }
+
+    pub fn event_to_json(&mut self, event: &Event) -> Result<String, Error> {
+        self.transport_to_json("Event", event)
+    }
+
+    pub fn event_from_json(&mut self, json: &str) -> Result<Event, Error> {
+        self.transport_from_json("Event", json)
+    }
+
+    pub fn return_to_json(&mut self, ret: &ReturnTransport) -> Result<String, Error> {
+        self.transport_to_json("ReturnTransport", ret)
+    }
+
+    pub fn return_from_json(&mut self, json: &str) -> Result<ReturnTransport, Error> {
+        self.transport_from_json("ReturnTransport", json)
+    }
+
+    /// Any message from transport.proto, given its bytes without a length prefix.
+    pub fn to_json(&mut self, structure: &str, bytes: &[u8]) -> Result<Json, Error> {
+        let message = self.transport.decode(structure, bytes)?;
+        Mapper{ schema: &self.transport, registry: Some(&mut self.registry) }.message_to_json(&message)
+    }
+
+    /// Any message from transport.proto. The bytes do not have a length prefix.
+    pub fn from_json(&mut self, structure: &str, json: &Json) -> Result<Vec<u8>, Error> {
+        let message = Mapper{ schema: &self.transport, registry: Some(&mut self.registry) }.message_from_json(structure, json)?;
+        self.transport.encode(&message)
+    }
+
+    fn transport_to_json<M: MessageWrite>(&mut self, structure: &str, message: &M) -> Result<String, Error> {
+        let message = self.transport.from_generated(structure, message)?;
+        let json = Mapper{ schema: &self.transport, registry: Some(&mut self.registry) }.message_to_json(&message)?;
+        Ok(serde_json::to_string_pretty(&json)?)
+    }
+
+    fn transport_from_json<M>(&mut self, structure: &str, json: &str) -> Result<M, Error>
+        where M: for<'a> MessageRead<'a> {
+        let json: Json = serde_json::from_str(json)?;
+        let message = Mapper{ schema: &self.transport, registry: Some(&mut self.registry) }.message_from_json(structure, &json)?;
+        self.transport.to_generated(&message)
+    }
+}
+
+/// Convert a message from any schema. serializedData payloads stay base64.
+pub fn message_to_json(schema: &DynamicSchema, message: &DynamicMessage) -> Result<Json, Error> {
+    Mapper{ schema, registry: None }.message_to_json(message)
+}
+
+/// Parse a message from any schema. Both the lowerCamelCase and the original field names are accepted.
+pub fn message_from_json(schema: &DynamicSchema, structure: &str, json: &Json) -> Result<DynamicMessage, Error> {
+    Mapper{ schema, registry: None }.message_from_json(structure, json)
+}
+
+/// The lowerCamelCase name protoc gives a field in JSON.
+pub fn json_name(field: &str) -> String {
+    let mut name = String::with_capacity(field.len());
+    let mut upper = false;
+    for c in field.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            name.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            name.push(c);
+        }
+    }
+    name
+}
+
+struct Mapper<'a> {
+    schema: &'a DynamicSchema,
+    // Used to decode serializedData payloads.
+    registry: Option<&'a mut SchemaRegistry>,
+}
+
+impl<'a> Mapper<'a> {
+    fn message_to_json(&mut self, message: &DynamicMessage) -> Result<Json, Error> {
+        let (def, _) = self.schema.find_message(&message.name)
+            .ok_or(failure::format_err!("Schema does not declare {:?}!", message.name))?;
+
+        let mut object = Map::new();
+        for (name, value) in &message.fields {
+            let field = def.field_by_name(name)
+                .ok_or(failure::format_err!("{:?} has no field {:?}!", message.name, name))?;
+
+            let json = match (name.as_str(), value) {
+                ("serializedData", Value::Bytes(bytes)) => self.payload_to_json(message, bytes),
+                _ => self.field_to_json(&message.name, &field.typ, value)?,
+            };
+            object.insert(json_name(name), json);
+        }
+        Ok(Json::Object(object))
+    }
+
+    fn field_to_json(&mut self, scope: &str, typ: &str, value: &Value) -> Result<Json, Error> {
+        let field_type = self.schema.field_type(scope, typ)?;
+        match (&field_type, value) {
+            (FieldType::Map(_, value_type), Value::Map(entries)) => {
+                let mut object = Map::new();
+                for (key, value) in entries {
+                    object.insert(map_key_to_string(key)?, self.field_to_json(scope, value_type, value)?);
+                }
+                Ok(Json::Object(object))
+            },
+            (_, Value::List(values)) => {
+                let values: Result<Vec<Json>, Error> = values.iter().map(|value| self.value_to_json(&field_type, value)).collect();
+                Ok(Json::Array(values?))
+            },
+            (_, value) => self.value_to_json(&field_type, value),
+        }
+    }
+
+    fn value_to_json(&mut self, field_type: &FieldType, value: &Value) -> Result<Json, Error> {
+        let json = match (field_type, value) {
+            (FieldType::Scalar(typ), Value::Int(v)) if is_64_bit(typ) => Json::String(v.to_string()),
+            (FieldType::Scalar(typ), Value::UInt(v)) if is_64_bit(typ) => Json::String(v.to_string()),
+            (FieldType::Scalar(_), Value::Int(v)) => Json::from(*v),
+            (FieldType::Scalar(_), Value::UInt(v)) => Json::from(*v),
+            (FieldType::Scalar(_), Value::Float(v)) => float_to_json(f64::from(*v)),
+            (FieldType::Scalar(_), Value::Double(v)) => float_to_json(*v),
+            (FieldType::Scalar(_), Value::Bool(v)) => Json::Bool(*v),
+            (FieldType::Scalar(_), Value::String(v)) => Json::String(v.clone()),
+            (FieldType::Scalar(_), Value::Bytes(v)) => Json::String(base64::encode(v)),
+            (FieldType::Enum(_), Value::Enum(_, Some(name))) => Json::String(name.clone()),
+            (FieldType::Enum(_), Value::Enum(number, None)) => Json::from(*number),
+            (FieldType::Message(_, _, _), Value::Message(message)) => self.message_to_json(message)?,
+            (_, value) => return Err(failure::format_err!("{:?} does not match its field type!", value)),
+        };
+        Ok(json)
+    }
+
+    // Payloads are written by quick_protobuf::serialize_into_vec, so they always start with their length.
+    // Falls back to base64 whenever the payload cannot be decoded, so that nothing is lost.
+    fn payload_to_json(&mut self, message: &DynamicMessage, bytes: &[u8]) -> Json {
+        let fallback = Json::String(base64::encode(bytes));
+        let (alias, structure) = match payload_descriptor(message) {
+            Some(descriptor) => descriptor,
+            None => return fallback,
+        };
+        let registry = match self.registry.as_mut() {
+            Some(registry) if registry.is_known(&alias) => registry,
+            _ => return fallback,
+        };
+
+        let decoded = registry.dynamic_schema(&alias).and_then(|schema| {
+            let payload = schema.decode(&structure, dynamicmessage::strip_length_prefix(bytes)?)?;
+            Mapper{ schema: &schema, registry: Some(&mut **registry) }.message_to_json(&payload)
+        });
+
+        match decoded {
+            Ok(json) => json,
+            Err(e) => {
+                log::warn!("Cannot decode {:?} payload from {:?}: {}", structure, alias, e);
+                fallback
+            },
+        }
+    }
+
+    fn message_from_json(&mut self, structure: &str, json: &Json) -> Result<DynamicMessage, Error> {
+        let (def, _) = self.schema.find_message(structure)
+            .ok_or(failure::format_err!("Schema does not declare {:?}!", structure))?;
+        let object = json.as_object()
+            .ok_or(failure::format_err!("Expected a JSON object for {:?}!", structure))?;
+
+        let mut message = DynamicMessage::new(structure);
+        for (key, json) in object {
+            let field = def.fields.iter().find(|field| &json_name(&field.name) == key || &field.name == key)
+                .ok_or(failure::format_err!("{:?} has no field {:?}!", structure, key))?;
+            if json.is_null() {
+                continue;
+            }
+
+            let value = match (field.name.as_str(), json) {
+                ("serializedData", Json::Object(_)) => Value::Bytes(self.payload_from_json(structure, object, json)?),
+                _ => self.field_from_json(structure, &field.typ, field.label, json)?,
+            };
+            message.set(&field.name, value);
+        }
+
+        // Keep schema order, like a decoded message.
+        message.fields.sort_by_key(|(name, _)| def.fields.iter().position(|field| &field.name == name));
+        Ok(message)
+    }
+
+    fn field_from_json(&mut self, scope: &str, typ: &str, label: Label, json: &Json) -> Result<Value, Error> {
+        let field_type = self.schema.field_type(scope, typ)?;
+        match (&field_type, json) {
+            (FieldType::Map(key_type, value_type), Json::Object(object)) => {
+                let key_type = self.schema.field_type(scope, key_type)?;
+                let mut entries = Vec::new();
+                for (key, json) in object {
+                    let key = self.value_from_json(&key_type, &Json::String(key.clone()))?;
+                    let value = self.field_from_json(scope, value_type, Label::Optional, json)?;
+                    entries.push((key, value));
+                }
+                Ok(Value::Map(entries))
+            },
+            (_, Json::Array(values)) if label == Label::Repeated => {
+                let values: Result<Vec<Value>, Error> = values.iter().map(|json| self.value_from_json(&field_type, json)).collect();
+                Ok(Value::List(values?))
+            },
+            (_, json) if label == Label::Repeated => Err(failure::format_err!("Expected a JSON array for {:?} but found {}!", typ, json)),
+            (_, json) => self.value_from_json(&field_type, json),
+        }
+    }
+
+    fn value_from_json(&mut self, field_type: &FieldType, json: &Json) -> Result<Value, Error> {
+        let value = match field_type {
+            FieldType::Scalar(typ) => match *typ {
+                "int32" | "int64" | "sint32" | "sint64" | "sfixed32" | "sfixed64" => Value::Int(json_number(json)?),
+                "uint32" | "uint64" | "fixed32" | "fixed64" => Value::UInt(json_number(json)?),
+                "float" => Value::Float(json_float(json)? as f32),
+                "double" => Value::Double(json_float(json)?),
+                "bool" => match json {
+                    Json::Bool(v) => Value::Bool(*v),
+                    Json::String(v) => Value::Bool(v.parse()?),
+                    other => return Err(failure::format_err!("Expected a bool but found {}!", other)),
+                },
+                "string" => Value::String(json.as_str().ok_or(failure::format_err!("Expected a string but found {}!", json))?.to_string()),
+                "bytes" => Value::Bytes(json_bytes(json)?),
+                other => return Err(failure::format_err!("Unknown scalar type {:?}!", other)),
+            },
+            FieldType::Enum(def) => match json {
+                Json::String(name) => {
+                    let number = def.values.iter().find(|(value_name, _)| value_name == name).map(|(_, number)| *number)
+                        .ok_or(failure::format_err!("{:?} has no value {:?}!", def.name, name))?;
+                    Value::Enum(number, Some(name.clone()))
+                },
+                json => {
+                    let number = json_number::<i32>(json)?;
+                    let name = def.values.iter().find(|(_, value)| *value == number).map(|(name, _)| name.clone());
+                    Value::Enum(number, name)
+                },
+            },
+            FieldType::Message(name, _, _) => Value::Message(self.message_from_json(name, json)?),
+            FieldType::Map(_, _) => return Err(failure::format_err!("Maps must be JSON objects!")),
+        };
+        Ok(value)
+    }
+
+    // The payload's type comes from the descriptor next to it in the same JSON object.
+    fn payload_from_json(&mut self, structure: &str, object: &Map<String, Json>, json: &Json) -> Result<Vec<u8>, Error> {
+        let descriptor = object.get("descriptor")
+            .ok_or(failure::format_err!("{:?} has a decoded serializedData but no descriptor!", structure))?;
+        let alias = descriptor.get("libraryAlias").and_then(Json::as_str)
+            .ok_or(failure::format_err!("{:?} descriptor has no libraryAlias!", structure))?;
+        let payload_structure = descriptor.get("structure").and_then(Json::as_str)
+            .ok_or(failure::format_err!("{:?} descriptor has no structure!", structure))?;
+
+        let registry = self.registry.as_mut()
+            .ok_or(failure::format_err!("Cannot encode a {:?} payload without a schema registry!", payload_structure))?;
+        let schema = registry.dynamic_schema(alias)?;
+        let payload = Mapper{ schema: &schema, registry: Some(&mut **registry) }.message_from_json(payload_structure, json)?;
+        Ok(dynamicmessage::add_length_prefix(&schema.encode(&payload)?))
+    }
+}
+
+fn payload_descriptor(message: &DynamicMessage) -> Option<(String, String)> {
+    match (message.get_path("descriptor.libraryAlias"), message.get_path("descriptor.structure")) {
+        (Some(Value::String(alias)), Some(Value::String(structure))) => Some((alias.clone(), structure.clone())),
+        _ => None,
+    }
+}
+
+fn is_64_bit(typ: &str) -> bool {
+    match typ {
+        "int64" | "uint64" | "sint64" | "fixed64" | "sfixed64" => true,
+        _ => false,
+    }
+}
+
+fn float_to_json(value: f64) -> Json {
+    if value.is_nan() {
+        Json::String("NaN".to_string())
+    } else if value.is_infinite() {
+        Json::String(if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string())
+    } else {
+        Json::from(value)
+    }
+}
+
+fn map_key_to_string(key: &Value) -> Result<String, Error> {
+    match key {
+        Value::String(key) => Ok(key.clone()),
+        Value::Int(key) => Ok(key.to_string()),
+        Value::UInt(key) => Ok(key.to_string()),
+        Value::Bool(key) => Ok(key.to_string()),
+        other => Err(failure::format_err!("{:?} cannot be a map key!", other)),
+    }
+}
+
+// Numbers may be written as JSON numbers or as strings.
+fn json_number<T>(json: &Json) -> Result<T, Error>
+    where T: std::str::FromStr, T::Err: std::fmt::Display {
+    let text = match json {
+        Json::Number(number) => number.to_string(),
+        Json::String(text) => text.clone(),
+        other => return Err(failure::format_err!("Expected a number but found {}!", other)),
+    };
+    text.parse().map_err(|e| failure::format_err!("Cannot parse {:?} as a number: {}", text, e))
+}
+
+fn json_float(json: &Json) -> Result<f64, Error> {
+    match json {
+        Json::String(text) if text == "NaN" => Ok(std::f64::NAN),
+        Json::String(text) if text == "Infinity" => Ok(std::f64::INFINITY),
+        Json::String(text) if text == "-Infinity" => Ok(std::f64::NEG_INFINITY),
+        json => json_number(json),
+    }
+}
+
+// Standard and url-safe base64 are both accepted.
+fn json_bytes(json: &Json) -> Result<Vec<u8>, Error> {
+    let text = json.as_str().ok_or(failure::format_err!("Expected base64 but found {}!", json))?;
+    match base64::decode(text) {
+        Ok(bytes) => Ok(bytes),
+        Err(_) => Ok(base64::decode_config(text, base64::URL_SAFE)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static THINGS: &str = "message Thing { required string name = 1; optional int64 count = 2; repeated bytes blobs = 3; }";
+
+    fn request(alias: &str, payload: Vec<u8>) -> RequestTransport {
+        let descriptor = TypeDescriptor::new(alias.to_string(), "Thing".to_string());
+        let changes = StructDataChanges::new(payload, vec!["name".to_string()], descriptor);
+        RequestTransport::new(ModuleId::new("module".to_string()), Event::new(ProcessStructData::new(changes, None).into()), None, None)
+    }
+
+    fn payload(request: &RequestTransport) -> &[u8] {
+        match &request.event.data {
+            mod_Event::OneOfdata::process_struct(data) => &data.changes.serializedData,
+            _ => &[],
+        }
+    }
+
+    fn thing_bytes() -> Vec<u8> {
+        let schema = DynamicSchema::parse(THINGS).unwrap();
+        let mut thing = DynamicMessage::new("Thing");
+        thing.set("name", Value::String("widget".to_string()));
+        thing.set("count", Value::Int(9_007_199_254_740_993));
+        thing.set("blobs", Value::List(vec![Value::Bytes(vec![0, 255])]));
+        dynamicmessage::add_length_prefix(&schema.encode(&thing).unwrap())
+    }
+
+    fn transcoder() -> JsonTranscoder {
+        let mut registry = SchemaRegistry::default();
+        registry.add("things", THINGS).unwrap();
+        JsonTranscoder::with_registry(registry)
+    }
+
+    #[test]
+    fn test_known_payload_round_trip() {
+        let mut transcoder = transcoder();
+        let sent = request("things", thing_bytes());
+        let json = transcoder.request_to_json(&sent).unwrap();
+
+        let parsed: Json = serde_json::from_str(&json).unwrap();
+        let payload = &parsed["event"]["processStruct"]["changes"]["serializedData"];
+        assert_eq!(payload["name"], Json::String("widget".to_string()));
+        // 64 bit integers are strings, and bytes are base64.
+        assert_eq!(payload["count"], Json::String("9007199254740993".to_string()));
+        assert_eq!(payload["blobs"], serde_json::json!(["AP8="]));
+        assert_eq!(parsed["event"]["processStruct"]["changes"]["dirtyProperties"], serde_json::json!(["name"]));
+
+        assert_eq!(transcoder.request_from_json(&json).unwrap(), sent);
+    }
+
+    #[test]
+    fn test_unknown_payload_stays_base64() {
+        let mut transcoder = transcoder();
+        // An unknown schema, and a known schema whose payload is missing its length prefix.
+        for sent in vec![request("unknown", thing_bytes()), request("things", thing_bytes()[1..].to_vec())] {
+            let json = transcoder.request_to_json(&sent).unwrap();
+            let parsed: Json = serde_json::from_str(&json).unwrap();
+            let json_payload = &parsed["event"]["processStruct"]["changes"]["serializedData"];
+            assert_eq!(json_payload, &Json::String(base64::encode(payload(&sent))));
+            assert_eq!(transcoder.request_from_json(&json).unwrap(), sent);
+        }
+    }
+
+    #[test]
+    fn test_return_round_trip() {
+        let mut transcoder = JsonTranscoder::default();
+        let ret: ReturnTransport = vec![request("unknown", vec![1, 2, 3]).event].into();
+        let json = transcoder.return_to_json(&ret).unwrap();
+        assert_eq!(transcoder.return_from_json(&json).unwrap(), ret);
+
+        let ret: ReturnTransport = "Something went wrong".to_string().into();
+        let json = transcoder.return_to_json(&ret).unwrap();
+        assert_eq!(transcoder.return_from_json(&json).unwrap(), ret);
+    }
+}
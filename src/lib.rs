@@ -43,13 +43,38 @@
 #![feature(as_cell)]
 
 pub mod transporter;
-pub mod autogen_protobuf;
+pub mod autogen_protobuf {
+    include!(concat!(env!("OUT_DIR"), "/autogen_protobuf.rs"));
+}
 pub mod common;
 pub mod hashenabler;
 pub mod logging;
 pub mod transport_glue;
 pub mod commonlibrary;
 pub mod pluginhandler;
+pub mod transaction;
+pub mod objectregistry;
+pub mod subscription;
+pub mod merge;
+pub mod schemastore;
+pub mod schemaregistry;
+pub mod protoparser;
+pub mod schemacompat;
+pub mod bundledschemas;
+pub mod negotiation;
+pub mod dynamicmessage;
+pub mod jsonmapping;
+pub mod codec;
+pub mod rpc;
+pub mod routing;
+pub mod pubsub;
+pub mod reliable;
+pub mod signing;
+pub mod replay;
+pub mod websockettransport;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod framing;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod wasmhandler;
@@ -57,10 +82,54 @@ pub mod wasmhandler;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod buildfunctions;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tcptransport;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod discovery;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod noise;
+
+#[cfg(unix)]
+pub mod unixtransport;
+
+#[cfg(test)]
+pub(crate) mod testutil;
+
 #[cfg(not(target_arch = "wasm32"))]
 pub use crate::pluginhandler::{PluginHandler};
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::tcptransport::{TcpTransporter, TcpTransportServer};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::discovery::Discovery;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::noise::{NoiseConfig, StaticKeypair};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use crate::websockettransport::{WebSocketTransporter, WebSocketTransportServer};
+
+#[cfg(target_arch = "wasm32")]
+pub use crate::websockettransport::BrowserWebSocket;
+
+#[cfg(unix)]
+pub use crate::unixtransport::{UnixTransporter, UnixTransportServer, AccessControl, PeerCredentials};
+
 pub use crate::transporter::{Transporter, RootTransporter};
+pub use crate::transaction::Transaction;
+pub use crate::objectregistry::ObjectRegistry;
+pub use crate::schemaregistry::SchemaRegistry;
+pub use crate::dynamicmessage::{DynamicMessage, DynamicSchema, Value};
+pub use crate::jsonmapping::JsonTranscoder;
+pub use crate::codec::Codec;
+pub use crate::routing::RoutingNode;
+pub use crate::pubsub::{TopicBus, Delivery};
+pub use crate::reliable::{ReliableSender, ReliableReceiver};
+pub use crate::signing::{Keyring, SigningTransporter, VerifyingTransporter};
+pub use crate::replay::{ReplayGuard, ReplayError};
 pub use crate::transport_glue::{TransportToModelGlue, TransportToProcessorGlue};
 pub use crate::common::{CommonModelFunctions, CommonStructureFunctions, Modifiable};
 pub use crate::autogen_protobuf::transport::*;
@@ -0,0 +1,634 @@
+//! Conflict-free merging of model changes that arrive from different replicas.
+//! Changes without a version vector were made locally, and are stamped before they are merged. Changes are merged per property:
+//! last-writer-wins properties use hybrid logical clocks, while counter and set properties are merged through their PropertyState.
+//! The version vector only records what each replica has seen. Replicas converge no matter what order changes arrive in.
+use crate::autogen_protobuf::transport::*;
+
+use hashbrown::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeStrategy {
+    /// The newest write wins.
+    LastWriterWins,
+    /// The property is a PnCounter. Every increment is kept, but never counted twice.
+    Counter,
+    /// The property is an OrSet. Every addition and removal is kept, but never applied twice.
+    Set,
+}
+
+impl Default for MergeStrategy {
+    fn default() -> Self { MergeStrategy::LastWriterWins }
+}
+
+pub fn compare_timestamps(a: &HybridTimestamp, b: &HybridTimestamp) -> Ordering {
+    a.wallTime.cmp(&b.wallTime)
+        .then(a.logical.cmp(&b.logical))
+        .then(a.nodeId.cmp(&b.nodeId))
+}
+
+pub struct HybridLogicalClock {
+    node_id: String,
+    last: HybridTimestamp,
+}
+
+impl HybridLogicalClock {
+    pub fn new(node_id: String) -> Self {
+        let last = HybridTimestamp{ wallTime: 0, logical: 0, nodeId: node_id.clone() };
+        HybridLogicalClock{ node_id, last }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Timestamp a local event.
+    pub fn now(&mut self) -> HybridTimestamp {
+        let physical = physical_time();
+        if physical > self.last.wallTime {
+            self.last.wallTime = physical;
+            self.last.logical = 0;
+        } else {
+            self.last.logical += 1;
+        }
+        self.last.clone()
+    }
+
+    /// Move the clock forward past a timestamp we received. Keeps causality even if our wall clock is behind.
+    pub fn update(&mut self, remote: &HybridTimestamp) -> HybridTimestamp {
+        let physical = physical_time();
+        let wall_time = physical.max(self.last.wallTime).max(remote.wallTime);
+
+        self.last.logical = if wall_time == self.last.wallTime && wall_time == remote.wallTime {
+            self.last.logical.max(remote.logical) + 1
+        } else if wall_time == self.last.wallTime {
+            self.last.logical + 1
+        } else if wall_time == remote.wallTime {
+            remote.logical + 1
+        } else {
+            0
+        };
+        self.last.wallTime = wall_time;
+        self.last.clone()
+    }
+}
+
+impl Default for HybridLogicalClock {
+    fn default() -> Self {
+        HybridLogicalClock::new(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+fn physical_time() -> u64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() * 1000 + u64::from(duration.subsec_millis()),
+        Err(_) => 0,
+    }
+}
+
+pub fn version_counter(version: &VersionVector, node_id: &str) -> u64 {
+    version.entries.iter()
+        .find(|entry| entry.nodeId == node_id)
+        .map(|entry| entry.counter)
+        .unwrap_or(0)
+}
+
+pub fn increment_version(version: &mut VersionVector, node_id: &str) {
+    match version.entries.iter_mut().find(|entry| entry.nodeId == node_id) {
+        Some(entry) => entry.counter = entry.counter.saturating_add(1),
+        None => version.entries.push(VersionEntry::new(node_id.to_string(), 1)),
+    }
+}
+
+/// Take the highest counter for each node. Entries are sorted so every replica ends up with the same bytes.
+pub fn merge_versions(a: &VersionVector, b: &VersionVector) -> VersionVector {
+    let mut merged: BTreeMap<String, u64> = BTreeMap::new();
+    for entry in a.entries.iter().chain(b.entries.iter()) {
+        let counter = merged.entry(entry.nodeId.clone()).or_insert(0);
+        *counter = (*counter).max(entry.counter);
+    }
+
+    VersionVector::new(merged.into_iter().map(|(node_id, counter)| VersionEntry::new(node_id, counter)).collect())
+}
+
+/// True if `a` has seen everything that `b` has.
+pub fn dominates(a: &VersionVector, b: &VersionVector) -> bool {
+    b.entries.iter().all(|entry| version_counter(a, &entry.nodeId) >= entry.counter)
+}
+
+// The merged state of a counter or set property.
+#[derive(Clone, PartialEq)]
+enum Crdt {
+    Counter(PnCounter),
+    Set(OrSet<Vec<u8>>),
+}
+
+impl Crdt {
+    fn new(strategy: MergeStrategy) -> Option<Self> {
+        match strategy {
+            MergeStrategy::LastWriterWins => None,
+            MergeStrategy::Counter => Some(Crdt::Counter(PnCounter::default())),
+            MergeStrategy::Set => Some(Crdt::Set(OrSet::default())),
+        }
+    }
+
+    fn merge(&mut self, state: &PropertyState) {
+        match self {
+            Crdt::Counter(counter) => counter.merge(&PnCounter::from_state(state)),
+            Crdt::Set(set) => set.merge(&OrSet::from_state(state)),
+        }
+    }
+
+    // Changes made locally hold operations instead of state.
+    fn apply_local(&mut self, node_id: &str, state: &PropertyState) {
+        match self {
+            Crdt::Counter(counter) => {
+                let sum = |entries: &[VersionEntry]| entries.iter().fold(0u64, |sum, entry| sum.saturating_add(entry.counter));
+                counter.increment(node_id, sum(&state.increments));
+                counter.decrement(node_id, sum(&state.decrements));
+            },
+            Crdt::Set(set) => {
+                for add in &state.adds {
+                    set.insert(add.element.clone());
+                }
+                for remove in &state.removes {
+                    set.remove(&remove.element);
+                }
+            },
+        }
+    }
+
+    fn to_state(&self, property: &str) -> PropertyState {
+        match self {
+            Crdt::Counter(counter) => counter.to_state(property),
+            Crdt::Set(set) => set.to_state(property),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ChangeMerger {
+    clock: HybridLogicalClock,
+    strategies: HashMap<(TypeDescriptor, String), MergeStrategy>,
+    versions: HashMap<Id, VersionVector>,
+    // The timestamp of the write that is currently applied for each property of each object.
+    applied: HashMap<Id, HashMap<String, HybridTimestamp>>,
+    // Counter and set properties of each object.
+    crdts: HashMap<Id, HashMap<String, Crdt>>,
+}
+
+impl ChangeMerger {
+    pub fn new(node_id: String) -> Self {
+        ChangeMerger{ clock: HybridLogicalClock::new(node_id), ..Default::default() }
+    }
+
+    pub fn set_strategy(&mut self, descriptor: TypeDescriptor, property: &str, strategy: MergeStrategy) {
+        self.strategies.insert((descriptor, property.to_string()), strategy);
+    }
+
+    pub fn strategy(&self, descriptor: &TypeDescriptor, property: &str) -> MergeStrategy {
+        self.strategies.get(&(descriptor.clone(), property.to_string())).cloned().unwrap_or_default()
+    }
+
+    /// Version a change made on this replica. The operations on counter and set properties become this replica's state for them.
+    /// Nothing is applied until the change is merged.
+    pub fn stamp(&mut self, changes: &mut ModelDataChanges) {
+        let timestamp = self.clock.now();
+        let mut version = self.versions.get(&changes.id).cloned().unwrap_or_default();
+        increment_version(&mut version, self.clock.node_id());
+
+        changes.version = Some(version);
+        changes.propertyVersions = changes.changes.dirtyProperties.iter()
+            .map(|property| PropertyVersion::new(property.clone(), timestamp.clone()))
+            .collect();
+
+        let node_id = self.clock.node_id().to_string();
+        let descriptor = changes.changes.descriptor.clone();
+        for state in changes.propertyStates.iter_mut() {
+            let existing = self.crdts.get(&changes.id).and_then(|crdts| crdts.get(&state.property)).cloned();
+            let mut crdt = match existing.or_else(|| Crdt::new(self.strategy(&descriptor, &state.property))) {
+                Some(crdt) => crdt,
+                None => continue,
+            };
+            crdt.apply_local(&node_id, state);
+            *state = crdt.to_state(&state.property);
+        }
+    }
+
+    /// Reduce the changes to the properties that should be applied. Returns None if there is nothing left to apply.
+    /// Changes without a version are stamped first. The rollback flag is ignored, so changes from other replicas cannot erase what was merged.
+    pub fn merge(&mut self, changes: &ModelDataChanges) -> Option<ModelDataChanges> {
+        let incoming_version = match &changes.version {
+            Some(version) => version,
+            None => {
+                let mut stamped = changes.clone();
+                self.stamp(&mut stamped);
+                return self.merge(&stamped);
+            },
+        };
+
+        // Only used to know what has been seen. A change that arrives late is still merged.
+        let version = self.versions.entry(changes.id.clone()).or_default();
+        *version = merge_versions(version, incoming_version);
+
+        for property_version in &changes.propertyVersions {
+            self.clock.update(&property_version.timestamp);
+        }
+
+        let descriptor = changes.changes.descriptor.clone();
+        let mut dirty_properties = Vec::new();
+        let mut property_states = Vec::new();
+        for property in &changes.changes.dirtyProperties {
+            let strategy = self.strategy(&descriptor, property);
+            if let Some(new_crdt) = Crdt::new(strategy) {
+                let state = match changes.propertyStates.iter().find(|state| &state.property == property) {
+                    Some(state) => state,
+                    None => {
+                        log::warn!("Property {:?} of {:?} has no PropertyState! Ignoring it.", property, changes.id);
+                        continue;
+                    },
+                };
+
+                let crdt = self.crdts.entry(changes.id.clone()).or_default()
+                    .entry(property.clone()).or_insert(new_crdt);
+                let before = crdt.clone();
+                crdt.merge(state);
+                if *crdt != before {
+                    dirty_properties.push(property.clone());
+                    property_states.push(crdt.to_state(property));
+                }
+                continue;
+            }
+
+            let timestamp = match changes.propertyVersions.iter().find(|version| &version.property == property) {
+                Some(version) => version.timestamp.clone(),
+                None => {
+                    log::warn!("Property {:?} of {:?} has no timestamp! Ignoring it.", property, changes.id);
+                    continue;
+                },
+            };
+
+            let applied = self.applied.entry(changes.id.clone()).or_default();
+            let newer = match applied.get(property) {
+                Some(current) => compare_timestamps(&timestamp, current) == Ordering::Greater,
+                None => true,
+            };
+
+            if newer {
+                applied.insert(property.clone(), timestamp);
+                dirty_properties.push(property.clone());
+            }
+        }
+
+        if dirty_properties.is_empty() {
+            log::trace!("Ignoring changes to {:?} that were already applied", changes.id);
+            return None;
+        }
+
+        let mut merged = changes.clone();
+        merged.changes.dirtyProperties = dirty_properties;
+        merged.propertyStates = property_states;
+        Some(merged)
+    }
+
+    /// Changes that put `state` back along with everything merged for the object so far. Used to undo a change.
+    pub fn snapshot(&self, id: &Id, state: StructDataChanges) -> ModelDataChanges {
+        let mut property_versions: Vec<PropertyVersion> = self.applied.get(id)
+            .map(|applied| applied.iter().map(|(property, timestamp)| PropertyVersion::new(property.clone(), timestamp.clone())).collect())
+            .unwrap_or_default();
+        property_versions.sort_by(|a, b| a.property.cmp(&b.property));
+
+        let mut property_states: Vec<PropertyState> = self.crdts.get(id)
+            .map(|crdts| crdts.iter().map(|(property, crdt)| crdt.to_state(property)).collect())
+            .unwrap_or_default();
+        property_states.sort_by(|a, b| a.property.cmp(&b.property));
+
+        let version = self.versions.get(id).cloned().unwrap_or_default();
+        ModelDataChanges::new(id.clone(), state, Some(version), property_versions, property_states, Some(true))
+    }
+
+    /// Replace everything merged for the object with a snapshot(...). Only for undoing changes on this replica.
+    pub fn restore(&mut self, changes: &ModelDataChanges) {
+        let id = changes.id.clone();
+        self.versions.insert(id.clone(), changes.version.clone().unwrap_or_default());
+        self.applied.insert(id.clone(), changes.propertyVersions.iter()
+            .map(|version| (version.property.clone(), version.timestamp.clone()))
+            .collect());
+
+        let descriptor = changes.changes.descriptor.clone();
+        let mut crdts = HashMap::new();
+        for state in &changes.propertyStates {
+            if let Some(mut crdt) = Crdt::new(self.strategy(&descriptor, &state.property)) {
+                crdt.merge(state);
+                crdts.insert(state.property.clone(), crdt);
+            }
+        }
+        self.crdts.insert(id, crdts);
+    }
+
+    /// Forget everything about an object. Call this when it is destroyed.
+    pub fn remove(&mut self, id: &Id) {
+        self.versions.remove(id);
+        self.applied.remove(id);
+        self.crdts.remove(id);
+    }
+}
+
+/// A change made locally to a counter property. Put it in ModelDataChanges::propertyStates and mark the property dirty.
+pub fn counter_change(property: &str, amount: i64) -> PropertyState {
+    let entry = vec![VersionEntry::new(String::new(), amount.wrapping_abs() as u64)];
+    let (increments, decrements) = if amount >= 0 { (entry, Vec::new()) } else { (Vec::new(), entry) };
+    PropertyState::new(property.to_string(), increments, decrements, Vec::new(), Vec::new())
+}
+
+/// A change made locally to a set property. Put it in ModelDataChanges::propertyStates and mark the property dirty.
+pub fn set_change(property: &str, inserted: Vec<Vec<u8>>, removed: Vec<Vec<u8>>) -> PropertyState {
+    let tags = |elements: Vec<Vec<u8>>| elements.into_iter().map(|element| SetTag::new(element, String::new())).collect();
+    PropertyState::new(property.to_string(), Vec::new(), Vec::new(), tags(inserted), tags(removed))
+}
+
+/// The merged value of a counter property, for Modifiable::modify(...). None if the changes do not include it.
+pub fn counter_value(changes: &ModelDataChanges, property: &str) -> Option<i64> {
+    changes.propertyStates.iter()
+        .find(|state| state.property == property)
+        .map(|state| PnCounter::from_state(state).value())
+}
+
+/// The merged elements of a set property, for Modifiable::modify(...). None if the changes do not include it.
+pub fn set_elements(changes: &ModelDataChanges, property: &str) -> Option<Vec<Vec<u8>>> {
+    changes.propertyStates.iter()
+        .find(|state| state.property == property)
+        .map(|state| OrSet::from_state(state).elements())
+}
+
+/// A counter that every replica can increment and decrement. Replicas converge by merging.
+/// Each replica's totals stop at u64::MAX, and the value stops at the bounds of an i64.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PnCounter {
+    increments: BTreeMap<String, u64>,
+    decrements: BTreeMap<String, u64>,
+}
+
+impl PnCounter {
+    pub fn increment(&mut self, node_id: &str, amount: u64) {
+        let total = self.increments.entry(node_id.to_string()).or_insert(0);
+        *total = total.saturating_add(amount);
+    }
+
+    pub fn decrement(&mut self, node_id: &str, amount: u64) {
+        let total = self.decrements.entry(node_id.to_string()).or_insert(0);
+        *total = total.saturating_add(amount);
+    }
+
+    pub fn value(&self) -> i64 {
+        // Other replicas choose their own totals, so the sums may not fit in an i64.
+        let sum = |totals: &BTreeMap<String, u64>| totals.values().map(|count| i128::from(*count)).sum::<i128>();
+        let value = sum(&self.increments) - sum(&self.decrements);
+        value.max(i128::from(i64::min_value())).min(i128::from(i64::max_value())) as i64
+    }
+
+    pub fn from_state(state: &PropertyState) -> Self {
+        PnCounter{ increments: totals(&state.increments), decrements: totals(&state.decrements) }
+    }
+
+    pub fn to_state(&self, property: &str) -> PropertyState {
+        let entries = |totals: &BTreeMap<String, u64>| totals.iter().map(|(node_id, count)| VersionEntry::new(node_id.clone(), *count)).collect();
+        PropertyState::new(property.to_string(), entries(&self.increments), entries(&self.decrements), Vec::new(), Vec::new())
+    }
+
+    pub fn merge(&mut self, other: &PnCounter) {
+        for (node_id, count) in &other.increments {
+            let current = self.increments.entry(node_id.clone()).or_insert(0);
+            *current = (*current).max(*count);
+        }
+        for (node_id, count) in &other.decrements {
+            let current = self.decrements.entry(node_id.clone()).or_insert(0);
+            *current = (*current).max(*count);
+        }
+    }
+}
+
+/// An observed-remove set. An add wins over a concurrent remove of the same element.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OrSet<T: Ord + Clone> {
+    // Each add gets a unique tag. Removing an element removes only the tags that were seen.
+    adds: BTreeMap<T, BTreeSet<String>>,
+    removes: BTreeMap<T, BTreeSet<String>>,
+}
+
+impl<T: Ord + Clone> Default for OrSet<T> {
+    fn default() -> Self {
+        OrSet{ adds: BTreeMap::new(), removes: BTreeMap::new() }
+    }
+}
+
+impl<T: Ord + Clone> OrSet<T> {
+    pub fn insert(&mut self, element: T) {
+        self.adds.entry(element).or_default().insert(uuid::Uuid::new_v4().to_string());
+    }
+
+    pub fn remove(&mut self, element: &T) {
+        if let Some(tags) = self.adds.get(element) {
+            self.removes.entry(element.clone()).or_default().extend(tags.iter().cloned());
+        }
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        match self.adds.get(element) {
+            None => false,
+            Some(tags) => {
+                let removed = self.removes.get(element);
+                tags.iter().any(|tag| removed.map(|removed| !removed.contains(tag)).unwrap_or(true))
+            },
+        }
+    }
+
+    pub fn elements(&self) -> Vec<T> {
+        self.adds.keys().filter(|element| self.contains(element)).cloned().collect()
+    }
+
+    pub fn merge(&mut self, other: &OrSet<T>) {
+        merge_tags(&mut self.adds, &other.adds);
+        merge_tags(&mut self.removes, &other.removes);
+    }
+}
+
+impl OrSet<Vec<u8>> {
+    pub fn from_state(state: &PropertyState) -> Self {
+        OrSet{ adds: tags(&state.adds), removes: tags(&state.removes) }
+    }
+
+    pub fn to_state(&self, property: &str) -> PropertyState {
+        let entries = |tags: &BTreeMap<Vec<u8>, BTreeSet<String>>| tags.iter()
+            .flat_map(|(element, tags)| tags.iter().map(move |tag| SetTag::new(element.clone(), tag.clone())))
+            .collect();
+        PropertyState::new(property.to_string(), Vec::new(), Vec::new(), entries(&self.adds), entries(&self.removes))
+    }
+}
+
+fn merge_tags<T: Ord + Clone>(ours: &mut BTreeMap<T, BTreeSet<String>>, theirs: &BTreeMap<T, BTreeSet<String>>) {
+    for (element, tags) in theirs {
+        ours.entry(element.clone()).or_default().extend(tags.iter().cloned());
+    }
+}
+
+// The highest total for each node.
+fn totals(entries: &[VersionEntry]) -> BTreeMap<String, u64> {
+    let mut totals: BTreeMap<String, u64> = BTreeMap::new();
+    for entry in entries {
+        let total = totals.entry(entry.nodeId.clone()).or_insert(0);
+        *total = (*total).max(entry.counter);
+    }
+    totals
+}
+
+fn tags(entries: &[SetTag]) -> BTreeMap<Vec<u8>, BTreeSet<String>> {
+    let mut tags: BTreeMap<Vec<u8>, BTreeSet<String>> = BTreeMap::new();
+    for entry in entries {
+        tags.entry(entry.element.clone()).or_default().insert(entry.tag.clone());
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor() -> TypeDescriptor {
+        TypeDescriptor::new("test".to_string(), "Object".to_string())
+    }
+
+    fn merger(node_id: &str) -> ChangeMerger {
+        let mut merger = ChangeMerger::new(node_id.to_string());
+        merger.set_strategy(descriptor(), "hits", MergeStrategy::Counter);
+        merger.set_strategy(descriptor(), "tags", MergeStrategy::Set);
+        merger
+    }
+
+    fn change(property: &str, state: Option<PropertyState>, data: u8) -> ModelDataChanges {
+        let changes = StructDataChanges::new(vec![data], vec![property.to_string()], descriptor());
+        ModelDataChanges::new(Id::new("object".to_string()), changes, None, Vec::new(), state.into_iter().collect(), None)
+    }
+
+    // Make a change on one replica, and return what it would send to the others.
+    fn local(merger: &mut ChangeMerger, changes: ModelDataChanges) -> ModelDataChanges {
+        merger.merge(&changes).unwrap()
+    }
+
+    // The values a model would hold after being modified with every change that was applied.
+    #[derive(Debug, Default, PartialEq)]
+    struct Model {
+        name: Option<u8>,
+        hits: i64,
+        tags: Vec<Vec<u8>>,
+    }
+
+    fn replay(changes: &[&ModelDataChanges]) -> Model {
+        let mut merger = merger("receiver");
+        let mut model = Model::default();
+        for changes in changes {
+            if let Some(merged) = merger.merge(changes) {
+                for property in &merged.changes.dirtyProperties {
+                    match property.as_str() {
+                        "hits" => model.hits = counter_value(&merged, "hits").unwrap(),
+                        "tags" => model.tags = set_elements(&merged, "tags").unwrap(),
+                        _ => model.name = Some(merged.changes.serializedData[0]),
+                    }
+                }
+            }
+        }
+        model
+    }
+
+    #[test]
+    fn test_replicas_converge_in_any_order() {
+        let mut a = merger("a");
+        let mut b = merger("b");
+        let a1 = local(&mut a, change("hits", Some(counter_change("hits", 2)), 0));
+        let a2 = local(&mut a, change("hits", Some(counter_change("hits", 3)), 0));
+        let a3 = local(&mut a, change("tags", Some(set_change("tags", vec![b"x".to_vec(), b"y".to_vec()], Vec::new())), 0));
+        let b1 = local(&mut b, change("hits", Some(counter_change("hits", -1)), 0));
+        let b2 = local(&mut b, change("name", None, 7));
+        // Concurrent with a3. B never saw the add, so the remove cannot take it away.
+        let b3 = local(&mut b, change("tags", Some(set_change("tags", vec![b"z".to_vec()], vec![b"x".to_vec()])), 0));
+        // A has seen B's name, so its own is newer.
+        a.merge(&b2).unwrap();
+        let a4 = local(&mut a, change("name", None, 9));
+
+        let expected = Model{ name: Some(9), hits: 4, tags: vec![b"x".to_vec(), b"y".to_vec(), b"z".to_vec()] };
+        assert_eq!(replay(&[&a1, &a2, &a3, &a4, &b1, &b2, &b3]), expected);
+        // A1 arrives after A2, and B's changes are interleaved.
+        assert_eq!(replay(&[&b3, &a2, &b1, &a4, &a1, &b2, &a3]), expected);
+        // Duplicates are never applied twice.
+        assert_eq!(replay(&[&a4, &a3, &a3, &b2, &a2, &b1, &a1, &b3, &a1, &b1]), expected);
+    }
+
+    #[test]
+    fn test_remove_after_observing_add() {
+        let mut a = merger("a");
+        let mut b = merger("b");
+        let added = local(&mut a, change("tags", Some(set_change("tags", vec![b"x".to_vec()], Vec::new())), 0));
+        b.merge(&added).unwrap();
+        let removed = local(&mut b, change("tags", Some(set_change("tags", Vec::new(), vec![b"x".to_vec()])), 0));
+
+        assert!(replay(&[&removed, &added]).tags.is_empty());
+        assert!(replay(&[&added, &removed]).tags.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_restores_merged_state() {
+        let mut a = merger("a");
+        local(&mut a, change("hits", Some(counter_change("hits", 5)), 0));
+        let snapshot = a.snapshot(&Id::new("object".to_string()), StructDataChanges::new(vec![5], vec!["hits".to_string()], descriptor()));
+        assert_eq!(counter_value(&snapshot, "hits"), Some(5));
+        assert_eq!(snapshot.version.as_ref().map(|version| version_counter(version, "a")), Some(1));
+
+        let undone = local(&mut a, change("hits", Some(counter_change("hits", 10)), 0));
+        assert_eq!(counter_value(&undone, "hits"), Some(15));
+        a.restore(&snapshot);
+
+        // The undone change is stamped with the same version again, but its increment was forgotten with it.
+        let next = local(&mut a, change("hits", Some(counter_change("hits", 1)), 0));
+        assert_eq!(counter_value(&next, "hits"), Some(6));
+        assert_eq!(next.version.as_ref().map(|version| version_counter(version, "a")), Some(2));
+    }
+
+    #[test]
+    fn test_rollback_flag_is_ignored_when_merging() {
+        let mut a = merger("a");
+        let mut b = merger("b");
+        let hits = local(&mut a, change("hits", Some(counter_change("hits", 5)), 0));
+        b.merge(&hits).unwrap();
+
+        // A replica cannot wipe out what was merged by calling its change a rollback.
+        let mut wipe = local(&mut b, change("hits", Some(counter_change("hits", 0)), 0));
+        wipe.propertyStates = vec![PnCounter::default().to_state("hits")];
+        wipe.rollback = Some(true);
+        assert!(a.merge(&wipe).is_none());
+        let next = local(&mut a, change("hits", Some(counter_change("hits", 1)), 0));
+        assert_eq!(counter_value(&next, "hits"), Some(6));
+    }
+
+    #[test]
+    fn test_counter_saturates() {
+        let half = u64::max_value() / 2 + 1;
+        let mut counter = PnCounter::default();
+        counter.increment("a", half);
+        counter.increment("b", half);
+        assert_eq!(counter.value(), i64::max_value());
+        counter.increment("a", u64::max_value());
+        assert_eq!(counter.to_state("hits").increments[0].counter, u64::max_value());
+
+        let mut negative = PnCounter::default();
+        negative.decrement("a", u64::max_value());
+        negative.decrement("b", u64::max_value());
+        assert_eq!(negative.value(), i64::min_value());
+
+        let mut a = merger("a");
+        let lowest = local(&mut a, change("hits", Some(counter_change("hits", i64::min_value())), 0));
+        assert_eq!(counter_value(&lowest, "hits"), Some(i64::min_value()));
+        let lower = local(&mut a, change("hits", Some(counter_change("hits", i64::min_value())), 0));
+        assert_eq!(counter_value(&lower, "hits"), Some(i64::min_value()));
+    }
+}
@@ -0,0 +1,113 @@
+//! Schema version negotiation between the host and its plugins.
+//! Both sides list the schema hashes they can decode, and agree on the newest version they have in common.
+use crate::autogen_protobuf::transport::*;
+use crate::bundledschemas::{transport_schema_hash, TRANSPORT_SCHEMA_NAME};
+use crate::codec;
+
+use failure::Error;
+use hashbrown::HashMap;
+
+/// A handshake that supports the transport schema this crate was built with, every schema in schema_urls, and every codec it ships.
+pub fn default_handshake() -> SchemaHandshake {
+    let mut handshake = SchemaHandshake::default();
+    handshake.codecs = codec::supported_codecs().iter().map(|name| name.to_string()).collect();
+    add_supported_schema(&mut handshake, TRANSPORT_SCHEMA_NAME, &transport_schema_hash());
+
+    let mut aliases: Vec<(String, &'static str)> = crate::autogen_protobuf::schema_urls::get_all_aliases().into_iter()
+        .filter(|(alias, _hash)| alias != TRANSPORT_SCHEMA_NAME)
+        .collect();
+    aliases.sort();
+    for (alias, hash) in aliases {
+        add_supported_schema(&mut handshake, &alias, hash);
+    }
+    handshake
+}
+
+/// Add a version to the handshake. Versions should be added oldest first.
+pub fn add_supported_schema(handshake: &mut SchemaHandshake, name: &str, hash: &str) {
+    match handshake.schemas.iter_mut().find(|versions| versions.name == name) {
+        Some(versions) => if !versions.hashes.iter().any(|existing| existing == hash) {
+            versions.hashes.push(hash.to_string());
+        },
+        None => handshake.schemas.push(SchemaVersions::new(name.to_string(), vec![hash.to_string()])),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct NegotiatedSchemas {
+    // Schema name to the hash both sides agreed on.
+    agreed: HashMap<String, String>,
+    // Schema name to the hash we send for it, which is the newest one we support.
+    sent: HashMap<String, String>,
+    // Every hash the other side can decode.
+    decodable: Vec<String>,
+    codec: String,
+}
+
+impl NegotiatedSchemas {
+    pub fn agreed(&self, name: &str) -> Option<&str> {
+        self.agreed.get(name).map(|hash| hash.as_str())
+    }
+
+    /// The codec both sides will use for RequestTransport and ReturnTransport.
+    pub fn codec(&self) -> &str {
+        &self.codec
+    }
+
+    /// Fails if the other side will not be able to decode data of this type.
+    /// Aliases are resolved to the exact version we send, so agreeing on an older version of a schema is not enough.
+    pub fn check(&self, descriptor: &TypeDescriptor) -> Result<(), Error> {
+        let version = self.sent.get(&descriptor.libraryAlias).unwrap_or(&descriptor.libraryAlias);
+        if self.decodable.contains(version) {
+            return Ok(());
+        }
+        Err(failure::format_err!("The receiver cannot decode {:?} at schema version {:?}! It supports {:?}", descriptor, version, self.decodable))
+    }
+}
+
+/// Agree on the newest version of each schema that both sides support.
+/// The codec is the first one in the remote's list that we support. The plugin replies with only the codec it chose,
+/// so both sides end up with the same one. A side that lists no codecs only speaks protobuf.
+pub fn negotiate(local: &SchemaHandshake, remote: &SchemaHandshake) -> Result<NegotiatedSchemas, Error> {
+    let codecs = |handshake: &SchemaHandshake| if handshake.codecs.is_empty() {
+        vec![codec::PROTOBUF.to_string()]
+    } else {
+        handshake.codecs.clone()
+    };
+    let (local_codecs, remote_codecs) = (codecs(local), codecs(remote));
+    let codec = remote_codecs.iter().find(|name| local_codecs.contains(name)).cloned()
+        .ok_or(failure::format_err!("No codec in common! Local: {:?} Remote: {:?}", local_codecs, remote_codecs))?;
+    log::debug!("Agreed on codec {:?}", codec);
+
+    let mut negotiated = NegotiatedSchemas{
+        agreed: HashMap::new(),
+        sent: local.schemas.iter()
+            .filter_map(|versions| versions.hashes.last().map(|hash| (versions.name.clone(), hash.clone())))
+            .collect(),
+        decodable: remote.schemas.iter().flat_map(|versions| versions.hashes.iter().cloned()).collect(),
+        codec,
+    };
+
+    for local_versions in &local.schemas {
+        let remote_versions = match remote.schemas.iter().find(|versions| versions.name == local_versions.name) {
+            None if local_versions.name == TRANSPORT_SCHEMA_NAME => {
+                return Err(failure::format_err!("The other side does not support the transport schema! It may have refused us."));
+            },
+            None => continue, // The other side does not use this schema at all.
+            Some(versions) => versions,
+        };
+
+        match local_versions.hashes.iter().rev().find(|hash| remote_versions.hashes.contains(hash)) {
+            Some(hash) => {
+                log::debug!("Agreed on {:?} version {:?}", local_versions.name, hash);
+                negotiated.agreed.insert(local_versions.name.clone(), hash.clone());
+            },
+            None if local_versions.name == TRANSPORT_SCHEMA_NAME => {
+                return Err(failure::format_err!("No common version of the transport schema! Local: {:?} Remote: {:?}", local_versions.hashes, remote_versions.hashes));
+            },
+            None => log::warn!("No common version of schema {:?}! Requests using it will be refused.", local_versions.name),
+        }
+    }
+
+    Ok(negotiated)
+}
@@ -0,0 +1,280 @@
+//! Encrypts everything sent over a FramedConnection with the Noise protocol.
+//! Both sides run an XX handshake, which proves each holds the private half of its static key. After that, every frame is encrypted and authenticated.
+//! Noise messages are at most 65535 bytes, so larger frames are split into several. Pass a NoiseConfig when connecting to encrypt that connection.
+use crate::framing::FramedConnection;
+
+use failure::Error;
+use std::convert::TryInto;
+
+pub static NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+const MAX_NOISE_MESSAGE: usize = 65535;
+// Each encrypted message carries a 16 byte authentication tag.
+const MAX_CHUNK: usize = MAX_NOISE_MESSAGE - 16;
+
+#[derive(Clone)]
+pub struct StaticKeypair {
+    pub private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Result<Self, Error> {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()
+            .map_err(|e| failure::format_err!("Unable to generate Noise keypair! {:?}", e))?;
+        Ok(StaticKeypair{ private: keypair.private, public: keypair.public })
+    }
+}
+
+#[derive(Clone)]
+pub struct NoiseConfig {
+    keypair: StaticKeypair,
+    // None accepts any peer that completes the handshake.
+    trusted: Option<Vec<Vec<u8>>>,
+}
+
+impl NoiseConfig {
+    /// Trusts no peers until they are added with trust(...), so every handshake is refused.
+    pub fn new(keypair: StaticKeypair) -> Self {
+        NoiseConfig{ keypair, trusted: Some(Vec::new()) }
+    }
+
+    /// Only complete handshakes with peers whose static public key is trusted.
+    pub fn trust(mut self, public: Vec<u8>) -> Self {
+        self.trusted.get_or_insert_with(Vec::new).push(public);
+        self
+    }
+
+    /// Complete handshakes with any peer. The connection is still encrypted, but anyone can be on the other end of it.
+    pub fn trust_any(mut self) -> Self {
+        self.trusted = None;
+        self
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.keypair.public
+    }
+
+    fn check_peer(&self, public: &[u8]) -> Result<(), Error> {
+        match &self.trusted {
+            Some(trusted) if !trusted.iter().any(|key| key.as_slice() == public) =>
+                Err(failure::format_err!("Peer's static key {} is not trusted!", bs58::encode(public).into_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct NoiseConnection<C: FramedConnection> {
+    inner: C,
+    transport: snow::TransportState,
+    remote_static: Vec<u8>,
+}
+
+impl<C: FramedConnection> NoiseConnection<C> {
+    /// Run the handshake as the side that connected.
+    pub fn initiate(mut inner: C, config: &NoiseConfig) -> Result<Self, Error> {
+        let mut handshake = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&config.keypair.private)
+            .build_initiator()
+            .map_err(|e| failure::format_err!("Unable to start Noise handshake! {:?}", e))?;
+
+        // -> e
+        write_handshake(&mut inner, &mut handshake)?;
+        // <- e, ee, s, es
+        read_handshake(&mut inner, &mut handshake)?;
+        // -> s, se
+        write_handshake(&mut inner, &mut handshake)?;
+        Self::finish(inner, handshake, config)
+    }
+
+    /// Run the handshake as the side that accepted.
+    pub fn respond(mut inner: C, config: &NoiseConfig) -> Result<Self, Error> {
+        let mut handshake = snow::Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&config.keypair.private)
+            .build_responder()
+            .map_err(|e| failure::format_err!("Unable to start Noise handshake! {:?}", e))?;
+
+        read_handshake(&mut inner, &mut handshake)?;
+        write_handshake(&mut inner, &mut handshake)?;
+        read_handshake(&mut inner, &mut handshake)?;
+        Self::finish(inner, handshake, config)
+    }
+
+    fn finish(inner: C, handshake: snow::HandshakeState, config: &NoiseConfig) -> Result<Self, Error> {
+        let remote_static = handshake.get_remote_static()
+            .ok_or(failure::format_err!("Peer did not send a static key!"))?
+            .to_vec();
+        config.check_peer(&remote_static)?;
+
+        let transport = handshake.into_transport_mode()
+            .map_err(|e| failure::format_err!("Noise handshake did not finish! {:?}", e))?;
+        log::debug!("Finished Noise handshake with {}", bs58::encode(&remote_static).into_string());
+        Ok(NoiseConnection{ inner, transport, remote_static })
+    }
+
+    /// The static public key the peer proved it holds.
+    pub fn remote_static(&self) -> &[u8] {
+        &self.remote_static
+    }
+}
+
+fn write_handshake<C: FramedConnection>(inner: &mut C, handshake: &mut snow::HandshakeState) -> Result<(), Error> {
+    let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+    let len = handshake.write_message(&[], &mut message)
+        .map_err(|e| failure::format_err!("Noise handshake failed! {:?}", e))?;
+    inner.send_frame(&message[..len])
+}
+
+fn read_handshake<C: FramedConnection>(inner: &mut C, handshake: &mut snow::HandshakeState) -> Result<(), Error> {
+    let message = inner.receive_frame()?
+        .ok_or(failure::format_err!("Connection closed during the Noise handshake!"))?;
+    let mut payload = vec![0u8; MAX_NOISE_MESSAGE];
+    handshake.read_message(&message, &mut payload)
+        .map_err(|e| failure::format_err!("Noise handshake failed! {:?}", e))?;
+    Ok(())
+}
+
+impl<C: FramedConnection> FramedConnection for NoiseConnection<C> {
+    /// The frame's length comes first, then the frame. Both are split into chunks that are encrypted one at a time.
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let mut plaintext = Vec::with_capacity(4 + payload.len());
+        plaintext.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        plaintext.extend_from_slice(payload);
+
+        let mut message = vec![0u8; MAX_NOISE_MESSAGE];
+        for chunk in plaintext.chunks(MAX_CHUNK) {
+            let len = self.transport.write_message(chunk, &mut message)
+                .map_err(|e| failure::format_err!("Unable to encrypt frame! {:?}", e))?;
+            self.inner.send_frame(&message[..len])?;
+        }
+        Ok(())
+    }
+
+    fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let mut plaintext = match self.receive_chunk()? {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+        if plaintext.len() < 4 {
+            return Err(failure::format_err!("Encrypted frame is missing its length!"));
+        }
+
+        let len = u32::from_be_bytes(plaintext[..4].try_into()?) as usize;
+        if len > crate::framing::MAX_FRAME_SIZE {
+            return Err(failure::format_err!("Frame of {} bytes is larger than the maximum of {}!", len, crate::framing::MAX_FRAME_SIZE));
+        }
+
+        plaintext.drain(..4);
+        while plaintext.len() < len {
+            let chunk = self.receive_chunk()?
+                .ok_or(failure::format_err!("Connection closed in the middle of an encrypted frame!"))?;
+            plaintext.extend_from_slice(&chunk);
+        }
+
+        if plaintext.len() != len {
+            return Err(failure::format_err!("Encrypted frame is {} bytes long, but should be {}!", plaintext.len(), len));
+        }
+        Ok(Some(plaintext))
+    }
+}
+
+impl<C: FramedConnection> NoiseConnection<C> {
+    fn receive_chunk(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let message = match self.inner.receive_frame()? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        let mut chunk = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = self.transport.read_message(&message, &mut chunk)
+            .map_err(|e| failure::format_err!("Unable to decrypt frame! It may have been tampered with. {:?}", e))?;
+        chunk.truncate(len);
+        Ok(Some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autogen_protobuf::transport::*;
+    use crate::tcptransport::{TcpTransporter, TcpTransportServer};
+    use crate::testutil::{request, Echo};
+    use crate::transporter::Transporter;
+
+    #[test]
+    fn test_encrypted_loopback() {
+        let server_keys = StaticKeypair::generate().unwrap();
+        let client_keys = StaticKeypair::generate().unwrap();
+        let server_config = NoiseConfig::new(server_keys.clone()).trust(client_keys.public.clone());
+        let client_config = NoiseConfig::new(client_keys).trust(server_keys.public);
+
+        let server = TcpTransportServer::bind("127.0.0.1:0").unwrap().with_encryption(server_config);
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || server.serve_one(&mut Echo).unwrap());
+
+        let mut client = TcpTransporter::connect_encrypted(addr, &client_config).unwrap();
+        // Large enough to be split into several Noise messages.
+        let descriptor = TypeDescriptor::new("alias".to_string(), "Structure".to_string());
+        let changes = StructDataChanges::new(vec![7u8; 200_000], Vec::new(), descriptor);
+        let sent = RequestTransport::new(ModuleId::new("echo".to_string()), Event::new(ProcessStructData::new(changes, None).into()), None, None);
+
+        let ret = client.transport_data(&sent);
+        assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+        assert_eq!(ret.vec, vec![sent.event]);
+
+        drop(client);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_untrusted_peer_is_refused() {
+        let server_keys = StaticKeypair::generate().unwrap();
+        let server_config = NoiseConfig::new(server_keys).trust(StaticKeypair::generate().unwrap().public);
+        let client_config = NoiseConfig::new(StaticKeypair::generate().unwrap()).trust_any();
+
+        let server = TcpTransportServer::bind("127.0.0.1:0").unwrap().with_encryption(server_config);
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || server.serve_one(&mut Echo));
+
+        // The client finishes its side of the handshake first, then the server refuses it and hangs up.
+        if let Ok(mut client) = TcpTransporter::connect_encrypted(addr, &client_config) {
+            let sent = request("echo", "id");
+            assert_eq!(client.transport_data(&sent).errors.len(), 1);
+        }
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_empty_trust_list_refuses_everyone() {
+        let server_config = NoiseConfig::new(StaticKeypair::generate().unwrap()).trust_any();
+        let client_config = NoiseConfig::new(StaticKeypair::generate().unwrap());
+
+        let server = TcpTransportServer::bind("127.0.0.1:0").unwrap().with_encryption(server_config);
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || server.serve_one(&mut Echo));
+
+        assert!(TcpTransporter::connect_encrypted(addr, &client_config).is_err());
+        // The server may or may not see the client hang up before its handshake finishes.
+        let _ = handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_silent_client_does_not_hold_up_others() {
+        let server_keys = StaticKeypair::generate().unwrap();
+        let client_keys = StaticKeypair::generate().unwrap();
+        let server_config = NoiseConfig::new(server_keys.clone()).trust(client_keys.public.clone());
+        let client_config = NoiseConfig::new(client_keys).trust(server_keys.public);
+
+        let server = TcpTransportServer::bind("127.0.0.1:0").unwrap().with_encryption(server_config);
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve(&mut Echo));
+
+        // Never starts its handshake. Only its own thread waits on it.
+        let _silent = std::net::TcpStream::connect(addr).unwrap();
+        let mut client = TcpTransporter::connect_encrypted(addr, &client_config).unwrap();
+        let sent = request("echo", "id");
+        let ret = client.transport_data(&sent);
+        assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+        assert_eq!(ret.vec, vec![sent.event]);
+    }
+}
@@ -0,0 +1,198 @@
+//! The object registry tracks which objects own which other objects.
+//! Ownership is learned from constructor events: any object constructed as a side effect of another object's constructor is its child.
+use crate::autogen_protobuf::transport::*;
+
+use failure::Error;
+use hashbrown::HashMap;
+
+#[derive(Clone, Debug)]
+pub struct ObjectEntry {
+    pub descriptor: TypeDescriptor,
+    pub parent: Option<Id>,
+    pub children: Vec<Id>,
+}
+
+#[derive(Clone, Default)]
+pub struct ObjectRegistry {
+    objects: HashMap<Id, ObjectEntry>,
+    // Children that were announced by a constructor, but have not been constructed yet.
+    pending_parents: HashMap<Id, Id>,
+}
+
+impl ObjectRegistry {
+    /// A constructor for `parent` asked for `child` to be created.
+    pub fn announce_child(&mut self, parent: &Id, child: &Id) -> Result<(), Error> {
+        if let Some(owner) = self.owner_of(child) {
+            if owner != parent {
+                return Err(failure::format_err!("{:?} is already owned by {:?}! It cannot also be owned by {:?}", child, owner, parent));
+            }
+        }
+
+        if let Some(existing) = self.pending_parents.insert(child.clone(), parent.clone()) {
+            if &existing != parent {
+                return Err(failure::format_err!("{:?} was announced by both {:?} and {:?}!", child, existing, parent));
+            }
+        }
+        Ok(())
+    }
+
+    /// An object was constructed. If a parent announced it, link the two together.
+    pub fn register(&mut self, id: Id, descriptor: TypeDescriptor) -> Result<(), Error> {
+        if self.objects.contains_key(&id) {
+            return Err(failure::format_err!("{:?} is already registered!", id));
+        }
+
+        let parent = self.pending_parents.remove(&id);
+        if let Some(parent) = &parent {
+            match self.objects.get_mut(parent) {
+                Some(entry) => entry.children.push(id.clone()),
+                None => log::warn!("{:?} was constructed after its parent {:?} was destroyed!", id, parent),
+            }
+        }
+
+        self.objects.insert(id, ObjectEntry{ descriptor, parent, children: Vec::new() });
+        Ok(())
+    }
+
+    /// An object was destroyed. Return its children so that they can be destroyed as well.
+    pub fn unregister(&mut self, id: &Id) -> Vec<(Id, TypeDescriptor)> {
+        let entry = match self.objects.remove(id) {
+            None => return Vec::new(),
+            Some(entry) => entry,
+        };
+
+        if let Some(parent) = &entry.parent {
+            if let Some(parent_entry) = self.objects.get_mut(parent) {
+                parent_entry.children.retain(|child| child != id);
+            }
+        }
+
+        entry.children.iter()
+            .filter_map(|child| self.objects.get(child).map(|child_entry| (child.clone(), child_entry.descriptor.clone())))
+            .collect()
+    }
+
+    /// Move an object to a new owner. `None` makes it a root object.
+    pub fn reparent(&mut self, id: &Id, new_parent: Option<Id>) -> Result<(), Error> {
+        if !self.objects.contains_key(id) {
+            return Err(failure::format_err!("Cannot reparent {:?}. It does not exist!", id));
+        }
+
+        if let Some(new_parent) = &new_parent {
+            if !self.objects.contains_key(new_parent) {
+                return Err(failure::format_err!("Cannot reparent {:?}. New parent {:?} does not exist!", id, new_parent));
+            }
+
+            // Walk up from the new parent. If we find ourselves, we would create a cycle.
+            let mut ancestor = Some(new_parent.clone());
+            while let Some(current) = ancestor {
+                if &current == id {
+                    return Err(failure::format_err!("Cannot reparent {:?} under its own descendant {:?}!", id, new_parent));
+                }
+                ancestor = self.owner_of(&current).cloned();
+            }
+        }
+
+        let old_parent = self.objects.get(id).and_then(|entry| entry.parent.clone());
+        if let Some(old_parent) = old_parent {
+            if let Some(entry) = self.objects.get_mut(&old_parent) {
+                entry.children.retain(|child| child != id);
+            }
+        }
+
+        if let Some(new_parent) = &new_parent {
+            if let Some(entry) = self.objects.get_mut(new_parent) {
+                entry.children.push(id.clone());
+            }
+        }
+
+        if let Some(entry) = self.objects.get_mut(id) {
+            entry.parent = new_parent;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &Id) -> Option<&ObjectEntry> {
+        self.objects.get(id)
+    }
+
+    pub fn owner_of(&self, id: &Id) -> Option<&Id> {
+        self.objects.get(id).and_then(|entry| entry.parent.as_ref())
+    }
+
+    pub fn descriptor_of(&self, id: &Id) -> Option<&TypeDescriptor> {
+        self.objects.get(id).map(|entry| &entry.descriptor)
+    }
+
+    pub fn children_of(&self, id: &Id) -> Vec<(Id, TypeDescriptor)> {
+        match self.objects.get(id) {
+            None => Vec::new(),
+            Some(entry) => entry.children.iter()
+                .filter_map(|child| self.objects.get(child).map(|child_entry| (child.clone(), child_entry.descriptor.clone())))
+                .collect(),
+        }
+    }
+
+    /// Objects that nothing owns.
+    pub fn roots(&self) -> Vec<(Id, TypeDescriptor)> {
+        self.objects.iter()
+            .filter(|(_id, entry)| entry.parent.is_none())
+            .map(|(id, entry)| (id.clone(), entry.descriptor.clone()))
+            .collect()
+    }
+
+    /// Objects whose owner has been destroyed without them.
+    pub fn orphans(&self) -> Vec<(Id, TypeDescriptor)> {
+        self.objects.iter()
+            .filter(|(_id, entry)| match &entry.parent {
+                Some(parent) => !self.objects.contains_key(parent),
+                None => false,
+            })
+            .map(|(id, entry)| (id.clone(), entry.descriptor.clone()))
+            .collect()
+    }
+
+    pub fn live_objects(&self) -> Vec<(Id, TypeDescriptor)> {
+        self.objects.iter().map(|(id, entry)| (id.clone(), entry.descriptor.clone())).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor() -> TypeDescriptor {
+        TypeDescriptor::new("test".to_string(), "Object".to_string())
+    }
+
+    fn id(val: &str) -> Id {
+        Id::new(val.to_string())
+    }
+
+    #[test]
+    fn test_orphans_and_reparent() {
+        let mut registry = ObjectRegistry::default();
+        registry.register(id("parent"), descriptor()).unwrap();
+        registry.announce_child(&id("parent"), &id("child")).unwrap();
+        registry.register(id("child"), descriptor()).unwrap();
+        assert_eq!(registry.children_of(&id("parent")), vec![(id("child"), descriptor())]);
+
+        // A child cannot be claimed by a second owner, or become its own ancestor.
+        registry.register(id("other"), descriptor()).unwrap();
+        assert!(registry.announce_child(&id("other"), &id("child")).is_err());
+        assert!(registry.reparent(&id("parent"), Some(id("child"))).is_err());
+        assert!(registry.reparent(&id("missing"), None).is_err());
+
+        // Destroying the parent without its child leaves an orphan until it is given a new owner.
+        assert_eq!(registry.unregister(&id("parent")), vec![(id("child"), descriptor())]);
+        assert_eq!(registry.orphans(), vec![(id("child"), descriptor())]);
+        registry.reparent(&id("child"), Some(id("other"))).unwrap();
+        assert!(registry.orphans().is_empty());
+        assert_eq!(registry.owner_of(&id("child")), Some(&id("other")));
+        assert_eq!(registry.roots(), vec![(id("other"), descriptor())]);
+    }
+}
@@ -3,33 +3,114 @@
 #[cfg(not(target_arch = "wasm32"))]
 use crate::Transporter;
 use crate::autogen_protobuf::transport::*;
+use crate::codec::Codec;
+use crate::negotiation::NegotiatedSchemas;
 
 use hashbrown::HashMap;
+use std::sync::Mutex;
+
+/// The codec a plugin agreed on with its host. Each plugin keeps its own next to its TransportNode, so libraries never share one.
+/// Plugins that were never asked to negotiate use protobuf.
+pub struct PluginCodec {
+    codec: Mutex<Box<Codec>>,
+}
+
+impl Default for PluginCodec {
+    fn default() -> Self {
+        PluginCodec{ codec: Mutex::new(Box::new(crate::codec::ProtobufCodec)) }
+    }
+}
+
+impl PluginCodec {
+    pub fn name(&self) -> &'static str {
+        self.codec.lock().unwrap().name()
+    }
+}
 
 #[cfg(not(target_arch = "wasm32"))]
-#[derive(Default)]
 pub struct PluginHandler {
     libraries: HashMap<ModuleId, Box<crate::commonlibrary::CommonFFI>>,
+    supported_schemas: SchemaHandshake,
+    // Plugins that do not support negotiation are missing from here. Their requests are not checked.
+    negotiated: HashMap<ModuleId, NegotiatedSchemas>,
+    // Plugins that do not support negotiation are missing from here too. They get protobuf.
+    codecs: HashMap<ModuleId, Box<Codec>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for PluginHandler {
+    fn default() -> Self {
+        PluginHandler {
+            libraries: HashMap::new(),
+            supported_schemas: crate::negotiation::default_handshake(),
+            negotiated: HashMap::new(),
+            codecs: HashMap::new(),
+        }
+    }
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 impl PluginHandler {
-    /// Passthrough function
+    /// Load a plugin and negotiate with it. Plugins that cannot agree with us are not kept.
     pub fn load_and_cache_plugin(&mut self, path: &std::path::PathBuf) -> Result<(), failure::Error> {
         use crate::commonlibrary::PluginLoader;
-        self.libraries.load_and_cache_plugin(&path)?;
+        let library = self.libraries.load_plugin(&path)?;
+        let module_id = ModuleId::new(path.to_str().ok_or(failure::format_err!("{:?} is not a valid module id!", path))?.into());
+        self.add_plugin(module_id, library)
+    }
+
+    /// Negotiate with an already loaded plugin, and route requests for the module id to it if that succeeds.
+    pub fn add_plugin(&mut self, module_id: ModuleId, library: Box<crate::commonlibrary::CommonFFI>) -> Result<(), failure::Error> {
+        if let Some((negotiated, codec)) = self.negotiate(&module_id, &*library)? {
+            self.codecs.insert(module_id.clone(), codec);
+            self.negotiated.insert(module_id.clone(), negotiated);
+        }
+        self.libraries.insert(module_id, library);
         Ok(())
     }
+
+    /// The codecs we offer plugins loaded from now on, preferred first. Each plugin uses the first one it also supports.
+    pub fn set_codec_preference(&mut self, codecs: &[&str]) {
+        self.supported_schemas.codecs = codecs.iter().map(|name| name.to_string()).collect();
+    }
+
+    /// Let plugins loaded from now on know that we can decode this schema version. Add versions oldest first.
+    pub fn add_supported_schema(&mut self, name: &str, hash: &str) {
+        crate::negotiation::add_supported_schema(&mut self.supported_schemas, name, hash);
+    }
+
+    // None if the plugin does not support negotiation at all.
+    fn negotiate(&self, module_id: &ModuleId, library: &crate::commonlibrary::CommonFFI) -> Result<Option<(NegotiatedSchemas, Box<Codec>)>, failure::Error> {
+        let remote = match library.call_ffi_negotiate(&self.supported_schemas) {
+            Ok(remote) => remote,
+            Err(e) => {
+                log::warn!("{:?} does not support schema negotiation. Its requests will not be checked. {:?}", module_id, e);
+                return Ok(None);
+            },
+        };
+
+        let negotiated = crate::negotiation::negotiate(&self.supported_schemas, &remote)
+            .map_err(|e| failure::format_err!("Cannot load {:?}! {}", module_id, e))?;
+        let codec = crate::codec::codec_by_name(negotiated.codec())
+            .ok_or(failure::format_err!("{:?} chose codec {:?} which we do not support!", module_id, negotiated.codec()))?;
+        Ok(Some((negotiated, codec)))
+    }
+}
+
+/// Uses the codec this plugin agreed on in ffi_handle_negotiation.
+pub fn ffi_handle_received_bytes<T: Transporter>(codec: &PluginCodec, transporter: &mut T, bytes: &[u8]) -> Vec<u8> {
+    let codec = codec.codec.lock().unwrap();
+    ffi_handle_received_bytes_with_codec(transporter, &**codec, bytes)
 }
 
-pub fn ffi_handle_received_bytes<T: Transporter>(transporter: &mut T, bytes: &[u8]) -> Vec<u8> {
-    let transport = match quick_protobuf::deserialize_from_slice::<RequestTransport>(bytes) {
+pub fn ffi_handle_received_bytes_with_codec<T: Transporter>(transporter: &mut T, codec: &Codec, bytes: &[u8]) -> Vec<u8> {
+    let transport = match codec.decode_request(bytes) {
         Err(e) => format!("Cannot parse data! Possibly incorrect version. {:?}", e).into(),
         Ok(transport) => transporter.transport_data(&transport),
     };
 
-    // serialize_into_vec returns a result - one that we cannot pass back. Fail as gracefully as we can :(
-    match quick_protobuf::serialize_into_vec(&transport) {
+    // encode_return returns a result - one that we cannot pass back. Fail as gracefully as we can :(
+    match codec.encode_return(&transport) {
         Ok(bytes) => bytes.to_vec(),
         Err(e) => {
             log::error!("Cannot write ReturnTransport to bytes! {:?}", e);
@@ -38,13 +119,57 @@ pub fn ffi_handle_received_bytes<T: Transporter>(transporter: &mut T, bytes: &[u
     }
 }
 
+/// Plugins call this from their 'ffi_negotiate' function with the schema versions and codecs they support.
+/// The host's versions are received, and the plugin's versions are sent back along with the codec it chose.
+/// If the plugin cannot agree with the host, it replies with an empty handshake so that the host refuses it.
+pub fn ffi_handle_negotiation(codec: &PluginCodec, supported: &SchemaHandshake, bytes: &[u8]) -> Vec<u8> {
+    let negotiated = quick_protobuf::deserialize_from_slice::<SchemaHandshake>(bytes)
+        .map_err(|e| failure::format_err!("Cannot parse schema handshake from host! {:?}", e))
+        .and_then(|host| crate::negotiation::negotiate(supported, &host))
+        .and_then(|negotiated| crate::codec::codec_by_name(negotiated.codec())
+            .ok_or(failure::format_err!("Agreed on codec {:?} which does not exist!", negotiated.codec())));
+
+    let reply = match negotiated {
+        Err(e) => {
+            log::error!("Refusing the host! {}", e);
+            SchemaHandshake::default()
+        },
+        Ok(negotiated) => {
+            let mut reply = supported.clone();
+            reply.codecs = vec![negotiated.name().to_string()];
+            *codec.codec.lock().unwrap() = negotiated;
+            reply
+        },
+    };
+
+    match quick_protobuf::serialize_into_vec(&reply) {
+        Ok(bytes) => bytes.to_vec(),
+        Err(e) => {
+            log::error!("Cannot write SchemaHandshake to bytes! {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
 /// We want to propagate over any dynamic library
 #[cfg(not(target_arch = "wasm32"))]
 impl Transporter for PluginHandler {
     fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport { 
         let dest = &transport.moduleId;
+
+        // Refuse anything the plugin told us it cannot decode.
+        if let (Some(negotiated), Some(descriptor)) = (self.negotiated.get(dest), transport.event.descriptor()) {
+            if let Err(e) = negotiated.check(descriptor) {
+                return format!("{:?}", e).into();
+            }
+        }
+
         if let Some(node) = self.libraries.get(&dest) {
-            return match node.call_ffi_handle_request(transport) {
+            let codec: &Codec = match self.codecs.get(dest) {
+                Some(codec) => &**codec,
+                None => &crate::codec::ProtobufCodec,
+            };
+            return match node.call_ffi_handle_request(transport, codec) {
                 Ok(ret) => ret,
                 Err(e) => format!("Return Transport Error: {:?}", e).into(),
             }
@@ -54,3 +179,125 @@ impl Transporter for PluginHandler {
         format!("PluginHandler does not have handler or node that supports {:?}", dest).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commonlibrary::CommonFFI;
+    use crate::bundledschemas::{transport_schema_hash, TRANSPORT_SCHEMA_NAME};
+    use crate::codec::{CBOR, MESSAGEPACK, PROTOBUF};
+    use crate::negotiation;
+    use crate::testutil::Echo;
+    use failure::Error;
+    use std::sync::Arc;
+
+    // Negotiates and decodes requests the way a real plugin would.
+    struct FakePlugin {
+        supported: SchemaHandshake,
+        codec: Arc<PluginCodec>,
+    }
+
+    impl CommonFFI for FakePlugin {
+        fn call_ffi_handle_request(&self, request: &RequestTransport, codec: &Codec) -> Result<ReturnTransport, Error> {
+            let reply = ffi_handle_received_bytes(&self.codec, &mut Echo, &codec.encode_request(request)?);
+            codec.decode_return(&reply)
+        }
+
+        fn call_ffi_init(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn call_ffi_negotiate(&self, supported: &SchemaHandshake) -> Result<SchemaHandshake, Error> {
+            let reply = ffi_handle_negotiation(&self.codec, &self.supported, &quick_protobuf::serialize_into_vec(supported)?);
+            Ok(quick_protobuf::deserialize_from_slice(&reply)?)
+        }
+    }
+
+    fn plugin_with_codecs(schemas: &[(&str, &str)], codecs: &[&str]) -> (Box<CommonFFI>, Arc<PluginCodec>) {
+        let mut supported = SchemaHandshake::default();
+        supported.codecs = codecs.iter().map(|name| name.to_string()).collect();
+        for (name, hash) in schemas {
+            negotiation::add_supported_schema(&mut supported, name, hash);
+        }
+        let codec = Arc::new(PluginCodec::default());
+        (Box::new(FakePlugin{ supported, codec: codec.clone() }), codec)
+    }
+
+    fn plugin(schemas: &[(&str, &str)]) -> Box<CommonFFI> {
+        plugin_with_codecs(schemas, &[crate::codec::PROTOBUF]).0
+    }
+
+    fn request(module: &str, alias: &str) -> RequestTransport {
+        let descriptor = TypeDescriptor::new(alias.to_string(), "Structure".to_string());
+        let event = Event::new(ProcessStructData::new(StructDataChanges::new(Vec::new(), Vec::new(), descriptor), None).into());
+        RequestTransport::new(ModuleId::new(module.to_string()), event, None, None)
+    }
+
+    #[test]
+    fn test_plugin_that_cannot_agree_is_not_kept() {
+        let mut handler = PluginHandler::default();
+        let result = handler.add_plugin(ModuleId::new("plugin".to_string()), plugin(&[(TRANSPORT_SCHEMA_NAME, "QmOtherTransport")]));
+        assert!(result.is_err());
+        assert_eq!(handler.transport_data(&request("plugin", TRANSPORT_SCHEMA_NAME)).errors.len(), 1);
+    }
+
+    #[test]
+    fn test_requests_are_checked_against_the_version_we_send() {
+        let transport = transport_schema_hash();
+        let mut handler = PluginHandler::default();
+        handler.add_supported_schema("things", "QmThingsV1");
+        handler.add_supported_schema("things", "QmThingsV2");
+        handler.add_supported_schema("stuff", "QmStuffV1");
+        handler.add_plugin(ModuleId::new("current".to_string()), plugin(&[(TRANSPORT_SCHEMA_NAME, &transport), ("things", "QmThingsV2")])).unwrap();
+        handler.add_plugin(ModuleId::new("outdated".to_string()), plugin(&[(TRANSPORT_SCHEMA_NAME, &transport), ("things", "QmThingsV1")])).unwrap();
+
+        assert!(handler.transport_data(&request("current", "things")).errors.is_empty());
+        assert!(handler.transport_data(&request("current", "QmThingsV2")).errors.is_empty());
+        assert!(handler.transport_data(&request("current", TRANSPORT_SCHEMA_NAME)).errors.is_empty());
+        // The plugin never said it can decode these.
+        assert_eq!(handler.transport_data(&request("current", "stuff")).errors.len(), 1);
+        assert_eq!(handler.transport_data(&request("current", "unknown")).errors.len(), 1);
+        // We send the newest version of "things", which the outdated plugin cannot decode.
+        assert_eq!(handler.transport_data(&request("outdated", "things")).errors.len(), 1);
+        assert!(handler.transport_data(&request("outdated", "QmThingsV1")).errors.is_empty());
+    }
+
+    #[test]
+    fn test_plugin_refuses_host_it_cannot_agree_with() {
+        let supported = negotiation::default_handshake();
+        let mut host = SchemaHandshake::default();
+        negotiation::add_supported_schema(&mut host, TRANSPORT_SCHEMA_NAME, "QmOtherTransport");
+
+        let codec = PluginCodec::default();
+        let reply: SchemaHandshake = quick_protobuf::deserialize_from_slice(&ffi_handle_negotiation(&codec, &supported, &quick_protobuf::serialize_into_vec(&host).unwrap())).unwrap();
+        assert!(negotiation::negotiate(&host, &reply).is_err());
+        let reply: SchemaHandshake = quick_protobuf::deserialize_from_slice(&ffi_handle_negotiation(&codec, &supported, b"not a handshake")).unwrap();
+        assert!(negotiation::negotiate(&host, &reply).is_err());
+    }
+
+    #[test]
+    fn test_each_plugin_keeps_its_own_codec() {
+        let schemas = [(TRANSPORT_SCHEMA_NAME, transport_schema_hash())];
+        let schemas: Vec<(&str, &str)> = schemas.iter().map(|(name, hash)| (*name, hash.as_str())).collect();
+        let mut handler = PluginHandler::default();
+        handler.set_codec_preference(&[CBOR, MESSAGEPACK, PROTOBUF]);
+
+        let (cbor, cbor_codec) = plugin_with_codecs(&schemas, &[PROTOBUF, CBOR]);
+        let (msgpack, msgpack_codec) = plugin_with_codecs(&schemas, &[MESSAGEPACK]);
+        let (neither, _) = plugin_with_codecs(&schemas, &["unknown"]);
+        handler.add_plugin(ModuleId::new("cbor".to_string()), cbor).unwrap();
+        handler.add_plugin(ModuleId::new("msgpack".to_string()), msgpack).unwrap();
+        assert!(handler.add_plugin(ModuleId::new("neither".to_string()), neither).is_err());
+
+        // The host's preference wins over the plugin's order.
+        assert_eq!(cbor_codec.name(), CBOR);
+        assert_eq!(msgpack_codec.name(), MESSAGEPACK);
+        for module in &["cbor", "msgpack"] {
+            let sent = request(module, TRANSPORT_SCHEMA_NAME);
+            let ret = handler.transport_data(&sent);
+            assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+            assert_eq!(ret.vec, vec![sent.event]);
+        }
+        assert_eq!(handler.transport_data(&request("neither", TRANSPORT_SCHEMA_NAME)).errors.len(), 1);
+    }
+}
@@ -0,0 +1,676 @@
+//! A small .proto parser so that schemas can be inspected at runtime and from build scripts.
+//! It understands proto2 and proto3 messages, enums, oneofs, maps and services. Only the packed and default options are kept.
+use failure::Error;
+use std::convert::TryFrom;
+
+/// Messages declared inside of each other deeper than this are refused. Schemas can be fetched at runtime, so they are not trusted.
+pub const MAX_DEPTH: usize = 100;
+
+pub const MAX_FIELD_NUMBER: u32 = 536_870_911;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProtoFile {
+    pub syntax: String,
+    pub package: Option<String>,
+    pub imports: Vec<String>,
+    pub messages: Vec<MessageDef>,
+    pub enums: Vec<EnumDef>,
+    pub services: Vec<ServiceDef>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageDef {
+    pub name: String,
+    pub fields: Vec<FieldDef>,
+    pub messages: Vec<MessageDef>,
+    pub enums: Vec<EnumDef>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Label {
+    Required,
+    Optional,
+    Repeated,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldDef {
+    pub name: String,
+    pub number: u32,
+    pub label: Label,
+    /// The type as written in the schema. Maps are written as map<key,value>
+    pub typ: String,
+    pub oneof: Option<String>,
+    pub packed: Option<bool>,
+    pub default: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnumDef {
+    pub name: String,
+    pub values: Vec<(String, i32)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServiceDef {
+    pub name: String,
+    pub rpcs: Vec<RpcDef>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RpcDef {
+    pub name: String,
+    pub input: String,
+    pub output: String,
+}
+
+impl ProtoFile {
+    pub fn parse(source: &str) -> Result<ProtoFile, Error> {
+        Parser{ tokens: tokenize(source)?, pos: 0 }.parse_file()
+    }
+
+    pub fn parse_file(path: &std::path::PathBuf) -> Result<ProtoFile, Error> {
+        ProtoFile::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn is_proto3(&self) -> bool {
+        self.syntax == "proto3"
+    }
+
+    /// Find a message by its full name within this file. Nested messages are written as Outer.Inner
+    pub fn find_message(&self, path: &str) -> Option<&MessageDef> {
+        let path = match &self.package {
+            Some(package) if path.starts_with(&format!("{}.", package)) => &path[package.len() + 1..],
+            _ => path,
+        };
+
+        let mut parts = path.split('.');
+        let first = parts.next()?;
+        let mut message = self.messages.iter().find(|message| message.name == first)?;
+        for part in parts {
+            message = message.messages.iter().find(|message| message.name == part)?;
+        }
+        Some(message)
+    }
+
+    /// Find an rpc by Service.method
+    pub fn find_rpc(&self, path: &str) -> Option<&RpcDef> {
+        let path = match &self.package {
+            Some(package) if path.starts_with(&format!("{}.", package)) => &path[package.len() + 1..],
+            _ => path,
+        };
+
+        let split = path.rfind('.')?;
+        let service = self.services.iter().find(|service| service.name == path[..split])?;
+        service.rpcs.iter().find(|rpc| rpc.name == path[split + 1..])
+    }
+
+    /// Every message in the file by its full name, including nested ones.
+    pub fn all_messages(&self) -> Vec<(String, &MessageDef)> {
+        let mut all = Vec::new();
+        for message in &self.messages {
+            collect_messages(message, message.name.clone(), &mut all);
+        }
+        all
+    }
+
+    /// Every enum in the file by its full name, including the ones nested in messages.
+    pub fn all_enums(&self) -> Vec<(String, &EnumDef)> {
+        let mut all: Vec<(String, &EnumDef)> = self.enums.iter().map(|def| (def.name.clone(), def)).collect();
+        for message in &self.messages {
+            collect_enums(message, &message.name, &mut all);
+        }
+        all
+    }
+}
+
+fn collect_enums<'a>(message: &'a MessageDef, path: &str, all: &mut Vec<(String, &'a EnumDef)>) {
+    for def in &message.enums {
+        all.push((format!("{}.{}", path, def.name), def));
+    }
+    for nested in &message.messages {
+        collect_enums(nested, &format!("{}.{}", path, nested.name), all);
+    }
+}
+
+fn collect_messages<'a>(message: &'a MessageDef, path: String, all: &mut Vec<(String, &'a MessageDef)>) {
+    for nested in &message.messages {
+        collect_messages(nested, format!("{}.{}", path, nested.name), all);
+    }
+    all.push((path, message));
+}
+
+impl MessageDef {
+    pub fn field_by_number(&self, number: u32) -> Option<&FieldDef> {
+        self.fields.iter().find(|field| field.number == number)
+    }
+
+    pub fn field_by_name(&self, name: &str) -> Option<&FieldDef> {
+        self.fields.iter().find(|field| field.name == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(String),
+    Str(String),
+    Symbol(char),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Error> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' { i += 1; }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i + 1 < chars.len() && !(chars[i] == '*' && chars[i + 1] == '/') { i += 1; }
+            i += 2;
+        } else if c == '"' || c == '\'' {
+            let quote = c;
+            let mut text = String::new();
+            i += 1;
+            while i < chars.len() && chars[i] != quote {
+                if chars[i] == '\\' && i + 1 < chars.len() { i += 1; }
+                text.push(chars[i]);
+                i += 1;
+            }
+            if i >= chars.len() {
+                return Err(failure::format_err!("Unterminated string in schema!"));
+            }
+            i += 1;
+            tokens.push(Token::Str(text));
+        } else if c.is_ascii_digit() || (c == '-' && chars.get(i + 1).map(|c| c.is_ascii_digit()).unwrap_or(false)) {
+            let start = i;
+            i += 1;
+            let hex = chars.get(i) == Some(&'x') || chars.get(i) == Some(&'X') || chars.get(i + 1) == Some(&'x') || chars.get(i + 1) == Some(&'X');
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                // The sign of an exponent, as in 1e-5
+                let exponent = !hex && (chars[i] == 'e' || chars[i] == 'E');
+                i += 1;
+                if exponent && (chars.get(i) == Some(&'-') || chars.get(i) == Some(&'+')) { i += 1; }
+            }
+            tokens.push(Token::Int(chars[start..i].iter().collect()));
+        } else if c.is_alphabetic() || c == '_' || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') { i += 1; }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            tokens.push(Token::Symbol(c));
+            i += 1;
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, Error> {
+        let token = self.tokens.get(self.pos).cloned().ok_or(failure::format_err!("Unexpected end of schema!"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn is_symbol(&self, symbol: char) -> bool {
+        self.peek() == Some(&Token::Symbol(symbol))
+    }
+
+    fn is_ident(&self, ident: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(found)) => found == ident,
+            _ => false,
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<(), Error> {
+        match self.next()? {
+            Token::Symbol(found) if found == symbol => Ok(()),
+            other => Err(failure::format_err!("Expected {:?} but found {:?} in schema!", symbol, other)),
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.next()? {
+            Token::Ident(ident) => Ok(ident),
+            other => Err(failure::format_err!("Expected a name but found {:?} in schema!", other)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        match self.next()? {
+            Token::Str(text) => Ok(text),
+            other => Err(failure::format_err!("Expected a string but found {:?} in schema!", other)),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, Error> {
+        let text = match self.next()? {
+            Token::Int(text) => text,
+            other => return Err(failure::format_err!("Expected a number but found {:?} in schema!", other)),
+        };
+
+        let negative = text.starts_with('-');
+        let digits = if negative { &text[1..] } else { &text[..] };
+        let value = if digits.starts_with("0x") || digits.starts_with("0X") {
+            i64::from_str_radix(&digits[2..], 16)?
+        } else if digits.len() > 1 && digits.starts_with('0') {
+            i64::from_str_radix(&digits[1..], 8)?
+        } else {
+            digits.parse::<i64>()?
+        };
+        Ok(if negative { -value } else { value })
+    }
+
+    /// Skip everything up to and including the next ';' or a balanced {...} block.
+    fn skip_statement(&mut self) -> Result<(), Error> {
+        loop {
+            match self.next()? {
+                Token::Symbol(';') => return Ok(()),
+                Token::Symbol('{') => return self.skip_block(),
+                _ => {},
+            }
+        }
+    }
+
+    /// Skip until the '}' that closes an already opened block.
+    fn skip_block(&mut self) -> Result<(), Error> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.next()? {
+                Token::Symbol('{') => depth += 1,
+                Token::Symbol('}') => depth -= 1,
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_file(&mut self) -> Result<ProtoFile, Error> {
+        let mut file = ProtoFile{ syntax: "proto2".to_string(), ..Default::default() };
+
+        while self.peek().is_some() {
+            if self.is_symbol(';') {
+                self.next()?;
+                continue;
+            }
+
+            match self.ident()?.as_str() {
+                "syntax" => {
+                    self.expect_symbol('=')?;
+                    file.syntax = self.string()?;
+                    self.expect_symbol(';')?;
+                },
+                "package" => {
+                    file.package = Some(self.ident()?);
+                    self.expect_symbol(';')?;
+                },
+                "import" => {
+                    if self.is_ident("public") || self.is_ident("weak") {
+                        self.next()?;
+                    }
+                    file.imports.push(self.string()?);
+                    self.expect_symbol(';')?;
+                },
+                "message" => {
+                    let message = self.parse_message(file.syntax == "proto3", 1)?;
+                    file.messages.push(message);
+                },
+                "enum" => file.enums.push(self.parse_enum()?),
+                "service" => file.services.push(self.parse_service()?),
+                _ => self.skip_statement()?, // option, extend...
+            }
+        }
+
+        Ok(file)
+    }
+
+    fn parse_message(&mut self, proto3: bool, depth: usize) -> Result<MessageDef, Error> {
+        let mut message = MessageDef{ name: self.ident()?, ..Default::default() };
+        if depth > MAX_DEPTH {
+            return Err(failure::format_err!("{:?} is nested more than {} messages deep!", message.name, MAX_DEPTH));
+        }
+        self.expect_symbol('{')?;
+
+        while !self.is_symbol('}') {
+            if self.is_symbol(';') {
+                self.next()?;
+                continue;
+            }
+
+            if self.is_ident("message") {
+                self.next()?;
+                message.messages.push(self.parse_message(proto3, depth + 1)?);
+            } else if self.is_ident("enum") {
+                self.next()?;
+                message.enums.push(self.parse_enum()?);
+            } else if self.is_ident("oneof") {
+                self.next()?;
+                let oneof = self.ident()?;
+                self.expect_symbol('{')?;
+                while !self.is_symbol('}') {
+                    if self.is_symbol(';') { self.next()?; continue; }
+                    if self.is_ident("option") { self.skip_statement()?; continue; }
+                    let typ = self.ident()?;
+                    let mut field = self.parse_field_rest(Label::Optional, typ)?;
+                    field.oneof = Some(oneof.clone());
+                    message.fields.push(field);
+                }
+                self.expect_symbol('}')?;
+            } else if self.is_ident("option") || self.is_ident("reserved") || self.is_ident("extensions") || self.is_ident("extend") {
+                self.skip_statement()?;
+            } else if self.is_ident("group") {
+                return Err(failure::format_err!("Groups are not supported in {:?}!", message.name));
+            } else {
+                let first = self.ident()?;
+                let (label, typ) = match first.as_str() {
+                    "required" => (Label::Required, self.parse_type()?),
+                    "optional" => (Label::Optional, self.parse_type()?),
+                    "repeated" => (Label::Repeated, self.parse_type()?),
+                    "map" => (Label::Repeated, self.parse_map_type()?),
+                    _ => (Label::Optional, first),
+                };
+                let mut field = self.parse_field_rest(label, typ)?;
+                if proto3 && label == Label::Repeated && field.packed.is_none() && is_packable(&field.typ) {
+                    field.packed = Some(true);
+                }
+                message.fields.push(field);
+            }
+        }
+
+        self.expect_symbol('}')?;
+        Ok(message)
+    }
+
+    fn parse_type(&mut self) -> Result<String, Error> {
+        if self.is_ident("map") {
+            self.next()?;
+            return self.parse_map_type();
+        }
+        self.ident()
+    }
+
+    fn parse_map_type(&mut self) -> Result<String, Error> {
+        self.expect_symbol('<')?;
+        let key = self.ident()?;
+        self.expect_symbol(',')?;
+        let value = self.ident()?;
+        self.expect_symbol('>')?;
+        Ok(format!("map<{},{}>", key, value))
+    }
+
+    /// name = number [options];
+    fn parse_field_rest(&mut self, label: Label, typ: String) -> Result<FieldDef, Error> {
+        let name = self.ident()?;
+        self.expect_symbol('=')?;
+        let number = self.int()?;
+        let number = u32::try_from(number).ok()
+            .filter(|number| *number >= 1 && *number <= MAX_FIELD_NUMBER)
+            .ok_or(failure::format_err!("Field {:?} has number {}, which is not between 1 and {}!", name, number, MAX_FIELD_NUMBER))?;
+        let mut field = FieldDef{ name, number, label, typ, oneof: None, packed: None, default: None };
+
+        for (option, value) in self.parse_options()? {
+            match (option.as_str(), value) {
+                ("packed", Some(value)) => field.packed = Some(value == "true"),
+                ("default", Some(value)) => field.default = Some(value),
+                _ => {},
+            }
+        }
+
+        self.expect_symbol(';')?;
+        Ok(field)
+    }
+
+    /// [name = value, ...] after a field or enum value. Returns nothing if there are no options.
+    fn parse_options(&mut self) -> Result<Vec<(String, Option<String>)>, Error> {
+        let mut options = Vec::new();
+        if !self.is_symbol('[') {
+            return Ok(options);
+        }
+
+        self.next()?;
+        while !self.is_symbol(']') {
+            let name = self.parse_option_name()?;
+            options.push((name, self.parse_option_value()?));
+            if self.is_symbol(',') { self.next()?; }
+        }
+        self.expect_symbol(']')?;
+        Ok(options)
+    }
+
+    /// A plain name such as packed, or a custom option such as (my.option).field
+    fn parse_option_name(&mut self) -> Result<String, Error> {
+        let mut name = String::new();
+        while !self.is_symbol('=') {
+            match self.next()? {
+                Token::Ident(part) => name.push_str(&part),
+                Token::Symbol(symbol) if symbol == '(' || symbol == ')' => name.push(symbol),
+                other => return Err(failure::format_err!("Unexpected {:?} in option name {:?}!", other, name)),
+            }
+        }
+        self.expect_symbol('=')?;
+        Ok(name)
+    }
+
+    /// Aggregate values such as { a: 1 } are skipped and return None.
+    fn parse_option_value(&mut self) -> Result<Option<String>, Error> {
+        match self.next()? {
+            Token::Ident(value) | Token::Int(value) => Ok(Some(value)),
+            Token::Str(mut value) => {
+                // Adjacent strings are joined together.
+                while let Some(Token::Str(more)) = self.peek().cloned() {
+                    self.next()?;
+                    value.push_str(&more);
+                }
+                Ok(Some(value))
+            },
+            // -inf and -nan
+            Token::Symbol('-') => match self.next()? {
+                Token::Ident(value) | Token::Int(value) => Ok(Some(format!("-{}", value))),
+                other => Err(failure::format_err!("Expected a number after '-' but found {:?} in schema!", other)),
+            },
+            Token::Symbol('{') => {
+                self.skip_block()?;
+                Ok(None)
+            },
+            Token::Symbol(symbol) => Err(failure::format_err!("Unexpected {:?} in option value!", symbol)),
+        }
+    }
+
+    fn parse_enum(&mut self) -> Result<EnumDef, Error> {
+        let mut def = EnumDef{ name: self.ident()?, ..Default::default() };
+        self.expect_symbol('{')?;
+
+        while !self.is_symbol('}') {
+            if self.is_symbol(';') { self.next()?; continue; }
+            if self.is_ident("option") || self.is_ident("reserved") { self.skip_statement()?; continue; }
+
+            let name = self.ident()?;
+            self.expect_symbol('=')?;
+            let value = self.int()?;
+            let value = i32::try_from(value)
+                .map_err(|_| failure::format_err!("Enum value {:?} = {} does not fit in an int32!", name, value))?;
+            self.parse_options()?;
+            self.expect_symbol(';')?;
+            def.values.push((name, value));
+        }
+
+        self.expect_symbol('}')?;
+        Ok(def)
+    }
+
+    fn parse_service(&mut self) -> Result<ServiceDef, Error> {
+        let mut service = ServiceDef{ name: self.ident()?, ..Default::default() };
+        self.expect_symbol('{')?;
+
+        while !self.is_symbol('}') {
+            if self.is_symbol(';') { self.next()?; continue; }
+            if !self.is_ident("rpc") { self.skip_statement()?; continue; }
+
+            self.next()?;
+            let name = self.ident()?;
+            let input = self.parse_rpc_type()?;
+            if self.ident()? != "returns" {
+                return Err(failure::format_err!("Expected 'returns' in rpc {:?}!", name));
+            }
+            let output = self.parse_rpc_type()?;
+
+            if self.is_symbol('{') {
+                self.next()?;
+                self.skip_block()?;
+            } else {
+                self.expect_symbol(';')?;
+            }
+            service.rpcs.push(RpcDef{ name, input, output });
+        }
+
+        self.expect_symbol('}')?;
+        Ok(service)
+    }
+
+    fn parse_rpc_type(&mut self) -> Result<String, Error> {
+        self.expect_symbol('(')?;
+        if self.is_ident("stream") {
+            self.next()?;
+        }
+        let typ = self.ident()?;
+        self.expect_symbol(')')?;
+        Ok(typ)
+    }
+}
+
+/// Scalar types that can be packed when repeated.
+pub fn is_packable(typ: &str) -> bool {
+    match typ {
+        "double" | "float" | "int32" | "int64" | "uint32" | "uint64" | "sint32" | "sint64" |
+        "fixed32" | "fixed64" | "sfixed32" | "sfixed64" | "bool" => true,
+        _ => false,
+    }
+}
+
+pub fn is_scalar(typ: &str) -> bool {
+    is_packable(typ) || typ == "string" || typ == "bytes"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static FIXTURE: &str = r#"
+        syntax = "proto2";
+        package fixture;
+        import public "other.proto";
+        option (file.option) = { name: "x" values: [1, 2] };
+
+        /* A message with
+           everything in it. */
+        message Outer {
+            option deprecated = true;
+            required string name = 1 [(custom.field).rule = { min: 1 max: 2 }];
+            optional double ratio = 2 [default = -inf];
+            optional float scale = 3 [default = 1.5e-3, deprecated = true];
+            repeated int32 values = 4 [packed = true];
+            map<string, Inner> lookup = 5;
+            optional string motd = 6 [default = "hello " "world"];
+            oneof choice {
+                int64 number = 7;
+                Inner inner = 8;
+            }
+            reserved 9 to 11;
+            message Inner {
+                optional Kind kind = 1 [default = SECOND];
+                enum Kind {
+                    FIRST = 0;
+                    SECOND = 1 [(custom.value) = { label: "two" }];
+                    NEGATIVE = -0x2;
+                }
+            }
+        }
+
+        enum Top { ZERO = 0; }
+
+        service Things {
+            option (service.option) = "ignored";
+            rpc Get(Outer) returns (Outer.Inner);
+            rpc Watch(stream Outer) returns (stream Outer) {}
+        }
+    "#;
+
+    #[test]
+    fn test_parse_fixture() {
+        let file = ProtoFile::parse(FIXTURE).unwrap();
+        assert_eq!(file.syntax, "proto2");
+        assert_eq!(file.package, Some("fixture".to_string()));
+        assert_eq!(file.imports, vec!["other.proto".to_string()]);
+
+        let outer = file.find_message("fixture.Outer").unwrap();
+        let names: Vec<&str> = outer.fields.iter().map(|field| field.name.as_str()).collect();
+        assert_eq!(names, vec!["name", "ratio", "scale", "values", "lookup", "motd", "number", "inner"]);
+        assert_eq!(outer.field_by_name("name").unwrap().label, Label::Required);
+        assert_eq!(outer.field_by_name("ratio").unwrap().default, Some("-inf".to_string()));
+        assert_eq!(outer.field_by_name("scale").unwrap().default, Some("1.5e-3".to_string()));
+        assert_eq!(outer.field_by_name("values").unwrap().packed, Some(true));
+        assert_eq!(outer.field_by_name("lookup").unwrap().typ, "map<string,Inner>");
+        assert_eq!(outer.field_by_name("motd").unwrap().default, Some("hello world".to_string()));
+        assert_eq!(outer.field_by_number(8).unwrap().oneof, Some("choice".to_string()));
+
+        let inner = file.find_message("Outer.Inner").unwrap();
+        assert_eq!(inner.field_by_name("kind").unwrap().default, Some("SECOND".to_string()));
+        let enums: Vec<(String, Vec<(String, i32)>)> = file.all_enums().into_iter().map(|(name, def)| (name, def.values.clone())).collect();
+        assert_eq!(enums, vec![
+            ("Top".to_string(), vec![("ZERO".to_string(), 0)]),
+            ("Outer.Inner.Kind".to_string(), vec![("FIRST".to_string(), 0), ("SECOND".to_string(), 1), ("NEGATIVE".to_string(), -2)]),
+        ]);
+
+        assert_eq!(file.find_rpc("Things.Get").unwrap().output, "Outer.Inner");
+        assert_eq!(file.find_rpc("fixture.Things.Watch").unwrap().input, "Outer");
+    }
+
+    #[test]
+    fn test_proto3_packs_repeated_scalars() {
+        let file = ProtoFile::parse("syntax = \"proto3\"; message M { repeated int32 a = 1; repeated string b = 2; repeated int32 c = 3 [packed = false]; }").unwrap();
+        let message = file.find_message("M").unwrap();
+        assert_eq!(message.fields.iter().map(|field| field.packed).collect::<Vec<_>>(), vec![Some(true), None, Some(false)]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(ProtoFile::parse("message M { required string name = 1").is_err());
+        assert!(ProtoFile::parse("message M { optional string name = 1 [default = \"x]; }").is_err());
+        assert!(ProtoFile::parse("message M { optional double d = 1 [default = -]; }").is_err());
+        assert!(ProtoFile::parse("message M { optional group G = 1 { } }").is_err());
+    }
+
+    #[test]
+    fn test_numbers_out_of_range() {
+        assert!(ProtoFile::parse("message M { optional int32 a = 536870911; }").is_ok());
+        assert!(ProtoFile::parse("message M { optional int32 a = 536870912; }").is_err());
+        assert!(ProtoFile::parse("message M { optional int32 a = 0; }").is_err());
+        assert!(ProtoFile::parse("message M { optional int32 a = -1; }").is_err());
+        assert!(ProtoFile::parse("message M { optional int32 a = 4294967297; }").is_err());
+        assert!(ProtoFile::parse("enum E { A = -2147483648; }").is_ok());
+        assert!(ProtoFile::parse("enum E { A = 2147483648; }").is_err());
+    }
+
+    #[test]
+    fn test_nesting_is_limited() {
+        let nested = |depth: usize| format!("{}{}", "message M { ".repeat(depth), "}".repeat(depth));
+        assert!(ProtoFile::parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(ProtoFile::parse(&nested(MAX_DEPTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! A topic bus for broadcasting structures. Topics are TypeDescriptors, and every ProcessStructData published
+//! is copied to each subscriber of its descriptor. Subscribers are either local listeners or modules reached through a transporter.
+//! The bus is a Transporter too, so remote publishers can reach it once it is added to a TransportNode with add_node(...).
+use crate::autogen_protobuf::transport::*;
+use crate::transporter::Transporter;
+
+use failure::Error;
+use std::collections::VecDeque;
+
+/// Called with every structure published to the topic. Returning an error counts as a failed delivery.
+pub type TopicListener = Box<FnMut(&ProcessStructData) -> Result<(), Error>>;
+
+/// At-least-once subscribers keep at most this many undelivered structures. The oldest are dropped first.
+pub const DEFAULT_MAX_PENDING: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    /// Sent once. Dropped if the delivery fails.
+    AtMostOnce,
+    /// Kept until it is delivered. Failed deliveries are retried in order, so a subscriber may receive a structure more than once.
+    AtLeastOnce,
+}
+
+enum Sink {
+    Local(TopicListener),
+    Remote(ModuleId, Box<Transporter>),
+}
+
+impl Sink {
+    fn deliver(&mut self, data: &ProcessStructData) -> Result<(), Error> {
+        match self {
+            Sink::Local(listener) => listener(data),
+            Sink::Remote(module_id, transporter) => {
+                let transport = RequestTransport::new(module_id.clone(), Event::new(data.clone().into()), None, None);
+                let ret = transporter.transport_data(&transport);
+                if !ret.errors.is_empty() {
+                    return Err(failure::format_err!("{}", ret.errors.join("\n")));
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+struct Subscriber {
+    id: ModuleId,
+    topic: TypeDescriptor,
+    delivery: Delivery,
+    sink: Sink,
+    pending: VecDeque<ProcessStructData>,
+}
+
+impl Subscriber {
+    /// Deliver pending structures in order until one fails. Returns how many were delivered.
+    fn flush(&mut self) -> usize {
+        let mut delivered = 0;
+        while let Some(data) = self.pending.front() {
+            if let Err(e) = self.sink.deliver(data) {
+                log::debug!("Delivery to {:?} failed. {} structures are pending. {:?}", self.id, self.pending.len(), e);
+                break;
+            }
+            self.pending.pop_front();
+            delivered += 1;
+        }
+        delivered
+    }
+}
+
+pub struct TopicBus {
+    subscribers: Vec<Subscriber>,
+    max_pending: usize,
+}
+
+impl Default for TopicBus {
+    fn default() -> Self {
+        TopicBus{ subscribers: Vec::new(), max_pending: DEFAULT_MAX_PENDING }
+    }
+}
+
+impl TopicBus {
+    pub fn set_max_pending(&mut self, max_pending: usize) {
+        self.max_pending = max_pending;
+    }
+
+    /// Returns the id used to unsubscribe.
+    pub fn subscribe(&mut self, topic: TypeDescriptor, delivery: Delivery, listener: TopicListener) -> ModuleId {
+        self.add_subscriber(topic, delivery, Sink::Local(listener))
+    }
+
+    /// Structures are sent to `module_id` through the transporter. Returns the id used to unsubscribe.
+    pub fn subscribe_remote<T: 'static + Transporter>(&mut self, topic: TypeDescriptor, delivery: Delivery, module_id: ModuleId, transporter: T) -> ModuleId {
+        self.add_subscriber(topic, delivery, Sink::Remote(module_id, Box::new(transporter)))
+    }
+
+    fn add_subscriber(&mut self, topic: TypeDescriptor, delivery: Delivery, sink: Sink) -> ModuleId {
+        let id = ModuleId::new(uuid::Uuid::new_v4().to_string());
+        self.subscribers.push(Subscriber{ id: id.clone(), topic, delivery, sink, pending: VecDeque::new() });
+        id
+    }
+
+    /// Anything still pending for the subscriber is dropped.
+    pub fn unsubscribe(&mut self, id: &ModuleId) {
+        self.subscribers.retain(|subscriber| &subscriber.id != id);
+    }
+
+    /// Copy the structure to every subscriber of its descriptor. Returns how many deliveries succeeded, including retried ones.
+    pub fn publish(&mut self, data: &ProcessStructData) -> usize {
+        let topic = &data.changes.descriptor;
+        let max_pending = self.max_pending;
+        let mut delivered = 0;
+
+        for subscriber in self.subscribers.iter_mut().filter(|subscriber| &subscriber.topic == topic) {
+            match subscriber.delivery {
+                Delivery::AtMostOnce => match subscriber.sink.deliver(data) {
+                    Ok(()) => delivered += 1,
+                    Err(e) => log::warn!("Dropping structure for {:?}! {:?}", subscriber.id, e),
+                },
+                Delivery::AtLeastOnce => {
+                    if subscriber.pending.len() >= max_pending {
+                        log::warn!("Too many structures are pending for {:?}! Dropping the oldest.", subscriber.id);
+                        subscriber.pending.pop_front();
+                    }
+                    subscriber.pending.push_back(data.clone());
+                    delivered += subscriber.flush();
+                },
+            }
+        }
+        delivered
+    }
+
+    /// Try again to deliver everything pending for at-least-once subscribers. Returns how many deliveries succeeded.
+    pub fn retry(&mut self) -> usize {
+        self.subscribers.iter_mut().map(|subscriber| subscriber.flush()).sum()
+    }
+
+    /// How many structures are waiting to be delivered to the subscriber.
+    pub fn pending(&self, id: &ModuleId) -> usize {
+        self.subscribers.iter()
+            .find(|subscriber| &subscriber.id == id)
+            .map(|subscriber| subscriber.pending.len())
+            .unwrap_or(0)
+    }
+}
+
+impl Transporter for TopicBus {
+    /// ProcessStructData sent here is published. The publisher is not told which subscribers failed.
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        match &transport.event.data {
+            mod_Event::OneOfdata::process_struct(data) => {
+                self.publish(data);
+                Vec::new().into()
+            },
+            other => format!("{:?} cannot be published! Only ProcessStructData can.", other).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn structure(topic: &str, byte: u8) -> ProcessStructData {
+        let descriptor = TypeDescriptor::new("alias".to_string(), topic.to_string());
+        ProcessStructData::new(StructDataChanges::new(vec![byte], Vec::new(), descriptor), None)
+    }
+
+    #[test]
+    fn test_delivery() {
+        let mut bus = TopicBus::default();
+        let topic = structure("Position", 0).changes.descriptor;
+        let online = Rc::new(RefCell::new(false));
+        let received = Rc::new(RefCell::new(Vec::new()));
+
+        // Fails until it comes online.
+        let listener = |online: Rc<RefCell<bool>>, received: Rc<RefCell<Vec<u8>>>| -> TopicListener {
+            Box::new(move |data: &ProcessStructData| {
+                if !*online.borrow() {
+                    return Err(failure::format_err!("Offline"));
+                }
+                received.borrow_mut().push(data.changes.serializedData[0]);
+                Ok(())
+            })
+        };
+
+        let most = Rc::new(RefCell::new(Vec::new()));
+        bus.subscribe(topic.clone(), Delivery::AtMostOnce, listener(online.clone(), most.clone()));
+        let least = bus.subscribe(topic.clone(), Delivery::AtLeastOnce, listener(online.clone(), received.clone()));
+
+        assert_eq!(bus.publish(&structure("Position", 1)), 0);
+        assert_eq!(bus.publish(&structure("Other", 9)), 0);
+        assert_eq!(bus.pending(&least), 1);
+
+        *online.borrow_mut() = true;
+        assert_eq!(bus.publish(&structure("Position", 2)), 3);
+        assert_eq!(*received.borrow(), vec![1, 2]);
+        assert_eq!(*most.borrow(), vec![2]);
+        assert_eq!(bus.pending(&least), 0);
+
+        bus.unsubscribe(&least);
+        assert_eq!(bus.publish(&structure("Position", 3)), 1);
+        assert_eq!(*received.borrow(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,448 @@
+//! Reliable one-way delivery over links that can lose requests or their returns.
+//! ReliableSender numbers each request and retransmits it with exponential backoff until a return acknowledges it.
+//! ReliableReceiver wraps the transporter on the other side. It acknowledges every request and passes each sequence on only once,
+//! so retransmissions whose first return was lost are not applied twice. Each request also carries the sender's low-water mark,
+//! so the receiver stops tracking sequences the sender will never send again.
+use crate::autogen_protobuf::transport::*;
+use crate::transporter::Transporter;
+
+use failure::Error;
+use hashbrown::HashMap;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+/// ReliableReceiver forgets the least recently heard from sender beyond this many.
+/// A forgotten sender's retransmissions may be passed on again, unless its low-water mark has moved past them.
+pub const MAX_SENDERS: usize = 1024;
+
+/// ReliableReceiver refuses requests that would leave more than this many sequences from one sender waiting for a gap to fill.
+/// They are not acknowledged, so the sender retries them later.
+pub const MAX_OUT_OF_ORDER: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    /// The backoff doubles after each failed attempt, up to this.
+    pub max_backoff: Duration,
+    /// The request is given up on after this many attempts.
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy{ initial_backoff: Duration::from_millis(100), max_backoff: Duration::from_secs(10), max_attempts: 10 }
+    }
+}
+
+struct Outgoing {
+    transport: RequestTransport,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+pub struct ReliableSender<T: Transporter> {
+    inner: T,
+    sender_id: String,
+    next_sequence: u64,
+    policy: RetryPolicy,
+    unacknowledged: BTreeMap<u64, Outgoing>,
+}
+
+impl<T: Transporter> ReliableSender<T> {
+    /// The sender id must be unique among everything sending to the same receiver.
+    /// Sequences start from the clock, so a sender that restarts with the same id is not mistaken for its old self.
+    pub fn new(inner: T, sender_id: &str) -> Self {
+        let next_sequence = crate::replay::initial_counter();
+        ReliableSender{ inner, sender_id: sender_id.to_string(), next_sequence, policy: RetryPolicy::default(), unacknowledged: BTreeMap::new() }
+    }
+
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Send the request now, and keep retrying it from poll() until it is acknowledged. Returns its sequence.
+    pub fn send(&mut self, transport: &RequestTransport) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+
+        let mut outgoing = Outgoing{ transport: transport.clone(), attempts: 0, next_attempt: Instant::now() };
+
+        if !self.attempt(sequence, &mut outgoing, Instant::now()) {
+            self.unacknowledged.insert(sequence, outgoing);
+        }
+        sequence
+    }
+
+    /// Retransmit everything that is due. Returns the requests that were given up on.
+    pub fn poll(&mut self) -> Vec<RequestTransport> {
+        self.poll_at(Instant::now())
+    }
+
+    /// Like poll(), as if it were `now`. Lets tests skip the backoff.
+    pub fn poll_at(&mut self, now: Instant) -> Vec<RequestTransport> {
+        let due: Vec<u64> = self.unacknowledged.iter()
+            .filter(|(_, outgoing)| outgoing.next_attempt <= now)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+
+        let mut given_up = Vec::new();
+        for sequence in due {
+            let mut outgoing = match self.unacknowledged.remove(&sequence) {
+                Some(outgoing) => outgoing,
+                None => continue,
+            };
+
+            if self.attempt(sequence, &mut outgoing, now) {
+                continue;
+            }
+
+            if outgoing.attempts >= self.policy.max_attempts {
+                log::warn!("Giving up on request {} after {} attempts!", sequence, outgoing.attempts);
+                outgoing.transport.delivery = None;
+                given_up.push(outgoing.transport);
+            } else {
+                self.unacknowledged.insert(sequence, outgoing);
+            }
+        }
+        given_up
+    }
+
+    /// How many requests are still waiting on an acknowledgement.
+    pub fn unacknowledged(&self) -> usize {
+        self.unacknowledged.len()
+    }
+
+    // Returns true if the request was acknowledged. Otherwise schedules the next attempt.
+    fn attempt(&mut self, sequence: u64, outgoing: &mut Outgoing, now: Instant) -> bool {
+        // The request being attempted is not in unacknowledged right now.
+        let low_water = self.unacknowledged.keys().next().map(|oldest| std::cmp::min(*oldest, sequence)).unwrap_or(sequence);
+        outgoing.transport.delivery = Some(DeliveryHeader::new(self.sender_id.clone(), sequence, Some(low_water)));
+        outgoing.attempts += 1;
+        let ret = self.inner.transport_data(&outgoing.transport);
+        if ret.ack == Some(sequence) {
+            // Delivered. Errors from whatever handled it will not go away by sending it again.
+            for err in ret.errors { log::warn!("Request {} was delivered, but failed! {:?}", sequence, err); }
+            return true;
+        }
+
+        let backoff = self.policy.initial_backoff * 2u32.saturating_pow(outgoing.attempts - 1);
+        outgoing.next_attempt = now + std::cmp::min(backoff, self.policy.max_backoff);
+        log::debug!("Request {} was not acknowledged. Retrying in {:?}. {:?}", sequence, outgoing.next_attempt - now, ret.errors);
+        false
+    }
+}
+
+// Sequences from one sender that were already passed on.
+#[derive(Default)]
+struct Received {
+    // Every sequence up to and including this one.
+    contiguous: u64,
+    // Sequences above contiguous that arrived out of order.
+    above: BTreeSet<u64>,
+    // When the sender was last heard from, in requests received by the ReliableReceiver.
+    last_used: u64,
+}
+
+impl Received {
+    // Returns false if the sequence was already received.
+    fn insert(&mut self, sequence: u64) -> Result<bool, Error> {
+        if sequence <= self.contiguous || self.above.contains(&sequence) {
+            return Ok(false);
+        }
+        if sequence != self.contiguous + 1 && self.above.len() >= MAX_OUT_OF_ORDER {
+            return Err(failure::format_err!("Already {} sequences are waiting for {}!", self.above.len(), self.contiguous + 1));
+        }
+
+        self.above.insert(sequence);
+        self.merge_above();
+        Ok(true)
+    }
+
+    // The sender will never send anything below low_water again, so there is nothing left to wait for down there.
+    fn advance(&mut self, low_water: u64) {
+        if low_water <= self.contiguous + 1 {
+            return;
+        }
+        self.contiguous = low_water - 1;
+        self.above = self.above.split_off(&low_water);
+        self.merge_above();
+    }
+
+    fn merge_above(&mut self) {
+        while self.above.remove(&(self.contiguous + 1)) {
+            self.contiguous += 1;
+        }
+    }
+}
+
+pub struct ReliableReceiver<T: Transporter> {
+    inner: T,
+    received: HashMap<String, Received>,
+    clock: u64,
+}
+
+impl<T: Transporter> ReliableReceiver<T> {
+    pub fn new(inner: T) -> Self {
+        ReliableReceiver{ inner, received: HashMap::new(), clock: 0 }
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn received_from(&mut self, sender_id: &str) -> &mut Received {
+        if !self.received.contains_key(sender_id) && self.received.len() >= MAX_SENDERS {
+            let oldest = self.received.iter().min_by_key(|(_, received)| received.last_used).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                log::debug!("Forgetting sender {:?}", oldest);
+                self.received.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        let received = self.received.entry(sender_id.to_string()).or_insert_with(Received::default);
+        received.last_used = self.clock;
+        received
+    }
+}
+
+impl<T: Transporter> Transporter for ReliableReceiver<T> {
+    /// Requests without a DeliveryHeader are passed straight through.
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        let header = match &transport.delivery {
+            Some(header) => header,
+            None => return self.inner.transport_data(transport),
+        };
+
+        let received = self.received_from(&header.senderId);
+        if let Some(low_water) = header.lowWater {
+            received.advance(low_water);
+        }
+        match received.insert(header.sequence) {
+            Ok(true) => (),
+            Ok(false) => {
+                log::debug!("Dropping duplicate request {} from {:?}", header.sequence, header.senderId);
+                let mut ret: ReturnTransport = Vec::new().into();
+                ret.ack = Some(header.sequence);
+                return ret;
+            },
+            Err(e) => return format!("Refusing request {} from {:?}! {:?}", header.sequence, header.senderId, e).into(),
+        }
+
+        let mut ret = self.inner.transport_data(transport);
+        ret.ack = Some(header.sequence);
+        ret
+    }
+}
+
+/// Loses requests and returns at random, as an unreliable network would. Seeded, so every run loses the same ones.
+pub struct LossySimulator<T: Transporter> {
+    inner: T,
+    rng: StdRng,
+    request_loss: f64,
+    return_loss: f64,
+    lost: usize,
+}
+
+impl<T: Transporter> LossySimulator<T> {
+    /// Losses are probabilities from 0.0 to 1.0. A lost return means the request was still handled.
+    pub fn new(inner: T, seed: u64, request_loss: f64, return_loss: f64) -> Self {
+        LossySimulator{ inner, rng: StdRng::seed_from_u64(seed), request_loss, return_loss, lost: 0 }
+    }
+
+    /// How many requests or returns were lost so far.
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Transporter> Transporter for LossySimulator<T> {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        if self.rng.gen_bool(self.request_loss) {
+            self.lost += 1;
+            return "Simulated loss of request!".to_string().into();
+        }
+
+        let ret = self.inner.transport_data(transport);
+        if self.rng.gen_bool(self.return_loss) {
+            self.lost += 1;
+            return "Simulated loss of return!".to_string().into();
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records the id of every destructor it receives.
+    #[derive(Default)]
+    struct Recorder {
+        received: Vec<String>,
+    }
+
+    impl Transporter for Recorder {
+        fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+            if let mod_Event::OneOfdata::destructor(data) = &transport.event.data {
+                self.received.push(data.id.val.clone());
+            }
+            Vec::new().into()
+        }
+    }
+
+    // Loses every request while it is closed.
+    struct Gate<T: Transporter> {
+        inner: T,
+        open: bool,
+    }
+
+    impl<T: Transporter> Transporter for Gate<T> {
+        fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+            if !self.open {
+                return "Closed!".to_string().into();
+            }
+            self.inner.transport_data(transport)
+        }
+    }
+
+    fn request(id: &str) -> RequestTransport {
+        crate::testutil::request("recorder", id)
+    }
+
+    #[test]
+    fn test_reliable_delivery_over_lossy_link() {
+        let link = LossySimulator::new(ReliableReceiver::new(Recorder::default()), 7, 0.3, 0.3);
+        let policy = RetryPolicy{ max_attempts: 100, ..RetryPolicy::default() };
+        let mut sender = ReliableSender::new(link, "sender").with_retry_policy(policy);
+
+        let descriptor = TypeDescriptor::new("alias".to_string(), "Structure".to_string());
+        let sent: Vec<String> = (0..50).map(|i| i.to_string()).collect();
+        for id in &sent {
+            let event = Event::new(DestructorData::new(Id::new(id.clone()), descriptor.clone()).into());
+            sender.send(&RequestTransport::new(ModuleId::new("recorder".to_string()), event, None, None));
+        }
+
+        // Pretend the backoff has always passed, so this runs instantly.
+        let mut later = Instant::now();
+        while sender.unacknowledged() > 0 {
+            later += Duration::from_secs(60);
+            assert!(sender.poll_at(later).is_empty());
+        }
+
+        // Returns were lost too, so some requests arrived more than once. Each was passed on exactly once.
+        let link = sender.inner_mut();
+        assert!(link.lost() > 0);
+        let mut received = link.inner_mut().inner_mut().received.clone();
+        received.sort_by_key(|id| id.parse::<u32>().unwrap());
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn test_duplicate_suppression() {
+        let mut received = Received::default();
+        assert!(received.insert(2).unwrap());
+        assert!(received.insert(1).unwrap());
+        assert!(!received.insert(2).unwrap());
+        assert!(!received.insert(1).unwrap());
+        assert!(received.insert(4).unwrap());
+        assert_eq!(received.contiguous, 2);
+        assert!(received.insert(3).unwrap());
+        assert_eq!(received.contiguous, 4);
+        assert!(received.above.is_empty());
+    }
+
+    #[test]
+    fn test_restarted_sender_is_not_mistaken_for_a_duplicate() {
+        let mut first = ReliableSender::new(ReliableReceiver::new(Recorder::default()), "sender");
+        first.send(&request("before"));
+        let receiver = std::mem::replace(first.inner_mut(), ReliableReceiver::new(Recorder::default()));
+
+        std::thread::sleep(Duration::from_millis(2));
+        let mut second = ReliableSender::new(receiver, "sender");
+        second.send(&request("after"));
+        assert_eq!(second.inner_mut().inner_mut().received, vec!["before".to_string(), "after".to_string()]);
+    }
+
+    #[test]
+    fn test_given_up_sequences_leave_no_gap() {
+        let link = Gate{ inner: ReliableReceiver::new(Recorder::default()), open: true };
+        let policy = RetryPolicy{ max_attempts: 1, ..RetryPolicy::default() };
+        let mut sender = ReliableSender::new(link, "sender").with_retry_policy(policy);
+        sender.send(&request("first"));
+
+        sender.inner_mut().open = false;
+        sender.send(&request("lost"));
+        assert_eq!(sender.poll_at(Instant::now() + Duration::from_secs(60)).len(), 1);
+
+        sender.inner_mut().open = true;
+        let last = sender.send(&request("last"));
+        let received = &sender.inner_mut().inner.received["sender"];
+        assert_eq!(received.contiguous, last);
+        assert!(received.above.is_empty());
+    }
+
+    #[test]
+    fn test_low_water_prunes_out_of_order_sequences() {
+        let mut received = Received::default();
+        assert!(received.insert(5).unwrap());
+        assert!(received.insert(9).unwrap());
+        received.advance(7);
+        assert_eq!(received.contiguous, 6);
+        assert_eq!(received.above.iter().cloned().collect::<Vec<u64>>(), vec![9]);
+        assert!(!received.insert(5).unwrap());
+
+        // An old low-water mark changes nothing.
+        received.advance(3);
+        assert_eq!(received.contiguous, 6);
+        assert!(received.insert(8).unwrap());
+        assert!(received.insert(7).unwrap());
+        assert_eq!(received.contiguous, 9);
+        assert!(received.above.is_empty());
+    }
+
+    #[test]
+    fn test_out_of_order_sequences_are_limited() {
+        let mut received = Received::default();
+        for sequence in 2..(2 + MAX_OUT_OF_ORDER as u64) {
+            assert!(received.insert(sequence).unwrap());
+        }
+        assert!(received.insert(MAX_OUT_OF_ORDER as u64 + 2).is_err());
+
+        // The one that fills the gap is still taken, and makes room.
+        assert!(received.insert(1).unwrap());
+        assert!(received.above.is_empty());
+        assert!(received.insert(MAX_OUT_OF_ORDER as u64 + 2).unwrap());
+    }
+
+    #[test]
+    fn test_least_recently_used_sender_is_forgotten() {
+        let delivered = |sender: &str| {
+            let mut transport = request(sender);
+            transport.delivery = Some(DeliveryHeader::new(sender.to_string(), 1, None));
+            transport
+        };
+
+        let mut receiver = ReliableReceiver::new(Recorder::default());
+        for i in 0..MAX_SENDERS {
+            receiver.transport_data(&delivered(&i.to_string()));
+        }
+        receiver.transport_data(&delivered("0"));
+
+        receiver.transport_data(&delivered("last"));
+        assert_eq!(receiver.received.len(), MAX_SENDERS);
+        assert!(receiver.received.contains_key("0"));
+        assert!(!receiver.received.contains_key("1"));
+    }
+}
@@ -0,0 +1,166 @@
+//! Stops signed messages from being recorded and sent again. Each Envelope carries a counter and a timestamp, and both are covered by the signature.
+//! Every signer in a process that uses the same key takes its counters from one shared_counter(...).
+//! A ReplayGuard keeps a sliding window of recent counters for every signer. It rejects counters it has already seen,
+//! counters that fell behind the window, and timestamps too far from the local clock.
+//! Only check messages whose signature was verified first, otherwise forged counters could push a signer's window forward.
+use crate::autogen_protobuf::transport::*;
+
+use failure::Fail;
+use hashbrown::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many counters behind the highest one are still accepted, for messages that arrive out of order.
+pub const REPLAY_WINDOW: u64 = 64;
+
+pub const DEFAULT_MAX_AGE_MS: u64 = 30_000;
+
+#[derive(Debug, Fail, Clone, PartialEq)]
+pub enum ReplayError {
+    #[fail(display = "Message from {} has no counter or timestamp.", key_id)]
+    Unsequenced { key_id: String },
+    #[fail(display = "Message {} from {} was already received.", counter, key_id)]
+    Duplicate { key_id: String, counter: u64 },
+    #[fail(display = "Message {} from {} is behind the replay window, which starts at {}.", counter, key_id, oldest)]
+    BehindWindow { key_id: String, counter: u64, oldest: u64 },
+    #[fail(display = "Message from {} is {} ms away from the local clock.", key_id, skew_ms)]
+    Stale { key_id: String, skew_ms: i128 },
+}
+
+lazy_static::lazy_static! {
+    // Key id to the last counter used with that key.
+    static ref COUNTERS: Mutex<HashMap<String, Arc<AtomicU64>>> = Mutex::new(HashMap::new());
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0)
+}
+
+/// The counter a new signer starts at. Based on the clock, so a signer that restarts continues above the counters it used before.
+pub fn initial_counter() -> u64 {
+    now_ms() * 1000
+}
+
+/// The counter for every signer using this key in this process. Signers that share a key must share its counter,
+/// or the one that signs least falls behind the receiver's window.
+pub fn shared_counter(key_id: &str) -> Arc<AtomicU64> {
+    let mut counters = COUNTERS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    counters.entry(key_id.to_string()).or_insert_with(|| Arc::new(AtomicU64::new(initial_counter()))).clone()
+}
+
+/// Take the next value from a shared counter.
+pub fn next_counter(counter: &AtomicU64) -> u64 {
+    counter.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+// Counters received from one signer.
+#[derive(Default)]
+struct Window {
+    highest: u64,
+    // Bit n is set if highest - n was received.
+    seen: u64,
+}
+
+impl Window {
+    fn insert(&mut self, key_id: &str, counter: u64) -> Result<(), ReplayError> {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+            return Ok(());
+        }
+
+        let behind = self.highest - counter;
+        if behind >= REPLAY_WINDOW {
+            return Err(ReplayError::BehindWindow{ key_id: key_id.to_string(), counter, oldest: self.highest - REPLAY_WINDOW + 1 });
+        }
+        if self.seen & (1 << behind) != 0 {
+            return Err(ReplayError::Duplicate{ key_id: key_id.to_string(), counter });
+        }
+        self.seen |= 1 << behind;
+        Ok(())
+    }
+}
+
+pub struct ReplayGuard {
+    windows: HashMap<String, Window>,
+    max_age: Duration,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        ReplayGuard{ windows: HashMap::new(), max_age: Duration::from_millis(DEFAULT_MAX_AGE_MS) }
+    }
+}
+
+impl ReplayGuard {
+    /// Messages whose timestamp is further than this from the local clock, in either direction, are rejected.
+    pub fn set_max_age(&mut self, max_age: Duration) {
+        self.max_age = max_age;
+    }
+
+    pub fn check(&mut self, envelope: &Envelope) -> Result<(), ReplayError> {
+        self.check_at(envelope, now_ms())
+    }
+
+    /// Like check(...), as if the local clock read `now_ms`.
+    pub fn check_at(&mut self, envelope: &Envelope, now_ms: u64) -> Result<(), ReplayError> {
+        let key_id = &envelope.keyId;
+        let (counter, timestamp) = match (envelope.counter, envelope.timestampMs) {
+            (Some(counter), Some(timestamp)) => (counter, timestamp),
+            _ => return Err(ReplayError::Unsequenced{ key_id: key_id.clone() }),
+        };
+
+        let skew_ms = i128::from(timestamp) - i128::from(now_ms);
+        if skew_ms.abs() as u128 > self.max_age.as_millis() {
+            return Err(ReplayError::Stale{ key_id: key_id.clone(), skew_ms });
+        }
+
+        self.windows.entry(key_id.clone()).or_insert_with(Window::default).insert(key_id, counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(counter: u64, timestamp: u64) -> Envelope {
+        Envelope{ keyId: "key".to_string(), counter: Some(counter), timestampMs: Some(timestamp), ..Default::default() }
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut guard = ReplayGuard::default();
+        let now = 1_000_000;
+
+        assert_eq!(guard.check_at(&envelope(10, now), now), Ok(()));
+        assert_eq!(guard.check_at(&envelope(12, now), now), Ok(()));
+        // Out of order, but inside the window.
+        assert_eq!(guard.check_at(&envelope(11, now), now), Ok(()));
+        assert_eq!(guard.check_at(&envelope(11, now), now), Err(ReplayError::Duplicate{ key_id: "key".to_string(), counter: 11 }));
+
+        assert_eq!(guard.check_at(&envelope(100, now), now), Ok(()));
+        assert_eq!(guard.check_at(&envelope(12, now), now), Err(ReplayError::BehindWindow{ key_id: "key".to_string(), counter: 12, oldest: 37 }));
+
+        assert_eq!(guard.check_at(&envelope(101, now - DEFAULT_MAX_AGE_MS - 1), now),
+            Err(ReplayError::Stale{ key_id: "key".to_string(), skew_ms: -i128::from(DEFAULT_MAX_AGE_MS) - 1 }));
+        assert_eq!(guard.check_at(&envelope(101, u64::max_value()), 0),
+            Err(ReplayError::Stale{ key_id: "key".to_string(), skew_ms: i128::from(u64::max_value()) }));
+
+        let mut unsequenced = envelope(102, now);
+        unsequenced.counter = None;
+        assert_eq!(guard.check_at(&unsequenced, now), Err(ReplayError::Unsequenced{ key_id: "key".to_string() }));
+    }
+
+    #[test]
+    fn test_shared_counter() {
+        let first = shared_counter("shared");
+        let second = shared_counter("shared");
+        let a = next_counter(&first);
+        let b = next_counter(&second);
+        assert_eq!(b, a + 1);
+        assert!(!Arc::ptr_eq(&first, &shared_counter("other")));
+    }
+}
@@ -0,0 +1,352 @@
+//! Learns which modules can be reached through which neighbour, so requests find models on other machines without manual wiring.
+//! Each node advertises the modules it serves plus every route it has learned. A route carries the path of node ids it travelled,
+//! and a node drops any route that already passes through itself, so advertisements can never loop.
+//! Routes expire unless they are advertised again. Call RoutingNode::advertise() more often than the ttl.
+//! Advertisements are exchanged over the connections a node already has to its neighbours. Each neighbour answers with its own,
+//! so a route is only ever learned from the neighbour at the other end of the connection, whatever nodeId it claims.
+use crate::autogen_protobuf::transport::*;
+use crate::transporter::Transporter;
+
+use failure::Error;
+use hashbrown::HashMap;
+use std::time::{Duration, Instant};
+
+/// Advertisements are addressed to this module.
+pub static ROUTING_MODULE: &str = "routing";
+
+pub const DEFAULT_ROUTE_TTL_MS: u64 = 30_000;
+
+/// Longer ttls are cut down to this, so a neighbour cannot keep a route alive forever.
+pub const MAX_ROUTE_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+struct Route {
+    next_hop: String,
+    path: Vec<String>,
+    descriptors: Vec<TypeDescriptor>,
+    expires: Instant,
+}
+
+pub struct RoutingTable {
+    node_id: String,
+    local: HashMap<ModuleId, Vec<TypeDescriptor>>,
+    routes: HashMap<ModuleId, Route>,
+}
+
+impl RoutingTable {
+    pub fn new(node_id: &str) -> Self {
+        RoutingTable{ node_id: node_id.to_string(), local: HashMap::new(), routes: HashMap::new() }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Advertise a module that this node handles itself.
+    pub fn serve(&mut self, module_id: ModuleId, descriptor: TypeDescriptor) {
+        let descriptors = self.local.entry(module_id).or_insert_with(Vec::new);
+        if !descriptors.contains(&descriptor) {
+            descriptors.push(descriptor);
+        }
+    }
+
+    /// Replace every route learned from the advertiser with the routes it advertised.
+    pub fn learn(&mut self, advertisement: &RouteAdvertisement) {
+        let now = Instant::now();
+        let expires = now + Duration::from_millis(std::cmp::min(advertisement.ttlMs, MAX_ROUTE_TTL_MS));
+        let next_hop = &advertisement.nodeId;
+
+        self.routes.retain(|_, route| route.expires > now && &route.next_hop != next_hop);
+        for entry in &advertisement.routes {
+            if entry.path.contains(&self.node_id) || self.local.contains_key(&entry.moduleId) {
+                continue;
+            }
+
+            let better = match self.routes.get(&entry.moduleId) {
+                Some(existing) => entry.path.len() < existing.path.len(),
+                None => true,
+            };
+            if better {
+                let route = Route{ next_hop: next_hop.clone(), path: entry.path.clone(), descriptors: entry.descriptors.clone(), expires };
+                self.routes.insert(entry.moduleId.clone(), route);
+            }
+        }
+    }
+
+    /// Forget every route through a neighbour, such as one whose connection was lost.
+    pub fn forget(&mut self, next_hop: &str) {
+        self.routes.retain(|_, route| route.next_hop != next_hop);
+    }
+
+    /// Drop routes that have not been advertised again in time.
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.routes.retain(|_, route| route.expires > now);
+    }
+
+    /// The neighbour to send requests for the module to.
+    pub fn next_hop(&self, module_id: &ModuleId) -> Option<&str> {
+        self.routes.get(module_id)
+            .filter(|route| route.expires > Instant::now())
+            .map(|route| route.next_hop.as_str())
+    }
+
+    /// The closest remote module that handles the descriptor.
+    pub fn module_for(&self, descriptor: &TypeDescriptor) -> Option<ModuleId> {
+        let now = Instant::now();
+        self.routes.iter()
+            .filter(|(_, route)| route.expires > now && route.descriptors.contains(descriptor))
+            .min_by(|(a_id, a), (b_id, b)| a.path.len().cmp(&b.path.len()).then_with(|| a_id.val.cmp(&b_id.val)))
+            .map(|(module_id, _)| module_id.clone())
+    }
+
+    /// How many nodes a request passes through to reach the module.
+    pub fn hops(&self, module_id: &ModuleId) -> Option<usize> {
+        self.routes.get(module_id).map(|route| route.path.len())
+    }
+
+    pub fn advertisement(&self, ttl: Duration) -> RouteAdvertisement {
+        let now = Instant::now();
+        let mut routes: Vec<RouteEntry> = self.local.iter()
+            .map(|(module_id, descriptors)| RouteEntry::new(module_id.clone(), descriptors.clone(), vec![self.node_id.clone()]))
+            .collect();
+
+        for (module_id, route) in self.routes.iter().filter(|(_, route)| route.expires > now) {
+            let mut path = vec![self.node_id.clone()];
+            path.extend(route.path.iter().cloned());
+            routes.push(RouteEntry::new(module_id.clone(), route.descriptors.clone(), path));
+        }
+
+        RouteAdvertisement::new(self.node_id.clone(), routes, ttl.as_millis() as u64)
+    }
+}
+
+/// Forwards requests to the neighbour that leads to their module.
+pub struct RoutingNode {
+    table: RoutingTable,
+    neighbours: HashMap<String, Box<Transporter>>,
+    ttl: Duration,
+}
+
+impl RoutingNode {
+    pub fn new(node_id: &str) -> Self {
+        RoutingNode{ table: RoutingTable::new(node_id), neighbours: HashMap::new(), ttl: Duration::from_millis(DEFAULT_ROUTE_TTL_MS) }
+    }
+
+    /// A node id that is unique to this process.
+    pub fn with_random_id() -> Self {
+        Self::new(&uuid::Uuid::new_v4().to_string())
+    }
+
+    /// How long neighbours keep our routes without hearing from us again.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    pub fn node_id(&self) -> &str {
+        self.table.node_id()
+    }
+
+    pub fn table(&self) -> &RoutingTable {
+        &self.table
+    }
+
+    /// Advertisements are only learned from neighbours, since the neighbour is where matching requests get sent.
+    pub fn add_neighbour<T: 'static + Transporter>(&mut self, node_id: &str, neighbour: T) {
+        if let Some(_existing) = self.neighbours.insert(node_id.to_string(), Box::new(neighbour)) {
+            panic!("There already exists neighbour {:?} in routing node!", node_id);
+        }
+    }
+
+    pub fn has_neighbour(&self, node_id: &str) -> bool {
+        self.neighbours.contains_key(node_id)
+    }
+
+    pub fn remove_neighbour(&mut self, node_id: &str) {
+        self.neighbours.remove(node_id);
+        self.table.forget(node_id);
+    }
+
+    pub fn serve(&mut self, module_id: ModuleId, descriptor: TypeDescriptor) {
+        self.table.serve(module_id, descriptor);
+    }
+
+    /// Learn an advertisement that arrived over the connection to `neighbour`.
+    /// Refused if it claims to come from any other node, since its routes would send requests down the wrong connection.
+    pub fn learn_from(&mut self, neighbour: &str, advertisement: &RouteAdvertisement) -> Result<(), Error> {
+        if !self.neighbours.contains_key(neighbour) {
+            return Err(failure::format_err!("Advertisement from {:?}, which is not a neighbour!", neighbour));
+        }
+        if advertisement.nodeId != neighbour {
+            return Err(failure::format_err!("Neighbour {:?} sent an advertisement for {:?}!", neighbour, advertisement.nodeId));
+        }
+        self.table.learn(advertisement);
+        Ok(())
+    }
+
+    /// What a neighbour that sent us its advertisement gets back. We cannot tell which connection it arrived on,
+    /// so it is not learned. The neighbour learns ours instead, and we learn its routes when we advertise to it.
+    pub fn answer(&mut self) -> RouteAdvertisement {
+        self.table.expire();
+        self.table.advertisement(self.ttl)
+    }
+
+    pub fn has_route(&self, module_id: &ModuleId) -> bool {
+        self.table.next_hop(module_id).is_some()
+    }
+
+    pub fn module_for(&self, descriptor: &TypeDescriptor) -> Option<ModuleId> {
+        self.table.module_for(descriptor)
+    }
+
+    /// Send our routes to every neighbour, and learn the routes each one answers with. Every neighbour is tried, even if some fail.
+    pub fn advertise(&mut self) -> Result<(), Error> {
+        let event = Event::new(self.answer().into());
+        let transport = RequestTransport::new(ModuleId::new(ROUTING_MODULE.to_string()), event, None, None);
+
+        let mut answers = Vec::new();
+        let mut errors = Vec::new();
+        for (node_id, neighbour) in self.neighbours.iter_mut() {
+            let ret = neighbour.transport_data(&transport);
+            if !ret.errors.is_empty() {
+                errors.push(format!("{}: {}", node_id, ret.errors.join(", ")));
+            }
+            for event in ret.vec {
+                if let mod_Event::OneOfdata::advertise(answer) = event.data {
+                    answers.push((node_id.clone(), answer));
+                }
+            }
+        }
+
+        for (node_id, answer) in answers {
+            if let Err(e) = self.learn_from(&node_id, &answer) {
+                errors.push(format!("{}: {}", node_id, e));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(failure::format_err!("Unable to advertise routes! {}", errors.join("\n")));
+        }
+        Ok(())
+    }
+}
+
+impl Transporter for RoutingNode {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        if let mod_Event::OneOfdata::advertise(_) = &transport.event.data {
+            return vec![Event::new(self.answer().into())].into();
+        }
+
+        let next_hop = match self.table.next_hop(&transport.moduleId) {
+            Some(next_hop) => next_hop.to_string(),
+            None => return format!("No route to {:?}!", transport.moduleId).into(),
+        };
+
+        match self.neighbours.get_mut(&next_hop) {
+            Some(neighbour) => neighbour.transport_data(transport),
+            None => format!("Route to {:?} uses missing neighbour {:?}!", transport.moduleId, next_hop).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(structure: &str) -> TypeDescriptor {
+        TypeDescriptor::new("alias".to_string(), structure.to_string())
+    }
+
+    fn module(val: &str) -> ModuleId {
+        ModuleId::new(val.to_string())
+    }
+
+    #[test]
+    fn test_routing_table() {
+        // c serves Model. b learns it from c, and a learns it from b.
+        let mut c = RoutingTable::new("c");
+        c.serve(module("model"), descriptor("Model"));
+        let mut b = RoutingTable::new("b");
+        b.learn(&c.advertisement(Duration::from_secs(60)));
+        let mut a = RoutingTable::new("a");
+        a.learn(&b.advertisement(Duration::from_secs(60)));
+
+        assert_eq!(a.module_for(&descriptor("Model")), Some(module("model")));
+        assert_eq!(a.next_hop(&module("model")), Some("b"));
+        assert_eq!(a.hops(&module("model")), Some(2));
+        assert_eq!(a.module_for(&descriptor("Other")), None);
+
+        // b hears its own route back through a, and must not route through a because of it.
+        b.learn(&a.advertisement(Duration::from_secs(60)));
+        assert_eq!(b.next_hop(&module("model")), Some("c"));
+
+        // Leaving a route out withdraws it.
+        a.learn(&RouteAdvertisement::new("b".to_string(), Vec::new(), 60_000));
+        assert_eq!(a.next_hop(&module("model")), None);
+
+        // Routes expire without being advertised again.
+        a.learn(&b.advertisement(Duration::from_millis(0)));
+        assert_eq!(a.next_hop(&module("model")), None);
+    }
+
+    // The connection to a neighbour. The neighbour answers whatever it is sent.
+    struct Connection(std::rc::Rc<std::cell::RefCell<RoutingNode>>);
+
+    impl Transporter for Connection {
+        fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+            self.0.borrow_mut().transport_data(transport)
+        }
+    }
+
+    fn node_serving(node_id: &str, model: &str) -> std::rc::Rc<std::cell::RefCell<RoutingNode>> {
+        let mut node = RoutingNode::new(node_id);
+        node.serve(module(model), descriptor(model));
+        std::rc::Rc::new(std::cell::RefCell::new(node))
+    }
+
+    #[test]
+    fn test_routes_are_learned_from_the_connection() {
+        let b = node_serving("b", "model");
+        let mut a = RoutingNode::new("a");
+        a.add_neighbour("b", Connection(b.clone()));
+        a.advertise().unwrap();
+        assert_eq!(a.module_for(&descriptor("model")), Some(module("model")));
+        assert_eq!(a.table().next_hop(&module("model")), Some("b"));
+
+        // b was only sent a's routes. It learns them when it advertises itself.
+        assert!(!b.borrow().has_route(&module("model")));
+    }
+
+    #[test]
+    fn test_advertisements_cannot_claim_another_node() {
+        // The connection a thinks goes to b actually reaches c.
+        let c = node_serving("c", "model");
+        let mut a = RoutingNode::new("a");
+        a.add_neighbour("b", Connection(c));
+        assert!(a.advertise().is_err());
+        assert_eq!(a.module_for(&descriptor("model")), None);
+
+        // Anyone can send us an advertisement claiming to be b. It is answered, not learned.
+        let mut forged = RoutingTable::new("b");
+        forged.serve(module("forged"), descriptor("forged"));
+        let transport = RequestTransport::new(module(ROUTING_MODULE), Event::new(forged.advertisement(Duration::from_secs(60)).into()), None, None);
+        let ret = a.transport_data(&transport);
+        assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+        assert_eq!(a.module_for(&descriptor("forged")), None);
+        match &ret.vec[0].data {
+            mod_Event::OneOfdata::advertise(answer) => assert_eq!(answer.nodeId, "a"),
+            other => panic!("Expected an advertisement, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_huge_ttl_is_clamped() {
+        let mut b = RoutingTable::new("b");
+        b.serve(module("model"), descriptor("model"));
+        let mut advertisement = b.advertisement(Duration::from_secs(60));
+        advertisement.ttlMs = u64::max_value();
+
+        let mut a = RoutingTable::new("a");
+        a.learn(&advertisement);
+        assert_eq!(a.next_hop(&module("model")), Some("b"));
+    }
+}
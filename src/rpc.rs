@@ -0,0 +1,43 @@
+//! Helpers used by the code that buildfunctions::rpc_generator() writes for each protobuf service.
+//! A call is a ProcessStructData whose descriptor structure is Service.method. The reply is a ProcessStructData of the return type, marked with rpcReply.
+use crate::autogen_protobuf::transport::*;
+
+use failure::Error;
+use quick_protobuf::{MessageRead, MessageWrite};
+
+/// Build the request for calling Service.method on the module.
+pub fn rpc_request<M: MessageWrite>(module_id: &ModuleId, library_alias: &str, method: &str, arg: &M) -> Result<RequestTransport, Error> {
+    let descriptor = TypeDescriptor::new(library_alias.to_string(), method.to_string());
+    let changes = StructDataChanges::new(quick_protobuf::serialize_into_vec(arg)?, Vec::new(), descriptor);
+    Ok(RequestTransport::new(module_id.clone(), Event::new(ProcessStructData::new(changes, None).into()), None, None))
+}
+
+pub fn rpc_decode_arg<M>(data: &ProcessStructData) -> Result<M, Error>
+    where M: for<'a> MessageRead<'a> {
+    Ok(quick_protobuf::deserialize_from_slice(&data.changes.serializedData)?)
+}
+
+/// The event a dispatcher returns with the method's result.
+pub fn rpc_reply<M: MessageWrite>(library_alias: &str, structure: &str, ret: &M) -> Result<Event, Error> {
+    let descriptor = TypeDescriptor::new(library_alias.to_string(), structure.to_string());
+    let changes = StructDataChanges::new(quick_protobuf::serialize_into_vec(ret)?, Vec::new(), descriptor);
+    Ok(Event::new(ProcessStructData::new(changes, Some(true)).into()))
+}
+
+/// Find the reply of the expected type in what the transporter returned.
+pub fn rpc_decode_reply<M>(ret: &ReturnTransport, library_alias: &str, structure: &str) -> Result<M, Error>
+    where M: for<'a> MessageRead<'a> {
+    if !ret.errors.is_empty() {
+        return Err(failure::format_err!("Rpc failed! {}", ret.errors.join("\n")));
+    }
+
+    for event in &ret.vec {
+        if let mod_Event::OneOfdata::process_struct(data) = &event.data {
+            let descriptor = &data.changes.descriptor;
+            if descriptor.libraryAlias == library_alias && descriptor.structure == structure {
+                return rpc_decode_arg(data);
+            }
+        }
+    }
+    Err(failure::format_err!("No {:?} reply was returned!", structure))
+}
@@ -0,0 +1,256 @@
+//! Compares two versions of a schema and reports the changes that would break existing data.
+//! Backward compatible: code built against the new schema can read data written with the old one.
+//! Forward compatible: code built against the old schema can read data written with the new one.
+use crate::protoparser::{EnumDef, FieldDef, Label, MessageDef, ProtoFile};
+
+use failure::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compatibility {
+    Full,
+    Backward,
+    Forward,
+    Incompatible,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchemaChange {
+    /// The full name of the message that changed.
+    pub message: String,
+    pub description: String,
+    pub breaks_backward: bool,
+    pub breaks_forward: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompatibilityReport {
+    pub changes: Vec<SchemaChange>,
+}
+
+impl CompatibilityReport {
+    pub fn is_backward_compatible(&self) -> bool {
+        !self.changes.iter().any(|change| change.breaks_backward)
+    }
+
+    pub fn is_forward_compatible(&self) -> bool {
+        !self.changes.iter().any(|change| change.breaks_forward)
+    }
+
+    pub fn compatibility(&self) -> Compatibility {
+        match (self.is_backward_compatible(), self.is_forward_compatible()) {
+            (true, true) => Compatibility::Full,
+            (true, false) => Compatibility::Backward,
+            (false, true) => Compatibility::Forward,
+            (false, false) => Compatibility::Incompatible,
+        }
+    }
+
+    /// Returns an error listing every change that breaks the required compatibility.
+    pub fn require(&self, required: Compatibility) -> Result<(), Error> {
+        let (need_backward, need_forward) = match required {
+            Compatibility::Full => (true, true),
+            Compatibility::Backward => (true, false),
+            Compatibility::Forward => (false, true),
+            Compatibility::Incompatible => (false, false),
+        };
+
+        let breaking: Vec<String> = self.changes.iter()
+            .filter(|change| (need_backward && change.breaks_backward) || (need_forward && change.breaks_forward))
+            .map(|change| format!("{}: {}", change.message, change.description))
+            .collect();
+
+        if !breaking.is_empty() {
+            return Err(failure::format_err!("Schema is not {:?} compatible!\n{}", required, breaking.join("\n")));
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, message: &str, description: String, breaks_backward: bool, breaks_forward: bool) {
+        self.changes.push(SchemaChange{ message: message.to_string(), description, breaks_backward, breaks_forward });
+    }
+}
+
+pub fn check_compatibility(old: &ProtoFile, new: &ProtoFile) -> CompatibilityReport {
+    let mut report = CompatibilityReport::default();
+    let new_messages = new.all_messages();
+
+    for (name, old_message) in old.all_messages() {
+        match new_messages.iter().find(|(new_name, _)| new_name == &name) {
+            Some((_, new_message)) => compare_messages(&name, old_message, new_message, &mut report),
+            // The message name is used in TypeDescriptor.structure, so neither side can find the other's data.
+            None => report.push(&name, "Message was removed or renamed.".to_string(), true, true),
+        }
+    }
+
+    let new_enums = new.all_enums();
+    for (name, old_enum) in old.all_enums() {
+        match new_enums.iter().find(|(new_name, _)| new_name == &name) {
+            Some((_, new_enum)) => compare_enums(&name, old_enum, new_enum, &mut report),
+            // Enums are only written as numbers. Fields that used it report their own type change.
+            None => report.push(&name, "Enum was removed or renamed.".to_string(), false, false),
+        }
+    }
+
+    report
+}
+
+fn compare_messages(name: &str, old: &MessageDef, new: &MessageDef, report: &mut CompatibilityReport) {
+    for old_field in &old.fields {
+        match new.field_by_number(old_field.number) {
+            Some(new_field) => compare_fields(name, old, new, old_field, new_field, report),
+            None => match new.field_by_name(&old_field.name) {
+                Some(new_field) => report.push(name, format!("Field {:?} changed number from {} to {}.", old_field.name, old_field.number, new_field.number), true, true),
+                // Old code still expects required fields to be there.
+                None => report.push(name, format!("Field {:?} ({}) was removed.", old_field.name, old_field.number), false, old_field.label == Label::Required),
+            },
+        }
+    }
+
+    for new_field in &new.fields {
+        let existed = old.field_by_number(new_field.number).is_some() || old.field_by_name(&new_field.name).is_some();
+        if !existed {
+            // Old data will not have new required fields.
+            report.push(name, format!("Field {:?} ({}) was added.", new_field.name, new_field.number), new_field.label == Label::Required, false);
+
+            // Old code does not know the new member, so it sees none of the oneof set.
+            if let Some(oneof) = &new_field.oneof {
+                if old.fields.iter().any(|field| field.oneof.as_ref() == Some(oneof)) {
+                    report.push(name, format!("Field {:?} ({}) was added to oneof {:?}.", new_field.name, new_field.number, oneof), false, true);
+                }
+            }
+        }
+    }
+}
+
+fn compare_fields(name: &str, old_message: &MessageDef, new_message: &MessageDef, old: &FieldDef, new: &FieldDef, report: &mut CompatibilityReport) {
+    if old.oneof != new.oneof {
+        let (breaks_backward, breaks_forward) = compare_oneofs(old_message, new_message, old, new);
+        report.push(name, format!("Field {:?} moved from oneof {:?} to {:?}.", new.name, old.oneof, new.oneof), breaks_backward, breaks_forward);
+    }
+
+    if old.name != new.name {
+        report.push(name, format!("Field {} was renamed from {:?} to {:?}.", old.number, old.name, new.name), false, false);
+    }
+
+    if old.typ != new.typ {
+        let compatible = wire_compatible(&old.typ, &new.typ);
+        report.push(name, format!("Field {:?} changed type from {} to {}.", new.name, old.typ, new.typ), !compatible, !compatible);
+    }
+
+    if old.label != new.label {
+        let (breaks_backward, breaks_forward) = match (old.label, new.label) {
+            (Label::Optional, Label::Required) => (true, false),
+            (Label::Required, Label::Optional) => (false, true),
+            _ => (true, true), // Between singular and repeated.
+        };
+        report.push(name, format!("Field {:?} changed from {:?} to {:?}.", new.name, old.label, new.label), breaks_backward, breaks_forward);
+    }
+}
+
+// A oneof keeps only the last member that was set.
+fn compare_oneofs(old_message: &MessageDef, new_message: &MessageDef, old: &FieldDef, new: &FieldDef) -> (bool, bool) {
+    let members = |message: &MessageDef, oneof: &Option<String>| -> Vec<u32> {
+        let mut members: Vec<u32> = message.fields.iter()
+            .filter(|field| oneof.is_some() && &field.oneof == oneof)
+            .map(|field| field.number)
+            .collect();
+        members.sort();
+        members
+    };
+    let old_members = members(old_message, &old.oneof);
+    let new_members = members(new_message, &new.oneof);
+    let existed = |number: &u32| old_message.field_by_number(*number).is_some();
+
+    match (&old.oneof, &new.oneof) {
+        // Old data may have set it alongside other fields that are now in the same oneof.
+        (None, Some(_)) => (new_members.iter().filter(|number| existed(number)).count() > 1, false),
+        // New data may set it alongside the members it used to exclude.
+        (Some(_), None) => (false, old_members.len() > 1),
+        // Only the name of the oneof changed.
+        _ if old_members == new_members => (false, false),
+        _ => (true, true),
+    }
+}
+
+fn compare_enums(name: &str, old: &EnumDef, new: &EnumDef, report: &mut CompatibilityReport) {
+    for (old_name, old_number) in &old.values {
+        let renumbered = new.values.iter().find(|(new_name, _)| new_name == old_name).filter(|(_, new_number)| new_number != old_number);
+        match (renumbered, new.values.iter().find(|(_, new_number)| new_number == old_number)) {
+            (Some((_, new_number)), _) => report.push(name, format!("Enum value {} changed number from {} to {}.", old_name, old_number, new_number), true, true),
+            (None, Some((new_name, _))) if new_name != old_name => report.push(name, format!("Enum value {} was renamed from {} to {}.", old_number, old_name, new_name), false, false),
+            (None, Some(_)) => {},
+            // New code no longer understands old data with this value.
+            (None, None) => report.push(name, format!("Enum value {} ({}) was removed.", old_name, old_number), true, false),
+        }
+    }
+
+    for (new_name, new_number) in &new.values {
+        let existed = old.values.iter().any(|(old_name, old_number)| old_name == new_name || old_number == new_number);
+        if !existed {
+            // Old code does not understand new data with this value.
+            report.push(name, format!("Enum value {} ({}) was added.", new_name, new_number), false, true);
+        }
+    }
+}
+
+/// Types that are encoded the same way on the wire, so old and new code can still decode each other's data.
+fn wire_compatible(old: &str, new: &str) -> bool {
+    let groups: [&[&str]; 5] = [
+        &["int32", "uint32", "int64", "uint64", "bool"],
+        &["sint32", "sint64"],
+        &["fixed32", "sfixed32"],
+        &["fixed64", "sfixed64"],
+        &["string", "bytes"],
+    ];
+    groups.iter().any(|group| group.contains(&old) && group.contains(&new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(old: &str, new: &str) -> CompatibilityReport {
+        check_compatibility(&ProtoFile::parse(old).unwrap(), &ProtoFile::parse(new).unwrap())
+    }
+
+    #[test]
+    fn test_compatibility_matrix() {
+        let base = "message M { required string name = 1; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }";
+        let cases = [
+            ("unchanged", base, Compatibility::Full),
+            ("optional field added", "message M { required string name = 1; optional int32 count = 2; optional bool flag = 5; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Full),
+            ("required field added", "message M { required string name = 1; optional int32 count = 2; required bool flag = 5; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Forward),
+            ("optional field removed", "message M { required string name = 1; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Full),
+            ("required field removed", "message M { optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Backward),
+            ("field renamed", "message M { required string title = 1; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Full),
+            ("field renumbered", "message M { required string name = 6; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Incompatible),
+            ("wire compatible type", "message M { required string name = 1; optional int64 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Full),
+            ("incompatible type", "message M { required string name = 1; optional fixed32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Incompatible),
+            ("optional made required", "message M { required string name = 1; required int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Forward),
+            ("message removed", "enum E { A = 0; B = 1; }", Compatibility::Incompatible),
+            ("enum value added", "message M { required string name = 1; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; C = 2; }", Compatibility::Backward),
+            ("enum value removed", "message M { required string name = 1; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; }", Compatibility::Forward),
+            ("enum value renamed", "message M { required string name = 1; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; BEE = 1; }", Compatibility::Full),
+            ("enum value renumbered", "message M { required string name = 1; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 2; }", Compatibility::Incompatible),
+            ("oneof renamed", "message M { required string name = 1; optional int32 count = 2; oneof pick { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Full),
+            ("field added to oneof", "message M { required string name = 1; optional int32 count = 2; oneof choice { string a = 3; int64 b = 4; bool c = 5; } } enum E { A = 0; B = 1; }", Compatibility::Backward),
+            ("field moved into new oneof", "message M { required string name = 1; oneof single { int32 count = 2; } oneof choice { string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Full),
+            ("field moved into existing oneof", "message M { required string name = 1; oneof choice { int32 count = 2; string a = 3; int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Forward),
+            ("field moved out of oneof", "message M { required string name = 1; optional int32 count = 2; optional string a = 3; oneof choice { int64 b = 4; } } enum E { A = 0; B = 1; }", Compatibility::Backward),
+        ];
+
+        for (description, new, expected) in cases.iter() {
+            let report = check(base, new);
+            assert_eq!(report.compatibility(), *expected, "{}: {:?}", description, report.changes);
+        }
+    }
+
+    #[test]
+    fn test_require_lists_breaking_changes() {
+        let report = check("message M { optional int32 count = 1; }", "message M { required int32 count = 1; }");
+        assert!(report.require(Compatibility::Forward).is_ok());
+        assert!(report.require(Compatibility::Incompatible).is_ok());
+        let error = report.require(Compatibility::Full).unwrap_err().to_string();
+        assert!(error.contains("Field \"count\" changed from Optional to Required."), "{}", error);
+    }
+}
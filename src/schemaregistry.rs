@@ -0,0 +1,211 @@
+//! The schema registry resolves TypeDescriptor.libraryAlias to a known schema at runtime.
+//! Schemas can be bundled into the binary, loaded from a directory, or fetched by hash from a SchemaStore backend.
+use crate::autogen_protobuf::transport::*;
+use crate::schemastore::{self, SchemaStore};
+use crate::protoparser::ProtoFile;
+use crate::dynamicmessage::{DynamicMessage, DynamicSchema};
+
+use failure::Error;
+use hashbrown::HashMap;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug)]
+pub struct Schema {
+    pub alias: String,
+    pub hash: String,
+    pub source: String,
+    pub file: ProtoFile,
+}
+
+#[derive(Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, Schema>,
+    aliases: HashMap<String, String>,
+    backend: Option<Box<SchemaStore>>,
+    // Descriptors that have been checked, and why they were refused. Cleared whenever the known schemas change.
+    verified: HashMap<TypeDescriptor, Result<(), String>>,
+}
+
+impl SchemaRegistry {
+    /// Schemas that are not known locally will be fetched from the backend by hash.
+    pub fn with_backend(backend: Box<SchemaStore>) -> Self {
+        SchemaRegistry{ backend: Some(backend), ..Default::default() }
+    }
+
+    /// Add a schema under an alias. Returns the schema's hash.
+    pub fn add(&mut self, alias: &str, source: &str) -> Result<String, Error> {
+        let hash = schemastore::ipfs_hash(source.as_bytes())?;
+        let file = ProtoFile::parse(source)?;
+        log::trace!("Registering schema {:?} as {:?}", alias, hash);
+        self.verified.clear();
+        self.aliases.insert(alias.to_string(), hash.clone());
+        self.schemas.insert(hash.clone(), Schema{ alias: alias.to_string(), hash: hash.clone(), source: source.to_string(), file });
+        Ok(hash)
+    }
+
+    /// Add a schema that was compiled into the binary with include_str!(...)
+    pub fn add_bundled(&mut self, alias: &str, source: &'static str) -> Result<String, Error> {
+        self.add(alias, source)
+    }
+
+    /// Add every alias from a generated autogen_protobuf::schema_urls::get_all_aliases(). The sources are fetched from the backend when needed.
+    pub fn add_aliases(&mut self, aliases: HashMap<String, &'static str>) {
+        for (alias, hash) in aliases {
+            self.aliases.insert(alias, hash.to_string());
+        }
+        self.verified.clear();
+    }
+
+    /// Load every .proto file in a directory. The alias is the file name without its extension.
+    pub fn load_dir(&mut self, dir: &PathBuf) -> Result<(), Error> {
+        log::debug!("Loading schemas from {:?}...", dir);
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext != "proto").unwrap_or(true) {
+                continue;
+            }
+
+            let alias = path.file_stem().and_then(|stem| stem.to_str())
+                .ok_or(failure::format_err!("Cannot determine alias for {:?}", path))?
+                .to_string();
+            let source = std::fs::read_to_string(&path)?;
+            self.add(&alias, &source)?;
+        }
+        log::debug!("...Finished loading schemas.");
+        Ok(())
+    }
+
+    /// Look up a schema by alias or by hash.
+    pub fn resolve(&mut self, alias_or_hash: &str) -> Result<&Schema, Error> {
+        let hash = self.aliases.get(alias_or_hash).cloned().unwrap_or_else(|| alias_or_hash.to_string());
+
+        if !self.schemas.contains_key(&hash) {
+            let backend = self.backend.as_ref()
+                .ok_or(failure::format_err!("Unknown schema {:?}!", alias_or_hash))?;
+            let source = String::from_utf8(backend.get(&hash)?)?;
+            if schemastore::ipfs_hash(source.as_bytes())? != hash {
+                return Err(failure::format_err!("Schema fetched for {:?} does not match its hash!", hash));
+            }
+            let file = ProtoFile::parse(&source)?;
+            self.schemas.insert(hash.clone(), Schema{ alias: alias_or_hash.to_string(), hash: hash.clone(), source, file });
+        }
+
+        Ok(&self.schemas[&hash])
+    }
+
+    pub fn is_known(&self, alias_or_hash: &str) -> bool {
+        self.aliases.contains_key(alias_or_hash) || self.schemas.contains_key(alias_or_hash)
+    }
+
+    /// Make sure the descriptor refers to a known schema that declares the structure. Rpc calls use Service.method as the structure.
+    /// Each descriptor is only checked once, so the backend is not asked for the same schema on every event.
+    pub fn verify_descriptor(&mut self, descriptor: &TypeDescriptor) -> Result<(), Error> {
+        if let Some(verified) = self.verified.get(descriptor) {
+            return verified.clone().map_err(|e| failure::format_err!("{}", e));
+        }
+
+        let verified = self.check_descriptor(descriptor).map_err(|e| e.to_string());
+        self.verified.insert(descriptor.clone(), verified.clone());
+        verified.map_err(|e| failure::format_err!("{}", e))
+    }
+
+    fn check_descriptor(&mut self, descriptor: &TypeDescriptor) -> Result<(), Error> {
+        let schema = self.resolve(&descriptor.libraryAlias)?;
+        if schema.file.find_message(&descriptor.structure).is_none() && schema.file.find_rpc(&descriptor.structure).is_none() {
+            return Err(failure::format_err!("Schema {:?} does not declare {:?}!", schema.alias, descriptor.structure));
+        }
+        Ok(())
+    }
+
+    /// A DynamicSchema for the alias, including any imported schemas that are also known.
+    pub fn dynamic_schema(&mut self, alias_or_hash: &str) -> Result<DynamicSchema, Error> {
+        let file = self.resolve(alias_or_hash)?.file.clone();
+        let imports = file.imports.clone();
+        let mut schema = DynamicSchema::new(file);
+
+        for import in imports {
+            let alias = std::path::Path::new(&import).file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(&import)
+                .to_string();
+            match self.resolve(&alias) {
+                Ok(imported) => schema.add_file(imported.file.clone()),
+                Err(e) => log::warn!("Cannot load import {:?} of {:?}: {}", import, alias_or_hash, e),
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Decode bytes that were serialized as the descriptor's structure, without generated code.
+    pub fn decode(&mut self, descriptor: &TypeDescriptor, bytes: &[u8]) -> Result<DynamicMessage, Error> {
+        self.dynamic_schema(&descriptor.libraryAlias)?.decode(&descriptor.structure, bytes)
+    }
+
+    pub fn encode(&mut self, descriptor: &TypeDescriptor, message: &DynamicMessage) -> Result<Vec<u8>, Error> {
+        self.dynamic_schema(&descriptor.libraryAlias)?.encode(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    static SOURCE: &str = "syntax = \"proto2\";\nmessage Thing { required string name = 1; }\nservice Things { rpc Get(Thing) returns (Thing); }\n";
+
+    // Serves one schema, and counts how often it is asked.
+    struct CountingStore {
+        fetches: Rc<Cell<usize>>,
+    }
+
+    impl SchemaStore for CountingStore {
+        fn add(&self, _schema: &[u8]) -> Result<String, Error> {
+            Err(failure::format_err!("Read only!"))
+        }
+
+        fn get(&self, hash: &str) -> Result<Vec<u8>, Error> {
+            self.fetches.set(self.fetches.get() + 1);
+            match schemastore::ipfs_hash(SOURCE.as_bytes())? == hash {
+                true => Ok(SOURCE.as_bytes().to_vec()),
+                false => Err(failure::format_err!("Unknown schema {:?}", hash)),
+            }
+        }
+    }
+
+    fn descriptor(alias: &str, structure: &str) -> TypeDescriptor {
+        TypeDescriptor::new(alias.to_string(), structure.to_string())
+    }
+
+    #[test]
+    fn test_verify_descriptor() {
+        let mut registry = SchemaRegistry::default();
+        let hash = registry.add("things", SOURCE).unwrap();
+        assert!(registry.verify_descriptor(&descriptor("things", "Thing")).is_ok());
+        assert!(registry.verify_descriptor(&descriptor(&hash, "Things.Get")).is_ok());
+        assert!(registry.verify_descriptor(&descriptor("things", "Missing")).is_err());
+        assert!(registry.verify_descriptor(&descriptor("unknown", "Thing")).is_err());
+
+        // A refused descriptor is checked again once a schema that declares it is added.
+        registry.add("unknown", SOURCE).unwrap();
+        assert!(registry.verify_descriptor(&descriptor("unknown", "Thing")).is_ok());
+    }
+
+    #[test]
+    fn test_backend_is_asked_once() {
+        let fetches = Rc::new(Cell::new(0));
+        let mut registry = SchemaRegistry::with_backend(Box::new(CountingStore{ fetches: fetches.clone() }));
+        let mut aliases = HashMap::new();
+        aliases.insert("things".to_string(), "QmUnknown");
+        registry.add_aliases(aliases);
+
+        let hash = schemastore::ipfs_hash(SOURCE.as_bytes()).unwrap();
+        for _ in 0..3 {
+            assert!(registry.verify_descriptor(&descriptor(&hash, "Thing")).is_ok());
+            assert!(registry.verify_descriptor(&descriptor("things", "Thing")).is_err());
+        }
+        assert_eq!(fetches.get(), 2);
+        assert!(registry.decode(&descriptor(&hash, "Thing"), &[0x0a, 0x01, b'a']).is_ok());
+        assert_eq!(fetches.get(), 2);
+    }
+}
@@ -0,0 +1,146 @@
+//! Schema stores keep the .proto files that SCHEMA_URL hashes refer to.
+//! Hashes are IPFS CIDv0 addresses, so a schema gets the same SCHEMA_URL no matter which store added it.
+use failure::Error;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+// IPFS splits files into chunks of this size. Anything larger would need a tree of nodes to hash.
+const IPFS_CHUNK_SIZE: usize = 262_144;
+
+pub trait SchemaStore {
+    /// Store the schema and return its content address.
+    fn add(&self, schema: &[u8]) -> Result<String, Error>;
+    /// Fetch a schema by its content address.
+    fn get(&self, hash: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Stores schemas as <hash>.proto files in a local directory. No IPFS daemon needed.
+pub struct LocalSchemaStore {
+    dir: PathBuf,
+}
+
+impl LocalSchemaStore {
+    pub fn new(dir: PathBuf) -> Self {
+        LocalSchemaStore{ dir }
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    fn schema_path(&self, hash: &str) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push(format!("{}.proto", hash));
+        path
+    }
+}
+
+/// Uses $PROTOCOLS_SCHEMA_STORE if it is set. Otherwise the user's cache directory, so every program and build script shares one store.
+/// Schemas are checked against their hash when they are read, so a shared store cannot hand out the wrong schema.
+impl Default for LocalSchemaStore {
+    fn default() -> Self {
+        let dir = match std::env::var_os("PROTOCOLS_SCHEMA_STORE") {
+            Some(dir) => PathBuf::from(dir),
+            None => default_dir(),
+        };
+        LocalSchemaStore::new(dir)
+    }
+}
+
+// $XDG_CACHE_HOME, ~/.cache or %LOCALAPPDATA%. Build scripts without any of those use OUT_DIR, and everything else the temp directory.
+fn default_dir() -> PathBuf {
+    let env_dir = |name: &str| std::env::var_os(name).map(PathBuf::from).filter(|dir| dir.is_absolute());
+    let mut dir = env_dir("XDG_CACHE_HOME")
+        .or_else(|| env_dir("HOME").map(|home| home.join(".cache")))
+        .or_else(|| env_dir("LOCALAPPDATA"))
+        .or_else(|| env_dir("OUT_DIR"))
+        .unwrap_or_else(std::env::temp_dir);
+    dir.push("protocols");
+    dir.push("schema_store");
+    dir
+}
+
+impl SchemaStore for LocalSchemaStore {
+    fn add(&self, schema: &[u8]) -> Result<String, Error> {
+        let hash = ipfs_hash(schema)?;
+        let path = self.schema_path(&hash);
+        log::debug!("Storing schema {:?} in {:?}", hash, path);
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, schema)?;
+        Ok(hash)
+    }
+
+    fn get(&self, hash: &str) -> Result<Vec<u8>, Error> {
+        let path = self.schema_path(hash);
+        if !path.exists() {
+            return Err(failure::format_err!("Schema {:?} is not in the local schema store {:?}!", hash, self.dir));
+        }
+
+        let schema = std::fs::read(&path)?;
+        if ipfs_hash(&schema)? != hash {
+            return Err(failure::format_err!("Schema {:?} in the local schema store has been modified!", path));
+        }
+        Ok(schema)
+    }
+}
+
+/// Compute the same CIDv0 that "ipfs add" would give this data.
+pub fn ipfs_hash(data: &[u8]) -> Result<String, Error> {
+    if data.len() > IPFS_CHUNK_SIZE {
+        return Err(failure::format_err!("Schemas larger than {} bytes cannot be hashed locally!", IPFS_CHUNK_SIZE));
+    }
+
+    // UnixFS Data message: Type = File, Data = data, filesize = len
+    let mut unixfs = Vec::new();
+    write_varint_field(&mut unixfs, 1, 2);
+    if !data.is_empty() {
+        write_bytes_field(&mut unixfs, 2, data);
+    }
+    write_varint_field(&mut unixfs, 3, data.len() as u64);
+
+    // dag-pb PBNode message with no links
+    let mut node = Vec::new();
+    write_bytes_field(&mut node, 1, &unixfs);
+
+    // sha2-256 multihash, base58 encoded
+    let mut multihash = vec![0x12, 0x20];
+    multihash.extend_from_slice(&Sha256::digest(&node));
+    Ok(bs58::encode(multihash).into_string())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(out, field << 3);
+    write_varint(out, value);
+}
+
+fn write_bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_varint(out, (field << 3) | 2);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ipfs_hash_matches_ipfs_add() {
+        assert_eq!(ipfs_hash(b"").unwrap(), "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH");
+        assert_eq!(ipfs_hash(b"hello world\n").unwrap(), "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o");
+    }
+
+    #[test]
+    fn test_default_dir_does_not_depend_on_working_directory() {
+        let dir = default_dir();
+        assert!(dir.is_absolute(), "{:?}", dir);
+        assert!(dir.ends_with("protocols/schema_store"), "{:?}", dir);
+    }
+}
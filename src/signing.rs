@@ -0,0 +1,431 @@
+//! Detects tampered messages. Requests and returns are signed with Ed25519 and carry the signer's key id in an Envelope.
+//! A Keyring lists the keys that are trusted and which modules each key may send requests to.
+//! SigningTransporter wraps the client side and VerifyingTransporter wraps the server side. Each rejects anything unsigned or tampered with,
+//! and anything replayed. See replay.rs. Returns name the request they answer, so one cannot be swapped for another.
+//! The envelope carries the exact bytes that were signed, so verification never depends on serializing the message the same way twice.
+use crate::autogen_protobuf::transport::*;
+use crate::replay::{self, ReplayGuard};
+use crate::transporter::Transporter;
+
+use ed25519_dalek::{Keypair, PublicKey, Signature};
+use failure::Error;
+use hashbrown::HashMap;
+use quick_protobuf::{MessageRead, MessageWrite};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+
+pub fn generate_keypair() -> Result<Keypair, Error> {
+    let mut rng = rand::rngs::OsRng::new()?;
+    Ok(Keypair::generate(&mut rng))
+}
+
+/// The base58 public key. Identifies the signer in an Envelope.
+pub fn key_id(public: &PublicKey) -> String {
+    bs58::encode(public.as_bytes()).into_string()
+}
+
+/// Messages that can carry an Envelope.
+pub trait Signable: MessageWrite + for<'a> MessageRead<'a> + Clone + PartialEq {
+    fn envelope(&self) -> Option<&Envelope>;
+    fn envelope_mut(&mut self) -> &mut Option<Envelope>;
+}
+
+impl Signable for RequestTransport {
+    fn envelope(&self) -> Option<&Envelope> {
+        self.envelope.as_ref()
+    }
+
+    fn envelope_mut(&mut self) -> &mut Option<Envelope> {
+        &mut self.envelope
+    }
+}
+
+impl Signable for ReturnTransport {
+    fn envelope(&self) -> Option<&Envelope> {
+        self.envelope.as_ref()
+    }
+
+    fn envelope_mut(&mut self) -> &mut Option<Envelope> {
+        &mut self.envelope
+    }
+}
+
+/// Sign the message with the keypair, replacing any existing envelope. Receivers that use a ReplayGuard will reject it. See sign_sequenced(...).
+pub fn sign<M: Signable>(message: &mut M, keypair: &Keypair) -> Result<(), Error> {
+    sign_envelope(message, keypair, Envelope{ keyId: key_id(&keypair.public), ..Default::default() })
+}
+
+/// Sign with a counter and the current time, so that receivers can reject replays. The counter must increase with every message.
+pub fn sign_sequenced<M: Signable>(message: &mut M, keypair: &Keypair, counter: u64) -> Result<(), Error> {
+    let envelope = Envelope{ keyId: key_id(&keypair.public), counter: Some(counter), timestampMs: Some(replay::now_ms()), ..Default::default() };
+    sign_envelope(message, keypair, envelope)
+}
+
+/// Sign a return as the answer to the request, so that the sender can tell it apart from returns to its other requests.
+pub fn sign_return(ret: &mut ReturnTransport, keypair: &Keypair, counter: u64, request: &RequestTransport) -> Result<(), Error> {
+    let envelope = Envelope{ keyId: key_id(&keypair.public), counter: Some(counter), timestampMs: Some(replay::now_ms()),
+        requestDigest: request_digest(request), ..Default::default() };
+    sign_envelope(ret, keypair, envelope)
+}
+
+/// The sha2-256 of the bytes the request was signed over. None if it is not signed.
+pub fn request_digest(request: &RequestTransport) -> Option<Vec<u8>> {
+    request.envelope.as_ref()
+        .and_then(|envelope| envelope.payload.as_ref())
+        .map(|payload| Sha256::digest(payload).to_vec())
+}
+
+fn sign_envelope<M: Signable>(message: &mut M, keypair: &Keypair, envelope: Envelope) -> Result<(), Error> {
+    *message.envelope_mut() = Some(envelope);
+    let payload = quick_protobuf::serialize_into_vec(&*message)?;
+    let signature = keypair.sign(&payload);
+    if let Some(envelope) = message.envelope_mut() {
+        envelope.signature = signature.to_bytes().to_vec();
+        envelope.payload = Some(payload);
+    }
+    Ok(())
+}
+
+// The message as it would have been signed, with the signature and payload left empty.
+fn unsigned<M: Signable>(message: &M) -> M {
+    let mut unsigned = message.clone();
+    if let Some(envelope) = unsigned.envelope_mut() {
+        envelope.signature = Vec::new();
+        envelope.payload = None;
+    }
+    unsigned
+}
+
+// Only call after the signature is verified.
+fn check_replay<M: Signable>(replay: &mut Option<ReplayGuard>, message: &M) -> Result<(), Error> {
+    if let (Some(guard), Some(envelope)) = (replay, message.envelope()) {
+        guard.check(envelope)?;
+    }
+    Ok(())
+}
+
+// Only call after the signature is verified.
+fn check_answers(ret: &ReturnTransport, request: &RequestTransport) -> Result<(), Error> {
+    let answered = ret.envelope.as_ref().and_then(|envelope| envelope.requestDigest.clone());
+    if answered.is_none() || answered != request_digest(request) {
+        return Err(failure::format_err!("Return does not answer this request!"));
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Keyring {
+    // Key id to the key and the modules it may send requests to.
+    keys: HashMap<String, (PublicKey, Vec<ModuleId>)>,
+}
+
+impl Keyring {
+    /// Trust the key. Returns its key id.
+    pub fn add(&mut self, public: PublicKey, allowed: Vec<ModuleId>) -> String {
+        let key_id = key_id(&public);
+        self.keys.insert(key_id.clone(), (public, allowed));
+        key_id
+    }
+
+    pub fn allow(&mut self, key_id: &str, module_id: ModuleId) -> Result<(), Error> {
+        let (_, allowed) = self.keys.get_mut(key_id)
+            .ok_or(failure::format_err!("Key {:?} is not in the keyring!", key_id))?;
+        if !allowed.contains(&module_id) {
+            allowed.push(module_id);
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key_id: &str) {
+        self.keys.remove(key_id);
+    }
+
+    /// Check that a trusted key signed exactly this message. Returns the key id.
+    /// The signature is checked over the payload bytes as they were received, and the payload must decode to the rest of the message.
+    pub fn verify<'a, M: Signable>(&self, message: &'a M) -> Result<&'a str, Error> {
+        let envelope = message.envelope()
+            .ok_or(failure::format_err!("Message is not signed!"))?;
+        let (public, _) = self.keys.get(&envelope.keyId)
+            .ok_or(failure::format_err!("Message is signed by unknown key {:?}!", envelope.keyId))?;
+        let payload = envelope.payload.as_ref()
+            .ok_or(failure::format_err!("Message signed by {:?} has no payload!", envelope.keyId))?;
+
+        let signature = Signature::from_bytes(&envelope.signature)
+            .map_err(|e| failure::format_err!("Invalid signature by {:?}! {:?}", envelope.keyId, e))?;
+        public.verify(payload, &signature)
+            .map_err(|_| failure::format_err!("Signature by {:?} does not match! The message was tampered with.", envelope.keyId))?;
+
+        let signed: M = quick_protobuf::deserialize_from_slice(payload)
+            .map_err(|e| failure::format_err!("Payload signed by {:?} does not decode! {:?}", envelope.keyId, e))?;
+        if signed != unsigned(message) {
+            return Err(failure::format_err!("Message does not match the payload signed by {:?}! The message was tampered with.", envelope.keyId));
+        }
+        Ok(&envelope.keyId)
+    }
+
+    /// Like verify(...), and the signer must be allowed to reach the destination module.
+    pub fn verify_request<'a>(&self, request: &'a RequestTransport) -> Result<&'a str, Error> {
+        let key_id = self.verify(request)?;
+        let allowed = self.keys.get(key_id).map(|(_, allowed)| allowed.contains(&request.moduleId)).unwrap_or(false);
+        if !allowed {
+            return Err(failure::format_err!("Key {:?} may not send requests to {:?}!", key_id, request.moduleId));
+        }
+        Ok(key_id)
+    }
+}
+
+/// Signs every request, and only accepts returns signed by a key in the keyring.
+pub struct SigningTransporter<T: Transporter> {
+    inner: T,
+    keypair: Keypair,
+    keyring: Keyring,
+    counter: Arc<AtomicU64>, // Shared with every signer using the same keypair.
+    replay: Option<ReplayGuard>,
+}
+
+impl<T: Transporter> SigningTransporter<T> {
+    pub fn new(inner: T, keypair: Keypair, keyring: Keyring) -> Self {
+        let counter = replay::shared_counter(&key_id(&keypair.public));
+        SigningTransporter{ inner, keypair, keyring, counter, replay: Some(ReplayGuard::default()) }
+    }
+
+    /// Accept returns without checking for replays, such as from servers that sign without a counter.
+    pub fn without_replay_protection(mut self) -> Self {
+        self.replay = None;
+        self
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Transporter> Transporter for SigningTransporter<T> {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        let mut transport = transport.clone();
+        if let Err(e) = sign_sequenced(&mut transport, &self.keypair, replay::next_counter(&self.counter)) {
+            return format!("Unable to sign request! {:?}", e).into();
+        }
+
+        let ret = self.inner.transport_data(&transport);
+        let verified = self.keyring.verify(&ret).map(|_| ())
+            .and_then(|_| check_answers(&ret, &transport))
+            .and_then(|_| check_replay(&mut self.replay, &ret));
+        match verified {
+            Ok(_) => ret,
+            Err(e) => {
+                log::warn!("Rejected return! {:?}", e);
+                // Keep whatever errors it carried. An unsigned return is usually a transport error.
+                let mut rejected: ReturnTransport = format!("Rejected return! {:?}", e).into();
+                rejected.errors.extend(ret.errors);
+                rejected
+            },
+        }
+    }
+}
+
+/// Only passes on requests signed by a key in the keyring, to modules that key is allowed to reach. Signs every return.
+pub struct VerifyingTransporter<T: Transporter> {
+    inner: T,
+    keypair: Keypair,
+    keyring: Keyring,
+    counter: Arc<AtomicU64>, // Shared with every signer using the same keypair.
+    replay: Option<ReplayGuard>,
+}
+
+impl<T: Transporter> VerifyingTransporter<T> {
+    pub fn new(inner: T, keypair: Keypair, keyring: Keyring) -> Self {
+        let counter = replay::shared_counter(&key_id(&keypair.public));
+        VerifyingTransporter{ inner, keypair, keyring, counter, replay: Some(ReplayGuard::default()) }
+    }
+
+    /// Accept requests without checking for replays, such as from clients that sign without a counter.
+    pub fn without_replay_protection(mut self) -> Self {
+        self.replay = None;
+        self
+    }
+
+    pub fn keyring_mut(&mut self) -> &mut Keyring {
+        &mut self.keyring
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Transporter> Transporter for VerifyingTransporter<T> {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        let verified = self.keyring.verify_request(transport).map(|_| ())
+            .and_then(|_| check_replay(&mut self.replay, transport));
+        let mut ret = match verified {
+            Ok(()) => self.inner.transport_data(transport),
+            Err(e) => {
+                log::warn!("Rejected request! {:?}", e);
+                format!("Rejected request! {:?}", e).into()
+            },
+        };
+
+        // Rejections are signed too, so the sender knows they are genuine.
+        if let Err(e) = sign_return(&mut ret, &self.keypair, replay::next_counter(&self.counter), transport) {
+            return format!("Unable to sign return! {:?}", e).into();
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::REPLAY_WINDOW;
+    use crate::testutil::{request, Echo};
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let client = generate_keypair().unwrap();
+        let mut keyring = Keyring::default();
+        keyring.add(client.public, vec![ModuleId::new("echo".to_string())]);
+
+        let mut signed = request("echo", "id");
+        sign(&mut signed, &client).unwrap();
+        assert!(keyring.verify_request(&signed).is_ok());
+
+        let mut tampered = signed.clone();
+        tampered.event = request("echo", "other").event;
+        assert!(keyring.verify_request(&tampered).is_err());
+
+        let mut redirected = signed.clone();
+        redirected.moduleId = ModuleId::new("admin".to_string());
+        assert!(keyring.verify_request(&redirected).is_err());
+
+        assert!(keyring.verify_request(&request("echo", "id")).is_err());
+        assert!(Keyring::default().verify_request(&signed).is_err());
+    }
+
+    #[test]
+    fn test_signed_transporters() {
+        let client = generate_keypair().unwrap();
+        let server = generate_keypair().unwrap();
+
+        let mut server_keyring = Keyring::default();
+        server_keyring.add(client.public, vec![ModuleId::new("echo".to_string())]);
+        let mut client_keyring = Keyring::default();
+        client_keyring.add(server.public, Vec::new());
+
+        let mut transporter = SigningTransporter::new(VerifyingTransporter::new(Echo, server, server_keyring), client, client_keyring);
+        let sent = request("echo", "id");
+        let ret = transporter.transport_data(&sent);
+        assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+        assert_eq!(ret.vec, vec![sent.event]);
+
+        let ret = transporter.transport_data(&request("admin", "id"));
+        assert!(ret.vec.is_empty());
+        assert_eq!(ret.errors.len(), 1);
+    }
+
+    // Answers every request with the first return it got.
+    struct Swap<T: Transporter> {
+        inner: T,
+        first: Option<ReturnTransport>,
+    }
+
+    impl<T: Transporter> Transporter for Swap<T> {
+        fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+            let ret = self.inner.transport_data(transport);
+            self.first.get_or_insert(ret).clone()
+        }
+    }
+
+    #[test]
+    fn test_returns_are_bound_to_requests() {
+        let client = generate_keypair().unwrap();
+        let server = generate_keypair().unwrap();
+
+        let mut server_keyring = Keyring::default();
+        server_keyring.add(client.public, vec![ModuleId::new("echo".to_string())]);
+        let mut client_keyring = Keyring::default();
+        client_keyring.add(server.public, Vec::new());
+
+        // Without replay protection, so that only the request binding can catch the swap.
+        let swap = Swap{ inner: VerifyingTransporter::new(Echo, server, server_keyring), first: None };
+        let mut transporter = SigningTransporter::new(swap, client, client_keyring).without_replay_protection();
+        assert!(transporter.transport_data(&request("echo", "first")).errors.is_empty());
+
+        let ret = transporter.transport_data(&request("echo", "second"));
+        assert!(ret.vec.is_empty());
+        assert!(ret.errors[0].contains("does not answer"), "{:?}", ret.errors);
+    }
+
+    #[test]
+    fn test_payload_must_match_message() {
+        let client = generate_keypair().unwrap();
+        let mut keyring = Keyring::default();
+        keyring.add(client.public, vec![ModuleId::new("echo".to_string())]);
+
+        let mut signed = request("echo", "id");
+        sign(&mut signed, &client).unwrap();
+
+        // A genuine signature over another message does not vouch for this one.
+        let mut other = request("echo", "other");
+        sign(&mut other, &client).unwrap();
+        let mut spliced = signed.clone();
+        spliced.envelope = other.envelope;
+        assert!(keyring.verify_request(&spliced).is_err());
+
+        let mut stripped = signed.clone();
+        stripped.envelope.as_mut().unwrap().payload = None;
+        assert!(keyring.verify_request(&stripped).is_err());
+    }
+
+    // Lets several clients reach one server.
+    struct Shared<T: Transporter>(std::rc::Rc<std::cell::RefCell<T>>);
+
+    impl<T: Transporter> Transporter for Shared<T> {
+        fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+            self.0.borrow_mut().transport_data(transport)
+        }
+    }
+
+    #[test]
+    fn test_transporters_share_a_keypairs_counter() {
+        let client = generate_keypair().unwrap();
+        let server = generate_keypair().unwrap();
+        let copy = |keypair: &Keypair| Keypair::from_bytes(&keypair.to_bytes()).unwrap();
+
+        let mut server_keyring = Keyring::default();
+        server_keyring.add(client.public, vec![ModuleId::new("echo".to_string())]);
+        let server = std::rc::Rc::new(std::cell::RefCell::new(VerifyingTransporter::new(Echo, server, server_keyring)));
+
+        let mut quiet = SigningTransporter::new(Shared(server.clone()), copy(&client), Keyring::default()).without_replay_protection();
+        let mut busy = SigningTransporter::new(Shared(server), client, Keyring::default()).without_replay_protection();
+        let request_errors = |ret: ReturnTransport| ret.errors.into_iter().filter(|error| error.contains("Rejected request")).count();
+
+        for _ in 0..REPLAY_WINDOW + 1 {
+            assert_eq!(request_errors(busy.transport_data(&request("echo", "id"))), 0);
+        }
+        // The quiet transporter continues from the busy one's counter, instead of from far behind the server's window.
+        assert_eq!(request_errors(quiet.transport_data(&request("echo", "id"))), 0);
+    }
+
+    #[test]
+    fn test_replay_is_rejected() {
+        let client = generate_keypair().unwrap();
+        let mut keyring = Keyring::default();
+        keyring.add(client.public, vec![ModuleId::new("echo".to_string())]);
+        let mut server = VerifyingTransporter::new(Echo, generate_keypair().unwrap(), keyring);
+
+        let mut signed = request("echo", "id");
+        sign_sequenced(&mut signed, &client, 1).unwrap();
+        assert!(server.transport_data(&signed).errors.is_empty());
+
+        let ret = server.transport_data(&signed);
+        assert_eq!(ret.errors.len(), 1);
+        assert!(ret.errors[0].contains("Duplicate"), "{:?}", ret.errors);
+
+        // Without a counter, the request cannot be told apart from a replay.
+        let mut unsequenced = request("echo", "id");
+        sign(&mut unsequenced, &client).unwrap();
+        assert_eq!(server.transport_data(&unsequenced).errors.len(), 1);
+    }
+}
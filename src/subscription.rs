@@ -0,0 +1,65 @@
+//! Subscriptions let modules and outside code find out when models change without polling.
+use crate::autogen_protobuf::transport::*;
+
+/// Called with every matching change.
+pub type ChangeListener = Box<FnMut(&ModelDataChanges)>;
+
+#[derive(Default)]
+pub struct Subscriptions {
+    // Modules are notified through a transport.
+    modules: Vec<SubscribeData>,
+    // Outside code is notified directly.
+    listeners: Vec<(SubscribeData, ChangeListener)>,
+}
+
+impl Subscriptions {
+    pub fn subscribe(&mut self, filter: SubscribeData) {
+        self.modules.push(filter);
+    }
+
+    /// The listener is identified by the filter's subscriber so that it can be unsubscribed later.
+    pub fn listen(&mut self, filter: SubscribeData, listener: ChangeListener) {
+        self.listeners.push((filter, listener));
+    }
+
+    /// Remove every subscription and listener for this subscriber.
+    pub fn unsubscribe(&mut self, data: &UnsubscribeData) {
+        self.modules.retain(|filter| filter.subscriber != data.subscriber);
+        self.listeners.retain(|(filter, _listener)| filter.subscriber != data.subscriber);
+    }
+
+    /// Call the listeners that match. Returns the modules that need to be notified.
+    pub fn notify(&mut self, changes: &ModelDataChanges) -> Vec<ModuleId> {
+        for (filter, listener) in self.listeners.iter_mut() {
+            if matches(filter, changes) {
+                listener(changes);
+            }
+        }
+
+        let mut subscribers: Vec<ModuleId> = self.modules.iter()
+            .filter(|filter| matches(filter, changes))
+            .map(|filter| filter.subscriber.clone())
+            .collect();
+
+        // A module only needs to hear about a change once, no matter how many of its subscriptions match.
+        subscribers.sort_by(|a, b| a.val.cmp(&b.val));
+        subscribers.dedup();
+        subscribers
+    }
+}
+
+pub fn matches(filter: &SubscribeData, changes: &ModelDataChanges) -> bool {
+    if let Some(id) = &filter.id {
+        if id != &changes.id { return false; }
+    }
+
+    if let Some(descriptor) = &filter.descriptor {
+        if descriptor != &changes.changes.descriptor { return false; }
+    }
+
+    if let Some(property) = &filter.property {
+        if !changes.changes.dirtyProperties.contains(property) { return false; }
+    }
+
+    true
+}
@@ -0,0 +1,195 @@
+//! Sends RequestTransports to another system over TCP.
+//! The server exposes any local Transporter. The client is a Transporter, so it can be added to a TransportNode with add_node(...).
+//! Either side can be given a NoiseConfig, and then every frame on its connections is encrypted. See noise.rs.
+use crate::autogen_protobuf::transport::*;
+use crate::codec::{Codec, ProtobufCodec};
+use crate::framing::{self, FramedConnection, PoisonOnError, StreamConnection};
+use crate::noise::{NoiseConfig, NoiseConnection};
+use crate::transporter::Transporter;
+
+use failure::Error;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// A slow or silent peer cannot hold up the server's handshakes for longer than this.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum TcpConnection {
+    Plain(StreamConnection<TcpStream>),
+    Encrypted(NoiseConnection<StreamConnection<TcpStream>>),
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream, encryption: Option<&NoiseConfig>, initiator: bool) -> Result<Self, Error> {
+        stream.set_nodelay(true)?;
+        let config = match encryption {
+            Some(config) => config,
+            None => return Ok(TcpConnection::Plain(StreamConnection::new(stream))),
+        };
+
+        let timeout = stream.read_timeout()?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let connection = StreamConnection::new(stream.try_clone()?);
+        let connection = if initiator {
+            NoiseConnection::initiate(connection, config)?
+        } else {
+            NoiseConnection::respond(connection, config)?
+        };
+        stream.set_read_timeout(timeout)?;
+        Ok(TcpConnection::Encrypted(connection))
+    }
+}
+
+impl FramedConnection for TcpConnection {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), Error> {
+        match self {
+            TcpConnection::Plain(connection) => connection.send_frame(payload),
+            TcpConnection::Encrypted(connection) => connection.send_frame(payload),
+        }
+    }
+
+    fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self {
+            TcpConnection::Plain(connection) => connection.receive_frame(),
+            TcpConnection::Encrypted(connection) => connection.receive_frame(),
+        }
+    }
+}
+
+pub struct TcpTransporter {
+    stream: TcpStream,
+    connection: PoisonOnError<TcpConnection>,
+    codec: Box<Codec>,
+}
+
+impl TcpTransporter {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        Self::connect_with(addr, None)
+    }
+
+    /// Run a Noise handshake with the server, then encrypt everything sent over the connection.
+    /// Fails if the server's static key is not trusted by the config.
+    pub fn connect_encrypted<A: ToSocketAddrs>(addr: A, config: &NoiseConfig) -> Result<Self, Error> {
+        Self::connect_with(addr, Some(config))
+    }
+
+    /// Like connect(...), but gives up if the server does not answer in time.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<Self, Error> {
+        Self::from_stream(TcpStream::connect_timeout(addr, timeout)?, None)
+    }
+
+    /// Like connect_encrypted(...), but gives up if the server does not answer in time.
+    pub fn connect_encrypted_timeout(addr: &SocketAddr, timeout: Duration, config: &NoiseConfig) -> Result<Self, Error> {
+        Self::from_stream(TcpStream::connect_timeout(addr, timeout)?, Some(config))
+    }
+
+    fn connect_with<A: ToSocketAddrs>(addr: A, encryption: Option<&NoiseConfig>) -> Result<Self, Error> {
+        Self::from_stream(TcpStream::connect(addr)?, encryption)
+    }
+
+    fn from_stream(stream: TcpStream, encryption: Option<&NoiseConfig>) -> Result<Self, Error> {
+        log::debug!("Connected to {:?}", stream.peer_addr()?);
+        let connection = TcpConnection::new(stream.try_clone()?, encryption, true)?;
+        Ok(TcpTransporter{ stream, connection: PoisonOnError::new(connection), codec: Box::new(ProtobufCodec) })
+    }
+
+    pub fn with_codec(mut self, codec: Box<Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// How long to wait for a request to return. None waits forever.
+    /// A request that times out leaves the connection unusable. Connect again to keep sending.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)?;
+        Ok(())
+    }
+}
+
+impl Transporter for TcpTransporter {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        match framing::request(&mut self.connection, &*self.codec, transport) {
+            Ok(ret) => ret,
+            Err(e) => format!("TCP transport error: {:?}", e).into(),
+        }
+    }
+}
+
+pub struct TcpTransportServer {
+    listener: TcpListener,
+    codec: Box<Codec>,
+    encryption: Option<NoiseConfig>,
+}
+
+impl TcpTransportServer {
+    /// Bind to port 0 to let the OS pick a free port. See local_addr().
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+        let listener = TcpListener::bind(addr)?;
+        log::debug!("Listening on {:?}", listener.local_addr()?);
+        Ok(TcpTransportServer{ listener, codec: Box::new(ProtobufCodec), encryption: None })
+    }
+
+    pub fn with_codec(mut self, codec: Box<Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Require a Noise handshake from every client, and encrypt everything sent over their connections.
+    /// Clients must connect with TcpTransporter::connect_encrypted(...).
+    pub fn with_encryption(mut self, config: NoiseConfig) -> Self {
+        self.encryption = Some(config);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Answer requests from any number of clients with the transporter. Does not return unless the listener fails.
+    pub fn serve<T: Transporter>(&self, transporter: &mut T) -> Result<(), Error> {
+        let listener = self.listener.try_clone()?;
+        let encryption = self.encryption.clone();
+        let incoming = std::iter::repeat_with(move || listener.accept())
+            .map(|accepted| -> Result<_, Error> {
+                let (stream, peer) = accepted?;
+                log::debug!("Accepted connection from {:?}", peer);
+                Ok(stream)
+            });
+        // The handshake runs on the connection's own thread, so a client that never finishes it only holds up itself.
+        let setup = move |stream| TcpConnection::new(stream, encryption.as_ref(), false);
+        framing::serve_connections_with_setup(incoming, setup, &*self.codec, transporter)
+    }
+
+    /// Accept a single connection and answer its requests until it closes.
+    pub fn serve_one<T: Transporter>(&self, transporter: &mut T) -> Result<(), Error> {
+        let (stream, peer) = self.listener.accept()?;
+        log::debug!("Accepted connection from {:?}", peer);
+        let mut connection = TcpConnection::new(stream, self.encryption.as_ref(), false)?;
+        framing::serve_connection(&mut connection, &*self.codec, transporter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{request, Echo};
+
+    #[test]
+    fn test_tcp_loopback() {
+        let server = TcpTransportServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || server.serve_one(&mut Echo).unwrap());
+
+        let mut client = TcpTransporter::connect(addr).unwrap();
+        for id in &["first", "second"] {
+            let sent = request("echo", id);
+            let ret = client.transport_data(&sent);
+            assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+            assert_eq!(ret.vec, vec![sent.event]);
+        }
+
+        drop(client);
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+//! Transporters and requests that the tests of several modules share.
+use crate::autogen_protobuf::transport::*;
+use crate::transporter::Transporter;
+
+/// Returns whatever event it was sent.
+pub struct Echo;
+
+impl Transporter for Echo {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        vec![transport.event.clone()].into()
+    }
+}
+
+/// A request to `module` that destroys the object `id`. Small enough for any transporter to carry.
+pub fn request(module: &str, id: &str) -> RequestTransport {
+    let descriptor = TypeDescriptor::new("alias".to_string(), "Structure".to_string());
+    RequestTransport::new(ModuleId::new(module.to_string()), Event::new(DestructorData::new(Id::new(id.to_string()), descriptor).into()), None, None)
+}
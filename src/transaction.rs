@@ -0,0 +1,27 @@
+//! A transaction groups events so that they are applied atomically across handlers.
+use crate::autogen_protobuf::transport::*;
+
+use failure::Error;
+
+#[derive(Default)]
+pub struct Transaction {
+    // Inverse events for each request that was applied, in the order they were applied.
+    applied: Vec<Vec<Event>>,
+}
+
+impl Transaction {
+    /// Remember how to undo a request. A request that returned errors was not applied, so the transaction fails.
+    pub fn record(&mut self, ret: &ReturnTransport) -> Result<(), Error> {
+        if !ret.errors.is_empty() {
+            return Err(failure::format_err!("Transaction request failed: {:?}", ret.errors));
+        }
+
+        self.applied.push(ret.inverse.clone());
+        Ok(())
+    }
+
+    /// The events that undo the whole transaction. The last request applied is the first one undone.
+    pub fn into_rollback_events(self) -> Vec<Event> {
+        self.applied.into_iter().rev().flatten().collect()
+    }
+}
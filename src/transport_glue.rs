@@ -15,6 +15,9 @@ pub trait TransportToProcessorGlue: CommonStructureFunctions {
         // Pass on transport to proper function
         let ret_data = match &transport.event.data {
             mod_Event::OneOfdata::process_struct(arg) => self.process_struct(arg.clone())?,
+            mod_Event::OneOfdata::model_changed(arg) => self.model_changed(arg.clone())?,
+            mod_Event::OneOfdata::call(arg) => reply_on_error(arg, self.call(arg.clone())),
+            mod_Event::OneOfdata::reply(arg) => self.reply(arg.clone())?,
             other => return Err(failure::format_err!("{:?} request function type unsupported!", other)),
         };
         Ok(ret_data.into())
@@ -24,27 +27,75 @@ pub trait TransportToProcessorGlue: CommonStructureFunctions {
 /// These functions are the endpoints to the different modules.
 pub trait TransportToModelGlue: CommonModelFunctions {
     fn handle_transport(&mut self, transport: &RequestTransport) -> Result<ReturnTransport, Error> {
+        // Figure out how to undo this request BEFORE it is applied.
+        let inverse = self.inverse(&transport.event)?;
+
         // Pass on transport to proper function
         let ret_data = match &transport.event.data {
             mod_Event::OneOfdata::constructor(arg) => self.constructor(arg.clone())?,
             mod_Event::OneOfdata::destructor(arg) => self.destructor(arg.clone())?,
             mod_Event::OneOfdata::update_model(arg) => self.update_model(arg.clone())?,
+            mod_Event::OneOfdata::list(arg) => self.list(arg.clone())?,
+            mod_Event::OneOfdata::get(arg) => self.get(arg.clone())?,
+            mod_Event::OneOfdata::model_changed(arg) => self.model_changed(arg.clone())?,
+            mod_Event::OneOfdata::call(arg) => reply_on_error(arg, self.call(arg.clone())),
+            mod_Event::OneOfdata::reply(arg) => self.reply(arg.clone())?,
             other => return Err(failure::format_err!("{:?} request function type unsupported!", other)),
         };
-        Ok(ret_data.into())
+
+        let mut ret: ReturnTransport = ret_data.into();
+        ret.inverse = inverse;
+        Ok(ret)
     }
 }
 
+impl Event {
+    /// The type this event is about, if it has one.
+    pub fn descriptor(&self) -> Option<&TypeDescriptor> {
+        match &self.data {
+            mod_Event::OneOfdata::constructor(data) => Some(&data.descriptor),
+            mod_Event::OneOfdata::destructor(data) => Some(&data.descriptor),
+            mod_Event::OneOfdata::update_model(data) => Some(&data.changes.changes.descriptor),
+            mod_Event::OneOfdata::process_struct(data) => Some(&data.changes.descriptor),
+            mod_Event::OneOfdata::list(data) => data.descriptor.as_ref(),
+            mod_Event::OneOfdata::get(data) => Some(&data.descriptor),
+            mod_Event::OneOfdata::object(data) => Some(&data.descriptor),
+            mod_Event::OneOfdata::subscribe(data) => data.descriptor.as_ref(),
+            mod_Event::OneOfdata::model_changed(data) => Some(&data.changes.changes.descriptor),
+            mod_Event::OneOfdata::call(data) => Some(&data.descriptor),
+            _ => None,
+        }
+    }
+}
+
+impl CallData {
+    /// The event that answers this call.
+    pub fn reply(&self, result: Vec<u8>) -> Event {
+        Event::new(ReplyData{ correlationId: self.correlationId.clone(), result: Some(result), error: None }.into())
+    }
+
+    pub fn reply_error(&self, error: &Error) -> Event {
+        Event::new(ReplyData{ correlationId: self.correlationId.clone(), result: None, error: Some(format!("{:?}", error)) }.into())
+    }
+}
+
+// The caller is waiting on a reply, so errors are sent back to it instead of failing the request.
+fn reply_on_error(call: &CallData, result: Result<Vec<Event>, Error>) -> Vec<Event> {
+    match result {
+        Ok(events) => events,
+        Err(e) => vec![call.reply_error(&e)],
+    }
+}
 
 impl From<Vec<Event>> for ReturnTransport {
     fn from(f: Vec<Event>) -> ReturnTransport {
-        ReturnTransport::new(f, vec![])
+        ReturnTransport::new(f, vec![], vec![], None, None)
     }
 }
 
 impl From<String> for ReturnTransport {
     fn from(f: String) -> ReturnTransport {
-        ReturnTransport::new(vec![], vec![f])
+        ReturnTransport::new(vec![], vec![f], vec![], None, None)
     }
 }
 
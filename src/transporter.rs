@@ -1,9 +1,15 @@
 use crate::autogen_protobuf::transport::*;
 use crate::{ TransportToProcessorGlue, TransportToModelGlue };
 use crate::{ CommonModelFunctions, CommonStructureFunctions };
+use crate::transaction::Transaction;
+use crate::objectregistry::ObjectRegistry;
+use crate::subscription::{Subscriptions, ChangeListener};
+use crate::schemaregistry::SchemaRegistry;
+use crate::routing::RoutingNode;
 
 use failure::Error;
 use std::convert::TryInto;
+use std::time::{Duration, Instant};
 
 use hashbrown::HashMap;
 
@@ -15,6 +21,11 @@ pub struct TransportNode {
     nodes: HashMap<ModuleId, Box<Transporter>>,
     struct_handlers: HashMap<ModuleId, Box<TransportToProcessorGlue>>, 
     model_handlers: HashMap<ModuleId, Box<TransportToModelGlue>>, 
+    subscriptions: Subscriptions,
+    // Requests for modules that are not local are sent wherever the router leads.
+    router: Option<RoutingNode>,
+    // While this is Some, applied changes wait here instead of being sent to subscribers.
+    held_notifications: Option<Vec<ModelDataChanges>>,
 }
 
 impl TransportNode {
@@ -34,22 +45,84 @@ impl TransportNode {
         }
     }
 
+    pub fn has_model_handler(&self, module_id: &ModuleId) -> bool {
+        self.model_handlers.contains_key(module_id)
+    }
+
     pub fn add_node<T: 'static + Transporter>(&mut self, module_id: ModuleId, new_node: T) {
         if let Some(_existing) = self.nodes.insert(module_id.clone(), Box::new(new_node)) {
             panic!("There already exists {:?} in transport node!", module_id); 
         }
     }
-}
 
+    pub fn set_router(&mut self, router: RoutingNode) {
+        self.router = Some(router);
+    }
 
+    pub fn router(&self) -> Option<&RoutingNode> {
+        self.router.as_ref()
+    }
 
-/// For now, broadcast to ALL transport. We never know if there are duplicate plugins for the same schema
-pub trait Transporter {
-    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport;
-}
+    pub fn router_mut(&mut self) -> Option<&mut RoutingNode> {
+        self.router.as_mut()
+    }
 
-impl Transporter for TransportNode {
-    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+    /// A neighbour that advertises to us gets our routes back. Its own routes are learned when we advertise to it.
+    pub fn answer_advertisement(&mut self, advertisement: &RouteAdvertisement) -> Result<RouteAdvertisement, Error> {
+        match &mut self.router {
+            Some(router) => Ok(router.answer()),
+            None => Err(failure::format_err!("Received routes from {:?} without a router!", advertisement.nodeId)),
+        }
+    }
+
+    /// Call `listener` whenever a change matching `filter` is applied. Returns the id used to unsubscribe.
+    pub fn listen(&mut self, mut filter: SubscribeData, listener: ChangeListener) -> ModuleId {
+        filter.subscriber = ModuleId::new(uuid::Uuid::new_v4().to_string());
+        let subscriber = filter.subscriber.clone();
+        self.subscriptions.listen(filter, listener);
+        subscriber
+    }
+
+    pub fn subscribe(&mut self, filter: SubscribeData) {
+        self.subscriptions.subscribe(filter);
+    }
+
+    pub fn unsubscribe(&mut self, data: &UnsubscribeData) {
+        self.subscriptions.unsubscribe(data);
+    }
+
+    /// Queue notifications until they are released or discarded. Used while the changes could still be rolled back.
+    pub fn hold_notifications(&mut self) {
+        self.held_notifications.get_or_insert_with(Vec::new);
+    }
+
+    /// Send every held notification and stop holding them. Returns any events the subscribers want handled.
+    pub fn release_notifications(&mut self) -> Vec<Event> {
+        let held = self.held_notifications.take().unwrap_or_default();
+        held.iter().flat_map(|changes| self.notify(changes)).collect()
+    }
+
+    /// Forget every held notification and stop holding them. The changes they were for have been rolled back.
+    pub fn discard_notifications(&mut self) {
+        self.held_notifications = None;
+    }
+
+    /// Let subscribers know about an applied change. Returns any events the subscribers want handled.
+    fn notify(&mut self, changes: &ModelDataChanges) -> Vec<Event> {
+        let mut events = Vec::new();
+        for subscriber in self.subscriptions.notify(changes) {
+            let notification = RequestTransport::new(subscriber.clone(), Event::new(ModelChangedData{ changes: changes.clone() }.into()), None, None);
+            let mut ret = self.transport_data(&notification);
+            // The change has been applied already, so a subscriber failing cannot undo it.
+            for err in &ret.errors {
+                log::error!("Subscriber {:?} failed to handle a change to {:?}! {}", subscriber, changes.id, err);
+            }
+            events.append(&mut ret.vec);
+        }
+        events
+    }
+
+    fn route(&mut self, transport: &RequestTransport) -> ReturnTransport {
         let dest = &transport.moduleId;
 
         // Check handlers first
@@ -71,14 +144,114 @@ impl Transporter for TransportNode {
             return node.transport_data(transport);
         }
 
+        // Then check remote modules.
+        if let Some(router) = &mut self.router {
+            if router.has_route(dest) {
+                return router.transport_data(transport);
+            }
+        }
+
         // If none exist, then just return an error
         format!("Transporter does not have handler or node that supports {:?}", dest).into()
     }
 }
 
+
+
+/// For now, broadcast to ALL transport. We never know if there are duplicate plugins for the same schema
+pub trait Transporter {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport;
+}
+
+impl Transporter for TransportNode {
+    /// Subscriptions are handled by the node itself. Applied model changes are passed on to subscribers.
+    /// Rollbacks are refused. They replace merge history, so only this node's own transactions may send them. See restore(...).
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        if let mod_Event::OneOfdata::update_model(data) = &transport.event.data {
+            if data.changes.rollback == Some(true) {
+                return format!("Refusing rollback of {:?}! Only transactions on the same node may roll it back.", data.id).into();
+            }
+        }
+        self.dispatch(transport)
+    }
+}
+
+impl TransportNode {
+    /// Like transport_data(...), but rollbacks are applied. Only for the inverse events of a transaction on this node.
+    fn restore(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        self.dispatch(transport)
+    }
+
+    fn dispatch(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        match &transport.event.data {
+            mod_Event::OneOfdata::subscribe(data) => {
+                self.subscribe(data.clone());
+                Vec::new().into()
+            },
+            mod_Event::OneOfdata::unsubscribe(data) => {
+                self.unsubscribe(data);
+                Vec::new().into()
+            },
+            mod_Event::OneOfdata::advertise(data) => {
+                match self.answer_advertisement(data) {
+                    Ok(answer) => vec![Event::new(answer.into())].into(),
+                    Err(e) => format!("{:?}", e).into(),
+                }
+            },
+            mod_Event::OneOfdata::update_model(_) => {
+                let mut ret = self.route(transport);
+                if !ret.errors.is_empty() {
+                    return ret;
+                }
+
+                // The handler reports the changes it actually applied. Merging may have left some out.
+                let (applied, mut events): (Vec<Event>, Vec<Event>) = ret.vec.into_iter().partition(|event| match event.data {
+                    mod_Event::OneOfdata::model_changed(_) => true,
+                    _ => false,
+                });
+                for event in applied {
+                    if let mod_Event::OneOfdata::model_changed(applied) = event.data {
+                        match &mut self.held_notifications {
+                            Some(held) => held.push(applied.changes),
+                            None => events.append(&mut self.notify(&applied.changes)),
+                        }
+                    }
+                }
+                ret.vec = events;
+                ret
+            },
+            _ => self.route(transport),
+        }
+    }
+}
+
+/// Calls that are not answered within this time get an error reply.
+pub const DEFAULT_CALL_TIMEOUT_MS: u64 = 30_000;
+
+#[derive(Clone)]
+struct PendingCall {
+    caller: Option<TypeDescriptor>,
+    deadline: Instant,
+}
+
+#[derive(Default)]
 pub struct RootTransporter {
     node: TransportNode,
     descriptor_to_module_ids: HashMap<TypeDescriptor, ModuleId>,
+    transaction: Option<Transaction>,
+    // True while a transaction is being rolled back. Only then are rollbacks sent on to the handlers.
+    rolling_back: bool,
+    registry: ObjectRegistry,
+    schema_registry: Option<SchemaRegistry>,
+    pending_calls: HashMap<String, PendingCall>,
+    // Replies to calls made with call_and_wait(...)
+    replies: HashMap<String, ReplyData>,
+    // Object events that list and get events produced. They are results, so they are kept here instead of being handled.
+    query_results: Vec<ObjectData>,
+    // What generated rpc dispatchers returned. There is no module to send a reply to, so it is kept like a query result.
+    rpc_replies: Vec<ProcessStructData>,
+    // Events that were still waiting when call_and_wait(...) got its reply. The next run starts with them.
+    queued: Vec<Event>,
 } 
 
 impl Transporter for RootTransporter {
@@ -97,48 +270,371 @@ impl RootTransporter {
         };
 
         // This will also update/create any data. Some data just keeps being sent through loops. Send that in the future.
-        let mut events = self.constructor(root_construct)?; 
-
-        // This is the runtime loop!
-        let mut done = false;
-        while !done {
-            let mut new_events = Vec::new();
-            for event in events {
-                match self.handle_event(event) {
-                    Err(e) => log::warn!("{:?}", e),
-                    Ok(mut new) => new_events.append(&mut new),
-                }
-            }
-            
-            events = new_events;
-            if events.len() == 0 { done = true; }
-        }
+        // The root object is constructed in a transaction too, so a failure leaves neither the handler nor the registry with it.
+        let result = self.run(vec![Event::new(root_construct.into())]);
 
         println!("No more events. Quitting!");
+        self.shutdown();
+        result
+    }
+
+    /// This is the runtime loop! Each event is applied as its own transaction, so one that fails is rolled back without undoing the others.
+    /// Keeps going after a rollback. Once there are no more events, fails if any of them were rolled back.
+    /// Use transaction(...) to apply a group of events all or nothing.
+    fn run(&mut self, events: Vec<Event>) -> Result<(), Error> {
+        let mut events = self.take_queued(events);
+        let mut failures = Vec::new();
+        while !events.is_empty() {
+            events = self.step(events, &mut failures);
+        }
+
+        if !failures.is_empty() {
+            return Err(failure::format_err!("{} events were rolled back! {:?}", failures.len(), failures));
+        }
         Ok(())
     }
 
+    /// Apply one generation of events and return the next one. The errors of events that were rolled back are added to failures.
+    fn step(&mut self, events: Vec<Event>, failures: &mut Vec<Error>) -> Vec<Event> {
+        let mut new_events = Vec::new();
+        for event in events {
+            match self.transaction(vec![event]) {
+                Ok(mut new) => new_events.append(&mut new),
+                Err(e) => {
+                    log::warn!("Event was rolled back! {:?}", e);
+                    failures.push(e);
+                },
+            }
+        }
+        new_events.append(&mut self.expire_calls());
+        new_events
+    }
+
+    /// Call a method on the module that handles descriptor and wait for its reply. Other events keep being processed meanwhile.
+    /// Returns as soon as the reply arrives. Events that are still waiting stay queued for the next run.
+    pub fn call_and_wait(&mut self, descriptor: TypeDescriptor, method: &str, argument: Vec<u8>, timeout: Duration) -> Result<Vec<u8>, Error> {
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        let call = CallData {
+            correlationId: correlation_id.clone(),
+            descriptor,
+            method: method.to_string(),
+            argument,
+            caller: None,
+            timeoutMs: Some(timeout.as_millis() as u64),
+        };
+
+        // Events that fail on the way are only logged. They have nothing to do with the call unless they stop its reply.
+        let mut events = self.take_queued(vec![Event::new(call.into())]);
+        let mut failures = Vec::new();
+        loop {
+            events = self.step(events, &mut failures);
+
+            if let Some(reply) = self.replies.remove(&correlation_id) {
+                self.queued = events;
+                return match (reply.result, reply.error) {
+                    (_, Some(error)) => Err(failure::format_err!("Call to {:?} failed! {}", method, error)),
+                    (Some(result), None) => Ok(result),
+                    (None, None) => Ok(Vec::new()),
+                };
+            }
+
+            // Nothing is left that could answer.
+            if events.is_empty() {
+                self.forget_call(&correlation_id);
+                return Err(failure::format_err!("Call to {:?} was never answered!", method));
+            }
+        }
+    }
+
+    fn take_queued(&mut self, mut events: Vec<Event>) -> Vec<Event> {
+        let mut queued = std::mem::replace(&mut self.queued, Vec::new());
+        queued.append(&mut events);
+        queued
+    }
+
+    fn forget_call(&mut self, correlation_id: &str) {
+        self.pending_calls.remove(correlation_id);
+        self.replies.remove(correlation_id);
+    }
+
+    fn handle_call(&mut self, data: CallData) -> Result<Vec<Event>, Error> {
+        log::debug!("Calling {:?} on {:?}...", data.method, data.descriptor);
+        let timeout = Duration::from_millis(data.timeoutMs.unwrap_or(DEFAULT_CALL_TIMEOUT_MS));
+        self.pending_calls.insert(data.correlationId.clone(), PendingCall{ caller: data.caller.clone(), deadline: Instant::now() + timeout });
+
+        let ret = match self.transport(&data.descriptor.clone(), data.clone().into()) {
+            Ok(ret) => ret,
+            // The caller is waiting on a reply, so send the error back instead of rolling the call back.
+            Err(e) => vec![data.reply_error(&e)],
+        };
+        log::debug!("...Returned from call(...)");
+        Ok(ret)
+    }
+
+    fn handle_reply(&mut self, data: ReplyData) -> Result<Vec<Event>, Error> {
+        let pending = match self.pending_calls.remove(&data.correlationId) {
+            Some(pending) => pending,
+            None => {
+                log::warn!("Dropping reply to {:?}. The call is unknown or has timed out.", data.correlationId);
+                return Ok(Vec::new());
+            },
+        };
+
+        // The deadline can pass in the middle of a generation, before expire_calls() gets to it.
+        if pending.deadline <= Instant::now() {
+            log::warn!("Reply to {:?} arrived after the call timed out!", data.correlationId);
+            return self.deliver_reply(pending, timeout_reply(data.correlationId));
+        }
+        self.deliver_reply(pending, data)
+    }
+
+    fn deliver_reply(&mut self, pending: PendingCall, data: ReplyData) -> Result<Vec<Event>, Error> {
+        match pending.caller {
+            Some(caller) => self.transport(&caller, data.into()),
+            None => {
+                self.replies.insert(data.correlationId.clone(), data);
+                Ok(Vec::new())
+            },
+        }
+    }
+
+    /// Send an error reply for every call that has passed its deadline.
+    fn expire_calls(&mut self) -> Vec<Event> {
+        let now = Instant::now();
+        let expired: Vec<String> = self.pending_calls.iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(correlation_id, _)| correlation_id.clone())
+            .collect();
+
+        let mut events = Vec::new();
+        for correlation_id in expired {
+            let pending = match self.pending_calls.remove(&correlation_id) {
+                Some(pending) => pending,
+                None => continue,
+            };
+
+            log::warn!("Call {:?} timed out!", correlation_id);
+            match self.deliver_reply(pending, timeout_reply(correlation_id)) {
+                Ok(mut new) => events.append(&mut new),
+                Err(e) => log::error!("Unable to deliver timeout to caller! {:?}", e),
+            }
+        }
+        events
+    }
+
+    /// Destroy every root object, which cascades to everything they own. Returns anything still alive afterwards, which has leaked.
+    pub fn shutdown(&mut self) -> Vec<(Id, TypeDescriptor)> {
+        log::debug!("Shutting down...");
+        let events = self.registry.roots().into_iter()
+            .map(|(id, descriptor)| Event::new(DestructorData{ id, descriptor }.into()))
+            .collect();
+        if let Err(e) = self.run(events) {
+            log::error!("Unable to destroy every object! {:?}", e);
+        }
+
+        let leaked = self.registry.live_objects();
+        for (id, descriptor) in &leaked {
+            log::warn!("Leaked object {:?} of type {:?}. Owned by {:?}", id, descriptor, self.registry.owner_of(id));
+        }
+        log::debug!("...Finished shutting down.");
+        leaked
+    }
+
+    /// Who owns this object? None if it is a root object or does not exist.
+    pub fn owner_of(&self, id: &Id) -> Option<Id> {
+        self.registry.owner_of(id).cloned()
+    }
+
+    /// Objects whose owner was destroyed without them.
+    pub fn orphans(&self) -> Vec<(Id, TypeDescriptor)> {
+        self.registry.orphans()
+    }
+
+    /// Give an object a new owner. Destroying the new owner will destroy this object as well.
+    pub fn reparent(&mut self, id: &Id, new_parent: Option<Id>) -> Result<(), Error> {
+        self.registry.reparent(id, new_parent)
+    }
+
+    pub fn registry(&self) -> &ObjectRegistry {
+        &self.registry
+    }
+
+    /// Once set, any descriptor that does not match a known schema is refused.
+    pub fn set_schema_registry(&mut self, schema_registry: SchemaRegistry) {
+        self.schema_registry = Some(schema_registry);
+    }
+
+    pub fn schema_registry_mut(&mut self) -> Option<&mut SchemaRegistry> {
+        self.schema_registry.as_mut()
+    }
+
+    /// List live objects. Filter by type and/or by the module that owns them.
+    pub fn list_objects(&mut self, descriptor: Option<TypeDescriptor>, module_id: Option<ModuleId>) -> Result<Vec<ObjectData>, Error> {
+        let module_ids = match (module_id, &descriptor) {
+            (Some(module_id), _) => vec![module_id],
+            (None, Some(descriptor)) => vec![self.descriptor_to_module_id(descriptor)?],
+            (None, None) => self.model_module_ids(),
+        };
+
+        let mut objects = Vec::new();
+        for module_id in module_ids {
+            let events = self.transport_to_module(module_id.clone(), ListObjectsData{ descriptor: descriptor.clone() }.into())?;
+            objects.append(&mut events_to_objects(events, &module_id));
+        }
+        Ok(objects)
+    }
+
+    /// Fetch the current serialized state of a single object.
+    pub fn get_object(&mut self, id: &Id) -> Result<ObjectData, Error> {
+        let descriptor = self.registry.descriptor_of(id).cloned()
+            .ok_or(failure::format_err!("{:?} is not a live object!", id))?;
+        let module_id = self.descriptor_to_module_id(&descriptor)?;
+        let events = self.transport_to_module(module_id.clone(), GetObjectData{ id: id.clone(), descriptor }.into())?;
+        events_to_objects(events, &module_id).into_iter().next()
+            .ok_or(failure::format_err!("Module {:?} did not return {:?}!", module_id, id))
+    }
+
+    /// Take the objects that list and get events sent through the runtime loop have found so far.
+    pub fn take_query_results(&mut self) -> Vec<ObjectData> {
+        std::mem::replace(&mut self.query_results, Vec::new())
+    }
+
+    /// Take the replies that rpc dispatchers have returned to requests sent through the runtime loop.
+    pub fn take_rpc_replies(&mut self) -> Vec<ProcessStructData> {
+        std::mem::replace(&mut self.rpc_replies, Vec::new())
+    }
+
+    fn model_module_ids(&self) -> Vec<ModuleId> {
+        let mut module_ids: Vec<ModuleId> = self.descriptor_to_module_ids.values()
+            .filter(|module_id| self.node.has_model_handler(module_id))
+            .cloned().collect();
+        module_ids.sort_by(|a, b| a.val.cmp(&b.val));
+        module_ids.dedup();
+        module_ids
+    }
+
+    /// Apply a group of events atomically. If any of them fail, every event that was already applied
+    /// is undone using the inverse events that the handlers returned. Returns the next generation of events.
+    pub fn transaction(&mut self, events: Vec<Event>) -> Result<Vec<Event>, Error> {
+        if self.transaction.is_some() {
+            return Err(failure::format_err!("Nested transactions are not supported!"));
+        }
+
+        self.transaction = Some(Transaction::default());
+        self.node.hold_notifications();
+        let registry = self.registry.clone();
+        let query_results = self.query_results.len();
+        let rpc_replies = self.rpc_replies.len();
+        let pending_calls = self.pending_calls.clone();
+        let replies = self.replies.clone();
+        let mut new_events = Vec::new();
+        let mut result = Ok(());
+        for event in events {
+            match self.handle_event(event) {
+                Ok(mut new) => new_events.append(&mut new),
+                Err(e) => { result = Err(e); break; },
+            }
+        }
+
+        let transaction = self.transaction.take().unwrap_or_default();
+        if let Err(e) = result {
+            self.rollback(transaction);
+            // The rollback shuffles ownership around. Put it back exactly how it was.
+            self.registry = registry;
+            self.query_results.truncate(query_results);
+            self.rpc_replies.truncate(rpc_replies);
+            self.pending_calls = pending_calls;
+            self.replies = replies;
+            self.node.discard_notifications();
+            return Err(e);
+        }
+
+        // Subscribers only hear about changes once they can no longer be rolled back.
+        new_events.append(&mut self.node.release_notifications());
+        Ok(new_events)
+    }
+
+    fn rollback(&mut self, transaction: Transaction) {
+        log::debug!("Rolling back transaction...");
+        self.rolling_back = true;
+        for event in transaction.into_rollback_events() {
+            // Any events caused by the rollback itself are dropped. We only want the old state back.
+            if let Err(e) = self.handle_event(event) {
+                log::error!("Unable to roll back event! {:?}", e);
+            }
+        }
+        self.rolling_back = false;
+        log::debug!("...Finished rolling back transaction.");
+    }
+
     fn handle_event(&mut self, event: Event) -> Result<Vec<Event>, Error> {
         match event.data {
             mod_Event::OneOfdata::constructor(data) => self.constructor(data),
             mod_Event::OneOfdata::destructor(data) => self.destructor(data),
             mod_Event::OneOfdata::update_model(data) => self.update_model(data),
             mod_Event::OneOfdata::process_struct(data) => self.process_struct(data),
+            mod_Event::OneOfdata::list(data) => self.list(data),
+            mod_Event::OneOfdata::get(data) => self.get(data),
+            mod_Event::OneOfdata::object(data) => { self.query_results.push(data); Ok(Vec::new()) },
+            mod_Event::OneOfdata::subscribe(data) => { self.node.subscribe(data); Ok(Vec::new()) },
+            mod_Event::OneOfdata::unsubscribe(data) => { self.node.unsubscribe(&data); Ok(Vec::new()) },
+            mod_Event::OneOfdata::model_changed(data) => Err(failure::format_err!("{:?} has no subscriber to send it to!", data)),
+            mod_Event::OneOfdata::call(data) => self.handle_call(data),
+            mod_Event::OneOfdata::reply(data) => self.handle_reply(data),
+            mod_Event::OneOfdata::advertise(data) => Err(failure::format_err!("Routes from {:?} are only learned over the connection to it! See RoutingNode::advertise().", data.nodeId)),
             mod_Event::OneOfdata::None => Err(failure::format_err!("Event type is None!")),
         }
     }
 
     fn set_descriptor_module_id(&mut self, descriptor: TypeDescriptor, module_id: ModuleId) {
+        if let Some(router) = self.node.router_mut() {
+            router.serve(module_id.clone(), descriptor.clone());
+        }
         self.descriptor_to_module_ids.insert(descriptor, module_id);
     }
 
+    /// Local handlers win. Otherwise the closest remote module is used.
     fn descriptor_to_module_id(&self, descriptor: &TypeDescriptor) -> Result<ModuleId, Error> {
-        match self.descriptor_to_module_ids.get(descriptor) {
-            Some(id) => Ok(id.clone()),
+        if let Some(id) = self.descriptor_to_module_ids.get(descriptor) {
+            return Ok(id.clone());
+        }
+
+        match self.node.router().and_then(|router| router.module_for(descriptor)) {
+            Some(id) => Ok(id),
             None => Err(failure::format_err!("No module for descriptor {:?}!", descriptor)),
         }
     }
 
+    /// Every handler added so far, and from now on, is advertised to the router's neighbours.
+    pub fn set_router(&mut self, mut router: RoutingNode) {
+        for (descriptor, module_id) in &self.descriptor_to_module_ids {
+            router.serve(module_id.clone(), descriptor.clone());
+        }
+        self.node.set_router(router);
+    }
+
+    pub fn router_mut(&mut self) -> Option<&mut RoutingNode> {
+        self.node.router_mut()
+    }
+
+    /// Send our routes to every neighbour. Call this more often than the route ttl.
+    pub fn advertise_routes(&mut self) -> Result<(), Error> {
+        match self.node.router_mut() {
+            Some(router) => router.advertise(),
+            None => Err(failure::format_err!("Cannot advertise routes without a router!")),
+        }
+    }
+
+    // Pass-through 
+    pub fn listen(&mut self, filter: SubscribeData, listener: ChangeListener) -> ModuleId {
+        self.node.listen(filter, listener)
+    }
+
+    // Pass-through 
+    pub fn unsubscribe(&mut self, subscriber: ModuleId) {
+        self.node.unsubscribe(&UnsubscribeData::new(subscriber));
+    }
+
     // Pass-through 
     pub fn add_struct_handler<H: 'static + CommonStructureFunctions + Default>(&mut self, descriptor: TypeDescriptor) {
         let module_id = ModuleId::new(uuid::Uuid::new_v4().to_string());
@@ -146,6 +642,15 @@ impl RootTransporter {
         self.set_descriptor_module_id(descriptor, module_id);
     }
 
+    /// One handler for several descriptors, such as every method of a generated rpc dispatcher.
+    pub fn add_struct_handler_for_descriptors<H: 'static + CommonStructureFunctions + Default>(&mut self, descriptors: Vec<TypeDescriptor>) {
+        let module_id = ModuleId::new(uuid::Uuid::new_v4().to_string());
+        self.node.add_struct_handler::<H>(module_id.clone());
+        for descriptor in descriptors {
+            self.set_descriptor_module_id(descriptor, module_id.clone());
+        }
+    }
+
     // Pass-through 
     pub fn add_model_handler<H: 'static + CommonModelFunctions + Default>(&mut self, descriptor: TypeDescriptor) {
         let module_id = ModuleId::new(uuid::Uuid::new_v4().to_string());
@@ -154,16 +659,57 @@ impl RootTransporter {
     }
 
     fn transport(&mut self, descriptor: &TypeDescriptor, data: mod_Event::OneOfdata) -> Result<Vec<Event>, Error> {
+        if let Some(schema_registry) = &mut self.schema_registry {
+            schema_registry.verify_descriptor(descriptor)?;
+        }
+
         let module_id = self.descriptor_to_module_id(&descriptor)?;
-        let transport = RequestTransport::new(module_id, Event::new(data));
-        let ret = self.transport_data(&transport).try_into()?;
+        self.transport_to_module(module_id, data)
+    }
+
+    fn transport_to_module(&mut self, module_id: ModuleId, data: mod_Event::OneOfdata) -> Result<Vec<Event>, Error> {
+        let transport = RequestTransport::new(module_id, Event::new(data), None, None);
+        let ret = if self.rolling_back {
+            self.node.restore(&transport)
+        } else {
+            self.transport_data(&transport)
+        };
+
+        // Inside of a transaction, any error fails the whole thing.
+        if let Some(transaction) = &mut self.transaction {
+            transaction.record(&ret)?;
+        }
+
+        let ret = ret.try_into()?;
         Ok(ret)
     }
 }
 
+fn timeout_reply(correlation_id: String) -> ReplyData {
+    ReplyData{ correlationId: correlation_id, result: None, error: Some("Call timed out!".to_string()) }
+}
+
+/// Pull the query results out of returned events and mark which module they came from.
+fn events_to_objects(events: Vec<Event>, module_id: &ModuleId) -> Vec<ObjectData> {
+    events.into_iter()
+        .filter_map(|event| match event.data {
+            mod_Event::OneOfdata::object(mut object) => {
+                object.moduleId = Some(module_id.clone());
+                Some(object)
+            },
+            _ => None,
+        }).collect()
+}
+
 impl CommonStructureFunctions for RootTransporter {
     /// Update structures only!!! When a structure is updated, return those structures for updating elsewhere.
     fn process_struct(&mut self, data: ProcessStructData) -> Result<Vec<Event>, Error> {
+        // A reply is typed by the method's return type, which no module handles.
+        if data.rpcReply == Some(true) {
+            self.rpc_replies.push(data);
+            return Ok(Vec::new());
+        }
+
         log::debug!("Calling process_struct({:?})...", data);
         let ret = self.transport(&data.changes.descriptor.clone(), data.into())?;
         log::debug!("...Returned from process_struct(...)");
@@ -175,16 +721,48 @@ impl CommonModelFunctions for RootTransporter {
     /// This is how you create your root object. It will return any objects we need to manually create.
     fn constructor(&mut self, data: ConstructorData) -> Result<Vec<Event>, Error> {
         log::debug!("Calling constructor({:?})...", data);
-        let ret = self.transport(&data.descriptor.clone(), data.into())?;
+        let id = data.id.clone();
+        let descriptor = data.descriptor.clone();
+
+        // Register first, so that an object the registry refuses is never handed to its handler.
+        self.registry.register(id.clone(), descriptor.clone())?;
+        let ret = match self.transport(&descriptor, data.into()) {
+            Ok(ret) => ret,
+            Err(e) => {
+                self.registry.unregister(&id);
+                return Err(e);
+            },
+        };
         log::debug!("...Returned from constructor(...)");
+
+        // Any object this constructor asks for belongs to it.
+        for event in &ret {
+            if let mod_Event::OneOfdata::constructor(child) = &event.data {
+                if child.id != id {
+                    self.registry.announce_child(&id, &child.id)?;
+                }
+            }
+        }
         Ok(ret)
     }
 
-    /// Destroys an object.  Recurse by destroying any returned sub-objects
+    /// Destroys an object.  Recurse by destroying any returned sub-objects, and anything else the registry says it owns.
     fn destructor(&mut self, data: DestructorData) -> Result<Vec<Event>, Error> {
         log::debug!("Calling destructor({:?})...", data);
-        let ret = self.transport(&data.descriptor.clone(), data.into())?;
+        let id = data.id.clone();
+        let mut ret = self.transport(&data.descriptor.clone(), data.into())?;
         log::debug!("...Returned from destructor(...)");
+
+        for (child, descriptor) in self.registry.unregister(&id) {
+            let already_destroyed = ret.iter().any(|event| match &event.data {
+                mod_Event::OneOfdata::destructor(existing) => existing.id == child,
+                _ => false,
+            });
+
+            if !already_destroyed {
+                ret.push(Event::new(DestructorData{ id: child, descriptor }.into()));
+            }
+        }
         Ok(ret)
     }
 
@@ -195,4 +773,371 @@ impl CommonModelFunctions for RootTransporter {
         log::debug!("...Returned from update_model(...)");
         Ok(ret)
     }
+
+    /// Lists objects across every model handler.
+    fn list(&mut self, data: ListObjectsData) -> Result<Vec<Event>, Error> {
+        log::debug!("Calling list({:?})...", data);
+        let objects = self.list_objects(data.descriptor, None)?;
+        log::debug!("...Returned from list(...)");
+        Ok(objects.into_iter().map(|object| Event::new(object.into())).collect())
+    }
+
+    fn get(&mut self, data: GetObjectData) -> Result<Vec<Event>, Error> {
+        log::debug!("Calling get({:?})...", data);
+        let module_id = self.descriptor_to_module_id(&data.descriptor)?;
+        let objects = events_to_objects(self.transport_to_module(module_id.clone(), data.into())?, &module_id);
+        log::debug!("...Returned from get(...)");
+        Ok(objects.into_iter().map(|object| Event::new(object.into())).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{Modifiable, ModelInterface};
+
+    fn descriptor(structure: &str) -> TypeDescriptor {
+        TypeDescriptor::new("test".to_string(), structure.to_string())
+    }
+
+    // A model with a single byte of state.
+    #[derive(Default)]
+    struct Counter {
+        value: u8,
+    }
+
+    impl Modifiable for Counter {
+        fn modify(&mut self, changes: &ModelDataChanges) {
+            if let Some(value) = changes.changes.serializedData.first() {
+                self.value = *value;
+            }
+        }
+
+        fn set_defaults(&mut self) {}
+        fn get_all_model_changes(&self) -> Vec<ModelDataChanges> { Vec::new() }
+        fn get_all_struct_changes(&self) -> Vec<StructDataChanges> { Vec::new() }
+        fn get_all_sub_object_ids(&self) -> Vec<(Id, TypeDescriptor)> { Vec::new() }
+
+        fn get_state(&self) -> Option<StructDataChanges> {
+            Some(StructDataChanges::new(vec![self.value], vec!["value".to_string()], descriptor("Counter")))
+        }
+    }
+
+    // Fails every structure it is sent.
+    #[derive(Default)]
+    struct Broken;
+
+    impl CommonStructureFunctions for Broken {
+        fn process_struct(&mut self, _data: ProcessStructData) -> Result<Vec<Event>, Error> {
+            Err(failure::format_err!("Broken!"))
+        }
+    }
+
+    fn root() -> RootTransporter {
+        let mut root = RootTransporter::default();
+        root.add_model_handler::<ModelInterface<Counter>>(descriptor("Counter"));
+        root.add_struct_handler::<Broken>(descriptor("Broken"));
+        root.add_model_handler::<Parent>(descriptor("Parent"));
+        root.add_model_handler::<Parent>(descriptor("Sticky"));
+        root
+    }
+
+    fn construct(id: &Id) -> Event {
+        Event::new(ConstructorData{ id: id.clone(), descriptor: descriptor("Counter"), serializedData: None }.into())
+    }
+
+    fn set(id: &Id, value: u8) -> Event {
+        let changes = StructDataChanges::new(vec![value], vec!["value".to_string()], descriptor("Counter"));
+        Event::new(UpdateModelData::new(id.clone(), ModelDataChanges::new(id.clone(), changes, None, Vec::new(), Vec::new(), None)).into())
+    }
+
+    // Constructs a Counter child along with itself. Refuses to be destroyed when it is sticky.
+    #[derive(Default)]
+    struct Parent;
+
+    impl CommonModelFunctions for Parent {
+        fn constructor(&mut self, data: ConstructorData) -> Result<Vec<Event>, Error> {
+            Ok(vec![construct(&child_of(&data.id))])
+        }
+
+        fn destructor(&mut self, data: DestructorData) -> Result<Vec<Event>, Error> {
+            match data.descriptor.structure.as_str() {
+                "Sticky" => Err(failure::format_err!("Sticky objects cannot be destroyed!")),
+                _ => Ok(Vec::new()),
+            }
+        }
+
+        fn update_model(&mut self, _data: UpdateModelData) -> Result<Vec<Event>, Error> { Ok(Vec::new()) }
+    }
+
+    fn child_of(id: &Id) -> Id {
+        Id::new(format!("{}/child", id.val))
+    }
+
+    fn construct_parent(id: &Id, structure: &str) -> Event {
+        Event::new(ConstructorData{ id: id.clone(), descriptor: descriptor(structure), serializedData: None }.into())
+    }
+
+    fn broken() -> Event {
+        Event::new(ProcessStructData::new(StructDataChanges::new(Vec::new(), Vec::new(), descriptor("Broken")), None).into())
+    }
+
+    fn value(root: &mut RootTransporter, id: &Id) -> u8 {
+        root.get_object(id).unwrap().state.unwrap().serializedData[0]
+    }
+
+    #[test]
+    fn test_transaction_commit_and_rollback() {
+        let mut root = root();
+        let id = Id::new("counter".to_string());
+        root.transaction(vec![construct(&id)]).unwrap();
+        root.transaction(vec![set(&id, 1)]).unwrap();
+        assert_eq!(value(&mut root, &id), 1);
+
+        // The second event fails, so the first one is undone.
+        assert!(root.transaction(vec![set(&id, 2), broken()]).is_err());
+        assert_eq!(value(&mut root, &id), 1);
+
+        let other = Id::new("other".to_string());
+        assert!(root.transaction(vec![construct(&other), broken()]).is_err());
+        assert!(root.get_object(&other).is_err());
+        assert!(root.registry().get(&other).is_none());
+    }
+
+    #[test]
+    fn test_rollbacks_from_outside_are_refused() {
+        let mut root = root();
+        let id = Id::new("counter".to_string());
+        root.run(vec![construct(&id), set(&id, 4)]).unwrap();
+
+        let mut rollback = set(&id, 9);
+        if let mod_Event::OneOfdata::update_model(data) = &mut rollback.data {
+            data.changes.rollback = Some(true);
+        }
+        let module_id = root.descriptor_to_module_id(&descriptor("Counter")).unwrap();
+        let ret = root.transport_data(&RequestTransport::new(module_id, rollback.clone(), None, None));
+        assert!(ret.errors[0].contains("Refusing rollback"), "{:?}", ret.errors);
+        // Nor can a rollback be slipped in among the events, where a handler may have returned it.
+        assert!(root.run(vec![rollback]).is_err());
+        assert_eq!(value(&mut root, &id), 4);
+    }
+
+    #[test]
+    fn test_rolled_back_event_is_reported() {
+        let mut root = root();
+        let id = Id::new("counter".to_string());
+        let other = Id::new("other".to_string());
+        root.run(vec![construct(&id)]).unwrap();
+
+        // Only the broken event is rolled back. The loop keeps going, and reports it at the end.
+        let result = root.run(vec![set(&id, 3), broken(), construct_parent(&other, "Parent")]);
+        assert!(format!("{:?}", result.unwrap_err()).contains("1 events were rolled back"));
+        assert_eq!(value(&mut root, &id), 3);
+        assert!(root.registry().get(&child_of(&other)).is_some());
+    }
+
+    #[test]
+    fn test_ownership_and_shutdown() {
+        let mut root = root();
+        let parent = Id::new("parent".to_string());
+        let other = Id::new("other".to_string());
+        root.run(vec![construct_parent(&parent, "Parent"), construct(&other)]).unwrap();
+        assert_eq!(root.owner_of(&child_of(&parent)), Some(parent.clone()));
+
+        // A parent cannot end up owned by its own child.
+        assert!(root.reparent(&parent, Some(child_of(&parent))).is_err());
+        root.reparent(&other, Some(child_of(&parent))).unwrap();
+        assert_eq!(root.owner_of(&other), Some(child_of(&parent)));
+
+        // Destroying the parent cascades through the child to the reparented object.
+        root.run(vec![Event::new(DestructorData::new(parent.clone(), descriptor("Parent")).into())]).unwrap();
+        assert!(root.registry().is_empty());
+        assert!(root.orphans().is_empty());
+
+        let sticky = Id::new("sticky".to_string());
+        root.run(vec![construct_parent(&sticky, "Sticky")]).unwrap();
+        let leaked = root.shutdown();
+        assert_eq!(leaked.len(), 2);
+        assert!(leaked.contains(&(sticky, descriptor("Sticky"))));
+    }
+
+    #[test]
+    fn test_failed_constructor_is_not_registered() {
+        let mut root = root();
+        let id = Id::new("counter".to_string());
+        root.run(vec![construct(&id)]).unwrap();
+
+        // Constructing it again fails in the registry, before the handler sees it.
+        assert!(root.run(vec![construct(&id)]).is_err());
+        assert_eq!(root.registry().live_objects().len(), 1);
+        assert_eq!(value(&mut root, &id), 0);
+    }
+
+    #[test]
+    fn test_queries_through_the_runtime_loop() {
+        let mut root = root();
+        let first = Id::new("first".to_string());
+        let second = Id::new("second".to_string());
+        root.run(vec![construct(&first), construct(&second), construct_parent(&Id::new("parent".to_string()), "Parent")]).unwrap();
+        root.run(vec![set(&second, 7)]).unwrap();
+
+        // The object events that come back are collected instead of failing the next generation.
+        let list = Event::new(ListObjectsData::new(Some(descriptor("Counter"))).into());
+        let get = Event::new(GetObjectData::new(second.clone(), descriptor("Counter")).into());
+        root.run(vec![list, get]).unwrap();
+
+        let results = root.take_query_results();
+        let mut listed: Vec<String> = results.iter().filter(|object| object.state.is_none()).map(|object| object.id.val.clone()).collect();
+        listed.sort();
+        assert_eq!(listed, vec!["first".to_string(), "parent/child".to_string(), "second".to_string()]);
+        let got: Vec<&ObjectData> = results.iter().filter(|object| object.state.is_some()).collect();
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].state.as_ref().unwrap().serializedData, vec![7]);
+        assert!(root.take_query_results().is_empty());
+
+        // Parent does not support get.
+        let get = Event::new(GetObjectData::new(Id::new("parent".to_string()), descriptor("Parent")).into());
+        assert!(root.run(vec![get]).is_err());
+    }
+
+    // Answers calls. Anything it is sent as a structure takes a while.
+    #[derive(Default)]
+    struct Service;
+
+    impl CommonStructureFunctions for Service {
+        fn process_struct(&mut self, _data: ProcessStructData) -> Result<Vec<Event>, Error> {
+            std::thread::sleep(Duration::from_millis(100));
+            Ok(Vec::new())
+        }
+
+        fn call(&mut self, data: CallData) -> Result<Vec<Event>, Error> {
+            match data.method.as_str() {
+                "echo" => Ok(vec![data.reply(data.argument.clone()), construct_parent(&Id::new("parent".to_string()), "Parent")]),
+                "broken" => Ok(vec![data.reply(data.argument.clone()), broken()]),
+                "slow" => Ok(vec![slow(), data.reply(data.argument.clone())]),
+                other => Err(failure::format_err!("No method {:?}!", other)),
+            }
+        }
+    }
+
+    fn slow() -> Event {
+        Event::new(ProcessStructData::new(StructDataChanges::new(Vec::new(), Vec::new(), descriptor("Service")), None).into())
+    }
+
+    fn service_root() -> RootTransporter {
+        let mut root = root();
+        root.add_struct_handler::<Service>(descriptor("Service"));
+        root.run(vec![construct(&Id::new("counter".to_string()))]).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_call_returns_at_the_reply_and_queues_the_rest() {
+        let mut root = service_root();
+        let parent = Id::new("parent".to_string());
+        let result = root.call_and_wait(descriptor("Service"), "echo", vec![9], Duration::from_secs(10)).unwrap();
+        assert_eq!(result, vec![9]);
+
+        // The parent was constructed along with the reply. Its child comes a generation later, so it waits for the next run.
+        assert!(root.registry().get(&parent).is_some());
+        assert!(root.registry().get(&child_of(&parent)).is_none());
+        root.run(Vec::new()).unwrap();
+        assert!(root.registry().get(&child_of(&parent)).is_some());
+    }
+
+    #[test]
+    fn test_failing_event_does_not_undo_the_reply() {
+        let mut root = service_root();
+        let result = root.call_and_wait(descriptor("Service"), "broken", vec![1], Duration::from_secs(10)).unwrap();
+        assert_eq!(result, vec![1]);
+        assert!(root.pending_calls.is_empty());
+        assert!(root.replies.is_empty());
+    }
+
+    #[test]
+    fn test_rolled_back_reply_is_forgotten() {
+        let mut root = service_root();
+        let call = CallData{ correlationId: "call".to_string(), descriptor: descriptor("Service"), method: "echo".to_string(), ..Default::default() };
+        assert!(root.transaction(vec![Event::new(call.into()), broken()]).is_err());
+        assert!(root.pending_calls.is_empty());
+        assert!(root.replies.is_empty());
+    }
+
+    #[test]
+    fn test_late_reply_in_the_same_generation_times_out() {
+        let mut root = service_root();
+        let result = root.call_and_wait(descriptor("Service"), "slow", vec![1], Duration::from_millis(50));
+        assert!(format!("{:?}", result.unwrap_err()).contains("timed out"));
+        assert!(root.pending_calls.is_empty());
+    }
+
+    // Written the way buildfunctions::rpc_generator() writes a dispatcher for a service with two methods.
+    #[derive(Default)]
+    struct EchoDispatcher;
+
+    impl CommonStructureFunctions for EchoDispatcher {
+        fn process_struct(&mut self, data: ProcessStructData) -> Result<Vec<Event>, Error> {
+            match data.changes.descriptor.structure.as_str() {
+                "Echo.first" | "Echo.second" => {
+                    let arg: ModuleId = crate::rpc::rpc_decode_arg(&data)?;
+                    Ok(vec![crate::rpc::rpc_reply("test", "ModuleId", &arg)?])
+                },
+                other => Err(failure::format_err!("Echo has no method {:?}!", other)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rpc_methods_share_a_dispatcher_and_replies_are_kept() {
+        let mut root = RootTransporter::default();
+        root.add_struct_handler_for_descriptors::<EchoDispatcher>(vec![descriptor("Echo.first"), descriptor("Echo.second")]);
+
+        let module_id = ModuleId::new("unused".to_string());
+        let first = crate::rpc::rpc_request(&module_id, "test", "Echo.first", &ModuleId::new("1".to_string())).unwrap().event;
+        let second = crate::rpc::rpc_request(&module_id, "test", "Echo.second", &ModuleId::new("2".to_string())).unwrap().event;
+        root.run(vec![first, second]).unwrap();
+
+        // The replies are typed ModuleId, which has no handler. They are kept instead of failing the next generation.
+        let replies: Vec<String> = root.take_rpc_replies().iter()
+            .map(|reply| quick_protobuf::deserialize_from_slice::<ModuleId>(&reply.changes.serializedData).unwrap().val)
+            .collect();
+        assert_eq!(replies, vec!["1".to_string(), "2".to_string()]);
+        assert!(root.take_rpc_replies().is_empty());
+    }
+
+    #[test]
+    fn test_notifications_are_sent_on_commit() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut root = root();
+        let id = Id::new("counter".to_string());
+        root.run(vec![construct(&id)]).unwrap();
+
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let listener = received.clone();
+        let filter = SubscribeData::new(ModuleId::default(), Some(id.clone()), None, None);
+        root.listen(filter, Box::new(move |changes: &ModelDataChanges| listener.borrow_mut().push(changes.changes.serializedData.clone())));
+
+        root.run(vec![set(&id, 1)]).unwrap();
+        assert_eq!(*received.borrow(), vec![vec![1]]);
+
+        // Rolled back changes never happened, so nobody hears about them.
+        assert!(root.transaction(vec![set(&id, 2), broken()]).is_err());
+        assert_eq!(*received.borrow(), vec![vec![1]]);
+
+        // A change that was already merged is not applied again, so it is not sent again either.
+        let mut versioned = set(&id, 3);
+        if let mod_Event::OneOfdata::update_model(data) = &mut versioned.data {
+            let mut version = VersionVector::default();
+            crate::merge::increment_version(&mut version, "replica");
+            data.changes.version = Some(version);
+            // Written after the local changes above, so it wins.
+            let timestamp = HybridTimestamp::new(crate::replay::now_ms() + 60_000, 0, "replica".to_string());
+            data.changes.propertyVersions = vec![PropertyVersion::new("value".to_string(), timestamp)];
+        }
+        root.run(vec![versioned.clone()]).unwrap();
+        root.run(vec![versioned]).unwrap();
+        assert_eq!(*received.borrow(), vec![vec![1], vec![3]]);
+    }
 }
@@ -0,0 +1,269 @@
+//! Sends RequestTransports to another process on the same host over a Unix domain socket.
+//! The server knows who is on the other end of each connection (SO_PEERCRED), and asks its AccessControl before any request is dispatched.
+use crate::autogen_protobuf::transport::*;
+use crate::codec::{self, Codec, ProtobufCodec};
+use crate::framing::{self, FramedConnection, PoisonOnError, StreamConnection};
+use crate::transporter::Transporter;
+
+use failure::Error;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The process on the other end of a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerCredentials {
+    /// Only Linux and Android report the pid.
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, Error> {
+    let mut cred = libc::ucred{ pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    };
+
+    if ret != 0 {
+        return Err(failure::format_err!("Unable to read peer credentials! {:?}", std::io::Error::last_os_error()));
+    }
+    Ok(PeerCredentials{ pid: Some(cred.pid), uid: cred.uid, gid: cred.gid })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn peer_credentials(stream: &UnixStream) -> Result<PeerCredentials, Error> {
+    let mut uid = 0;
+    let mut gid = 0;
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(failure::format_err!("Unable to read peer credentials! {:?}", std::io::Error::last_os_error()));
+    }
+    Ok(PeerCredentials{ pid: None, uid, gid })
+}
+
+/// Decides whether a peer may make a request. Returning an error refuses it, and the error is sent back to the peer.
+pub trait AccessControl: Send + Sync {
+    fn check(&self, credentials: &PeerCredentials, request: &RequestTransport) -> Result<(), Error>;
+}
+
+impl<F> AccessControl for F where F: Fn(&PeerCredentials, &RequestTransport) -> Result<(), Error> + Send + Sync {
+    fn check(&self, credentials: &PeerCredentials, request: &RequestTransport) -> Result<(), Error> {
+        self(credentials, request)
+    }
+}
+
+/// Only allow processes running as the same user as this one. This is the default.
+pub struct SameUser;
+
+impl AccessControl for SameUser {
+    fn check(&self, credentials: &PeerCredentials, _request: &RequestTransport) -> Result<(), Error> {
+        let uid = unsafe { libc::getuid() };
+        if credentials.uid != uid {
+            return Err(failure::format_err!("Peer uid {} is not allowed!", credentials.uid));
+        }
+        Ok(())
+    }
+}
+
+/// Only allow processes running as one of the uids. Useful when plugins run sandboxed as their own user.
+pub struct AllowUids(pub Vec<u32>);
+
+impl AccessControl for AllowUids {
+    fn check(&self, credentials: &PeerCredentials, _request: &RequestTransport) -> Result<(), Error> {
+        if !self.0.contains(&credentials.uid) {
+            return Err(failure::format_err!("Peer uid {} is not allowed!", credentials.uid));
+        }
+        Ok(())
+    }
+}
+
+pub struct UnixTransporter {
+    connection: PoisonOnError<StreamConnection<UnixStream>>,
+    codec: Box<Codec>,
+}
+
+impl UnixTransporter {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let stream = UnixStream::connect(path.as_ref())?;
+        log::debug!("Connected to {:?}", path.as_ref());
+        Ok(UnixTransporter{ connection: PoisonOnError::new(StreamConnection::new(stream)), codec: Box::new(ProtobufCodec) })
+    }
+
+    pub fn with_codec(mut self, codec: Box<Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// How long to wait for a request to return. None waits forever.
+    /// A request that times out leaves the connection unusable. Connect again to keep sending.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        self.connection.get_ref().stream().set_read_timeout(timeout)?;
+        self.connection.get_ref().stream().set_write_timeout(timeout)?;
+        Ok(())
+    }
+
+    /// Who is serving the other end of the socket.
+    pub fn peer_credentials(&self) -> Result<PeerCredentials, Error> {
+        peer_credentials(self.connection.get_ref().stream())
+    }
+}
+
+impl Transporter for UnixTransporter {
+    fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+        match framing::request(&mut self.connection, &*self.codec, transport) {
+            Ok(ret) => ret,
+            Err(e) => format!("Unix socket transport error: {:?}", e).into(),
+        }
+    }
+}
+
+/// Removes the socket file when dropped.
+pub struct UnixTransportServer {
+    listener: UnixListener,
+    path: PathBuf,
+    codec: Box<Codec>,
+    access: Arc<AccessControl>,
+}
+
+impl UnixTransportServer {
+    /// Fails if something already exists at the path.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let listener = UnixListener::bind(path.as_ref())?;
+        log::debug!("Listening on {:?}", path.as_ref());
+        Ok(UnixTransportServer{ listener, path: path.as_ref().to_path_buf(), codec: Box::new(ProtobufCodec), access: Arc::new(SameUser) })
+    }
+
+    pub fn with_codec(mut self, codec: Box<Codec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn with_access_control<A: 'static + AccessControl>(mut self, access: A) -> Self {
+        self.access = Arc::new(access);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Answer requests from any number of clients with the transporter. Does not return unless the listener fails.
+    pub fn serve<T: Transporter>(&self, transporter: &mut T) -> Result<(), Error> {
+        let listener = self.listener.try_clone()?;
+        let codec_name = self.codec.name();
+        let access = self.access.clone();
+        let incoming = std::iter::repeat_with(move || listener.accept())
+            .map(move |accepted| -> Result<_, Error> {
+                let (stream, _) = accepted?;
+                let codec = codec::codec_by_name(codec_name)
+                    .ok_or(failure::format_err!("Codec {} is not supported!", codec_name))?;
+                CheckedConnection::new(stream, codec, access.clone())
+            });
+        framing::serve_connections(incoming, &*self.codec, transporter)
+    }
+
+    /// Accept a single connection and answer its requests until it closes.
+    pub fn serve_one<T: Transporter>(&self, transporter: &mut T) -> Result<(), Error> {
+        let (stream, _) = self.listener.accept()?;
+        let codec = codec::codec_by_name(self.codec.name())
+            .ok_or(failure::format_err!("Codec {} is not supported!", self.codec.name()))?;
+        let mut connection = CheckedConnection::new(stream, codec, self.access.clone())?;
+        framing::serve_connection(&mut connection, &*self.codec, transporter)
+    }
+}
+
+impl Drop for UnixTransportServer {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Unable to remove socket {:?}! {:?}", self.path, e);
+        }
+    }
+}
+
+// Answers refused requests itself, so only allowed requests are ever received by the server.
+struct CheckedConnection {
+    connection: StreamConnection<UnixStream>,
+    credentials: PeerCredentials,
+    codec: Box<Codec>,
+    access: Arc<AccessControl>,
+}
+
+impl CheckedConnection {
+    fn new(stream: UnixStream, codec: Box<Codec>, access: Arc<AccessControl>) -> Result<Self, Error> {
+        let credentials = peer_credentials(&stream)?;
+        log::debug!("Accepted connection from {:?}", credentials);
+        Ok(CheckedConnection{ connection: StreamConnection::new(stream), credentials, codec, access })
+    }
+
+    fn check(&self, frame: &[u8]) -> Result<(), Error> {
+        let request = self.codec.decode_request(frame)?;
+        self.access.check(&self.credentials, &request)
+    }
+}
+
+impl FramedConnection for CheckedConnection {
+    fn send_frame(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.connection.send_frame(payload)
+    }
+
+    fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        while let Some(frame) = self.connection.receive_frame()? {
+            match self.check(&frame) {
+                Ok(()) => return Ok(Some(frame)),
+                Err(e) => {
+                    log::warn!("Refused request from {:?}! {:?}", self.credentials, e);
+                    let ret: ReturnTransport = format!("Request refused! {:?}", e).into();
+                    let bytes = self.codec.encode_return(&ret)?;
+                    self.connection.send_frame(&bytes)?;
+                },
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{request, Echo};
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("protocols-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_unix_socket_credentials() {
+        let server = UnixTransportServer::bind(socket_path("credentials")).unwrap()
+            .with_access_control(|credentials: &PeerCredentials, request: &RequestTransport| {
+                SameUser.check(credentials, request)?;
+                if request.moduleId.val == "forbidden" {
+                    return Err(failure::format_err!("Forbidden module"));
+                }
+                Ok(())
+            });
+        let path = server.path().to_path_buf();
+        let handle = std::thread::spawn(move || server.serve_one(&mut Echo).unwrap());
+
+        let mut client = UnixTransporter::connect(&path).unwrap();
+        assert_eq!(client.peer_credentials().unwrap().uid, unsafe { libc::getuid() });
+
+        let sent = request("allowed", "id");
+        let ret = client.transport_data(&sent);
+        assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+        assert_eq!(ret.vec, vec![sent.event]);
+
+        let ret = client.transport_data(&request("forbidden", "id"));
+        assert!(ret.vec.is_empty());
+        assert_eq!(ret.errors.len(), 1);
+
+        drop(client);
+        handle.join().unwrap();
+        assert!(!path.exists());
+    }
+}
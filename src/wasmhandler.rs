@@ -2,7 +2,7 @@ use std::path::PathBuf;
 use hashbrown::HashMap;
 use failure::Error;
 
-use crate::{ RequestTransport, ReturnTransport };
+use crate::{ RequestTransport, ReturnTransport, SchemaHandshake };
 
 /*#[cfg(not(target_arch = "wasm32"))]
 impl From<PathBuf> for WasmModule {
@@ -99,10 +99,10 @@ impl WasmModule {
 
 #[cfg(not(target_arch = "wasm32"))]
 impl crate::commonlibrary::CommonFFI for WasmModule {
-    fn call_ffi_handle_request(&self, transport: &RequestTransport) -> Result<ReturnTransport, Error> {
+    fn call_ffi_handle_request(&self, transport: &RequestTransport, codec: &crate::codec::Codec) -> Result<ReturnTransport, Error> {
         log::trace!("Calling wasm FFI function 'handle_request_ffi(...)'...");
 
-        let bytes = quick_protobuf::serialize_into_vec(transport)?;
+        let bytes = codec.encode_request(transport)?;
         let results = self.invoke_with_hacky_bytes_arg("handle_request_ffi_wasm", &bytes)?;
         // Results is an identifier of what is to be returned.
         // The module could send back a DIFFERENT identifier of another module (if it can guess it)
@@ -118,7 +118,7 @@ impl crate::commonlibrary::CommonFFI for WasmModule {
             .ok_or(failure::format_err!("return_data result does not exist in map for call_ffi_handle_request!"))?;
 
         // Read back from UNIQUE_CALLS.
-        let ret: ReturnTransport = quick_protobuf::deserialize_from_slice(&from_ffi)?;
+        let ret = codec.decode_return(&from_ffi)?;
         log::trace!("...Received from FFI: {:?}", ret);
         Ok(ret)
     }
@@ -129,6 +129,25 @@ impl crate::commonlibrary::CommonFFI for WasmModule {
         log::debug!("...init() successful!");
         Ok(())
     }
+
+    fn call_ffi_negotiate(&self, supported: &SchemaHandshake) -> Result<SchemaHandshake, Error> {
+        log::debug!("Calling wasm FFI function 'negotiate_ffi_wasm(...)'...");
+
+        let bytes = quick_protobuf::serialize_into_vec(supported)?;
+        let results = self.invoke_with_hacky_bytes_arg("negotiate_ffi_wasm", &bytes)?;
+        let identifier = match results.get(0) {
+            Some(wasmer_runtime::Value::I32(identifier)) => identifier,
+            Some(other) => return Err(failure::format_err!("call_ffi_negotiate did not return an i32! Found {:?}", other)),
+            None => return Err(failure::format_err!("call_ffi_negotiate did not return anything! Expecting an i32!")),
+        };
+
+        let from_ffi = RETURN_IDENTIFIER.lock().unwrap().remove(identifier)
+            .ok_or(failure::format_err!("return_data result does not exist in map for call_ffi_negotiate!"))?;
+
+        let ret: SchemaHandshake = quick_protobuf::deserialize_from_slice(&from_ffi)?;
+        log::debug!("...Plugin supports {:?}", ret);
+        Ok(ret)
+    }
 }
 
 // Webasm calls this function to return byte data
@@ -0,0 +1,270 @@
+//! Sends RequestTransports over WebSockets, so browsers can reach a native system.
+//! Each request and return is one binary message, encoded the same way as every other transport.
+//! Native builds get a blocking client and a server. Browser (wasm32) builds get BrowserWebSocket, which returns through callbacks.
+use crate::autogen_protobuf::transport::*;
+use crate::codec::{Codec, ProtobufCodec};
+
+use failure::Error;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::native::*;
+
+#[cfg(target_arch = "wasm32")]
+pub use self::browser::*;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::*;
+    use crate::framing::{self, FramedConnection, PoisonOnError};
+    use crate::tcptransport::HANDSHAKE_TIMEOUT;
+    use crate::transporter::Transporter;
+
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+    use tungstenite::{Message, WebSocket};
+    use tungstenite::client::AutoStream;
+
+    /// Frames are WebSocket binary messages.
+    pub struct WebSocketConnection<S: Read + Write> {
+        socket: WebSocket<S>,
+    }
+
+    impl<S: Read + Write> WebSocketConnection<S> {
+        pub fn new(socket: WebSocket<S>) -> Self {
+            WebSocketConnection{ socket }
+        }
+
+        /// Finish the closing handshake, so the other side knows the connection ended on purpose.
+        pub fn close(&mut self) -> Result<(), Error> {
+            self.socket.close(None)?;
+            while self.receive_frame()?.is_some() {}
+            Ok(())
+        }
+    }
+
+    impl<S: Read + Write> FramedConnection for WebSocketConnection<S> {
+        fn send_frame(&mut self, payload: &[u8]) -> Result<(), Error> {
+            self.socket.write_message(Message::Binary(payload.to_vec()))?;
+            Ok(())
+        }
+
+        fn receive_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+            loop {
+                match self.socket.read_message() {
+                    Ok(Message::Binary(payload)) => return Ok(Some(payload)),
+                    Ok(Message::Text(_)) => return Err(failure::format_err!("Received a text message! Only binary messages are supported.")),
+                    Err(tungstenite::Error::ConnectionClosed) => return Ok(None),
+                    Ok(_) => continue, // tungstenite answers pings and closes itself. Keep reading so the answer is sent.
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+
+    pub struct WebSocketTransporter {
+        connection: PoisonOnError<WebSocketConnection<AutoStream>>,
+        codec: Box<Codec>,
+    }
+
+    impl WebSocketTransporter {
+        /// Connect to a ws:// or wss:// url.
+        pub fn connect(url: &str) -> Result<Self, Error> {
+            let (socket, _) = tungstenite::connect(url::Url::parse(url)?)?;
+            log::debug!("Connected to {}", url);
+            Ok(WebSocketTransporter{ connection: PoisonOnError::new(WebSocketConnection::new(socket)), codec: Box::new(ProtobufCodec) })
+        }
+
+        pub fn with_codec(mut self, codec: Box<Codec>) -> Self {
+            self.codec = codec;
+            self
+        }
+    }
+
+    impl Transporter for WebSocketTransporter {
+        fn transport_data(&mut self, transport: &RequestTransport) -> ReturnTransport {
+            match framing::request(&mut self.connection, &*self.codec, transport) {
+                Ok(ret) => ret,
+                Err(e) => format!("WebSocket transport error: {:?}", e).into(),
+            }
+        }
+    }
+
+    impl Drop for WebSocketTransporter {
+        fn drop(&mut self) {
+            // A poisoned connection may still hold part of an old reply, so there is no clean way to close it.
+            if self.connection.is_poisoned() {
+                return;
+            }
+            if let Err(e) = self.connection.get_mut().close() {
+                log::debug!("WebSocket did not close cleanly. {:?}", e);
+            }
+        }
+    }
+
+    pub struct WebSocketTransportServer {
+        listener: TcpListener,
+        codec: Box<Codec>,
+    }
+
+    impl WebSocketTransportServer {
+        /// Bind to port 0 to let the OS pick a free port. See local_addr().
+        pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self, Error> {
+            let listener = TcpListener::bind(addr)?;
+            log::debug!("Listening for WebSockets on {:?}", listener.local_addr()?);
+            Ok(WebSocketTransportServer{ listener, codec: Box::new(ProtobufCodec) })
+        }
+
+        pub fn with_codec(mut self, codec: Box<Codec>) -> Self {
+            self.codec = codec;
+            self
+        }
+
+        pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+            Ok(self.listener.local_addr()?)
+        }
+
+        /// Answer requests from any number of clients with the transporter. Does not return unless the listener fails.
+        /// Each client is upgraded to a WebSocket on its own thread.
+        pub fn serve<T: Transporter>(&self, transporter: &mut T) -> Result<(), Error> {
+            let listener = self.listener.try_clone()?;
+            let incoming = std::iter::repeat_with(move || listener.accept())
+                .map(|accepted| -> Result<_, Error> { Ok(accepted?.0) });
+            framing::serve_connections_with_setup(incoming, accept, &*self.codec, transporter)
+        }
+
+        /// Accept a single connection and answer its requests until it closes.
+        pub fn serve_one<T: Transporter>(&self, transporter: &mut T) -> Result<(), Error> {
+            let (stream, _) = self.listener.accept()?;
+            framing::serve_connection(&mut accept(stream)?, &*self.codec, transporter)
+        }
+    }
+
+    // A client that never finishes the upgrade gives up its thread after HANDSHAKE_TIMEOUT.
+    fn accept(stream: TcpStream) -> Result<WebSocketConnection<TcpStream>, Error> {
+        let peer = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let timeouts = stream.try_clone()?;
+        let socket = tungstenite::accept(stream)
+            .map_err(|e| failure::format_err!("WebSocket handshake with {:?} failed! {}", peer, e))?;
+        timeouts.set_read_timeout(None)?;
+        log::debug!("Accepted WebSocket from {:?}", peer);
+        Ok(WebSocketConnection::new(socket))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod browser {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen::closure::Closure;
+    use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+    type OnReturn = Box<FnOnce(ReturnTransport)>;
+
+    /// A browser can't block while waiting on a return, so each request takes a callback instead.
+    /// Returns arrive in the order the requests were sent, so callbacks are answered in that order.
+    pub struct BrowserWebSocket {
+        socket: WebSocket,
+        codec: Rc<Box<Codec>>,
+        waiting: Rc<RefCell<VecDeque<OnReturn>>>,
+        _on_message: Closure<FnMut(MessageEvent)>,
+        on_open: Option<Closure<FnMut()>>,
+    }
+
+    impl BrowserWebSocket {
+        /// Connect to a ws:// or wss:// url. Wait for set_on_open(...) before sending requests.
+        pub fn connect(url: &str) -> Result<Self, Error> {
+            Self::connect_with_codec(url, Box::new(ProtobufCodec))
+        }
+
+        pub fn connect_with_codec(url: &str, codec: Box<Codec>) -> Result<Self, Error> {
+            let socket = WebSocket::new(url).map_err(|e| failure::format_err!("Unable to open WebSocket to {}! {:?}", url, e))?;
+            socket.set_binary_type(BinaryType::Arraybuffer);
+
+            let codec = Rc::new(codec);
+            let waiting: Rc<RefCell<VecDeque<OnReturn>>> = Rc::new(RefCell::new(VecDeque::new()));
+            let on_message = {
+                let codec = codec.clone();
+                let waiting = waiting.clone();
+                Closure::wrap(Box::new(move |event: MessageEvent| {
+                    let bytes = js_sys::Uint8Array::new(&event.data()).to_vec();
+                    let ret = codec.decode_return(&bytes)
+                        .unwrap_or_else(|e| format!("Cannot parse WebSocket message! {:?}", e).into());
+                    match waiting.borrow_mut().pop_front() {
+                        Some(on_return) => on_return(ret),
+                        None => log::warn!("Received a return for a request that was never sent!"),
+                    }
+                }) as Box<FnMut(MessageEvent)>)
+            };
+            socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+            Ok(BrowserWebSocket{ socket, codec, waiting, _on_message: on_message, on_open: None })
+        }
+
+        pub fn set_on_open<F: 'static + FnMut()>(&mut self, on_open: F) {
+            let on_open = Closure::wrap(Box::new(on_open) as Box<FnMut()>);
+            self.socket.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+            self.on_open = Some(on_open);
+        }
+
+        pub fn request<F: 'static + FnOnce(ReturnTransport)>(&self, transport: &RequestTransport, on_return: F) -> Result<(), Error> {
+            let mut bytes = self.codec.encode_request(transport)?;
+            self.socket.send_with_u8_array(&mut bytes)
+                .map_err(|e| failure::format_err!("Unable to send over WebSocket! {:?}", e))?;
+            self.waiting.borrow_mut().push_back(Box::new(on_return));
+            Ok(())
+        }
+    }
+
+    impl Drop for BrowserWebSocket {
+        fn drop(&mut self) {
+            // The closures are about to be freed, so javascript must stop calling them.
+            self.socket.set_onmessage(None);
+            self.socket.set_onopen(None);
+            let _ = self.socket.close();
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use crate::testutil::{request, Echo};
+    use crate::transporter::Transporter;
+
+    #[test]
+    fn test_websocket_loopback() {
+        let server = WebSocketTransportServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = std::thread::spawn(move || server.serve_one(&mut Echo).unwrap());
+
+        let mut client = WebSocketTransporter::connect(&format!("ws://{}", addr)).unwrap();
+        let sent = request("echo", "id");
+        let ret = client.transport_data(&sent);
+        assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+        assert_eq!(ret.vec, vec![sent.event]);
+
+        drop(client);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_silent_client_does_not_hold_up_others() {
+        let server = WebSocketTransportServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || server.serve(&mut Echo));
+
+        // Never sends its upgrade request. Only its own thread waits on it.
+        let _silent = std::net::TcpStream::connect(addr).unwrap();
+        let mut client = WebSocketTransporter::connect(&format!("ws://{}", addr)).unwrap();
+        let sent = request("echo", "id");
+        let ret = client.transport_data(&sent);
+        assert!(ret.errors.is_empty(), "{:?}", ret.errors);
+        assert_eq!(ret.vec, vec![sent.event]);
+    }
+}